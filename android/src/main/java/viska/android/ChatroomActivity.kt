@@ -23,6 +23,7 @@ import androidx.compose.ui.unit.dp
 import com.google.protobuf.ByteString
 import com.google.protobuf.BytesValue
 import java.lang.IllegalArgumentException
+import viska.daemon.Daemon.ChatroomMessagesRequest
 import viska.daemon.Daemon.Message
 import viska.daemon.DaemonWrapper
 import viska.database.displayId
@@ -50,9 +51,11 @@ class ChatroomActivity : InstanceActivity() {
           return@MaterialTheme
         }
 
+        val messagesRequest =
+            ChatroomMessagesRequest.newBuilder().setChatroomId(chatroomIdProtobuf.value).build()
         val messagesSubscription by daemon
             .nodeGrpcClient
-            .watchChatroomMessages(chatroomIdProtobuf)
+            .watchChatroomMessages(messagesRequest)
             .collectAsState(null)
 
         Scaffold(topBar = { TopAppBar(title = { Text(text = chatroom?.name ?: "") }) }) { _ ->
@@ -1,40 +1,269 @@
 tonic::include_proto!("viska.changelog");
 
+use crate::clock::HybridClock;
+use crate::database::changelog::ChangelogService;
 use crate::database::chatroom::ChatroomService;
 use crate::database::message::MessageService;
+use crate::database::object_store::ObjectStore;
 use crate::database::peer::PeerService;
+use crate::database::reaction::ReactionService;
+use crate::database::vcard::VcardService;
 use crate::database::Event;
 use changelog_payload::Content;
 use diesel::prelude::*;
 use std::sync::Arc;
 
+/// Applies [ChangelogPayload]s to the database.
+///
+/// Conflicting changes to the same object are resolved by their [HybridTimestamp]s, so that the
+/// database converges to the same state regardless of the order of merging.
 pub(crate) struct ChangelogMerger {
+    pub clock: HybridClock,
     pub peer_service: Arc<PeerService>,
+    pub objects: Arc<ObjectStore>,
 }
 
 impl ChangelogMerger {
+    /// Commits [ChangelogPayload]s authored on the local device.
+    ///
+    /// Returns the [ChangelogEntry]s recorded in the changelog, for pushing to other devices.
     pub fn commit(
         &self,
         connection: &'_ SqliteConnection,
         payloads: impl Iterator<Item = ChangelogPayload>,
+    ) -> QueryResult<(Vec<ChangelogEntry>, Vec<Event>)> {
+        let mut entries = vec![];
+        let mut events = vec![];
+        for payload in payloads {
+            let timestamp = self.clock.now();
+            events.extend(self.apply(connection, payload.clone(), &timestamp)?);
+            entries.push(ChangelogService::append(
+                connection,
+                self.clock.device_id(),
+                payload,
+                timestamp,
+            )?);
+        }
+        Ok((entries, events))
+    }
+
+    /// Merges [ChangelogEntry]s committed on other devices, skipping those already merged.
+    pub fn merge(
+        &self,
+        connection: &'_ SqliteConnection,
+        entries: impl Iterator<Item = ChangelogEntry>,
+    ) -> QueryResult<Vec<Event>> {
+        let mut events = vec![];
+        for entry in entries {
+            if ChangelogService::insert(connection, &entry)? {
+                let timestamp = entry.timestamp.unwrap_or_default();
+                self.clock.observe(&timestamp);
+                events.extend(self.apply(
+                    connection,
+                    entry.payload.unwrap_or_default(),
+                    &timestamp,
+                )?);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Writes the objects in a [ChangelogPayload] ahead of committing or merging it, so that the
+    /// database connection is not held meanwhile.
+    pub fn write_objects(&self, payload: &ChangelogPayload) -> std::io::Result<()> {
+        let blob = match &payload.content {
+            Some(Content::AddMessage(message)) => message.attachment.as_ref(),
+            Some(Content::AddVcard(vcard)) => vcard.photo.as_ref(),
+            _ => None,
+        };
+        match blob {
+            Some(blob) => self.objects.write_blob(blob),
+            None => Ok(()),
+        }
+    }
+
+    fn apply(
+        &self,
+        connection: &'_ SqliteConnection,
+        payload: ChangelogPayload,
+        timestamp: &HybridTimestamp,
     ) -> QueryResult<Vec<Event>> {
         // TODO: Send events and use transaction
+        let clock = timestamp.to_bytes();
         let mut events = vec![];
-        for payload in payloads {
-            log::debug!("Committing {:?}", &payload.content);
-            match payload.content {
-                Some(Content::AddChatroom(chatroom)) => {
-                    events.push(ChatroomService::save(connection, &chatroom)?);
-                }
-                Some(Content::AddPeer(peer)) => {
-                    events.push(self.peer_service.save(connection, peer)?);
-                }
-                Some(Content::AddMessage(message)) => {
-                    events.push(MessageService::update(connection, &message)?);
-                }
-                None => todo!("Empty transaction payload"),
+        log::debug!("Committing {:?}", &payload.content);
+        match payload.content {
+            Some(Content::AddChatroom(chatroom)) => {
+                events.extend(ChatroomService::save(connection, &chatroom, &clock)?);
             }
+            Some(Content::AddPeer(peer)) => {
+                events.extend(self.peer_service.save(connection, peer, &clock)?);
+            }
+            Some(Content::AddMessage(message)) => {
+                events.push(MessageService::update(connection, &self.objects, &message)?);
+            }
+            Some(Content::AddVcard(vcard)) => {
+                events.extend(VcardService::save(
+                    connection,
+                    &self.objects,
+                    vcard,
+                    &clock,
+                )?);
+            }
+            Some(Content::EditMessage(edit)) => {
+                events.extend(MessageService::edit(connection, &edit)?);
+            }
+            Some(Content::DeleteMessage(delete)) => {
+                events.extend(MessageService::delete(connection, &delete)?);
+            }
+            Some(Content::Reaction(reaction)) => {
+                events.extend(ReactionService::save(connection, &reaction, &clock)?);
+            }
+            Some(Content::ChangeMembership(change)) => {
+                events.extend(ChatroomService::change_membership(connection, &change)?);
+            }
+            None => log::warn!("Skipping an empty changelog payload"),
         }
         Ok(events)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Database;
+    use crate::database::Storage;
+    use proptest::prelude::*;
+
+    fn account(index: u8) -> Vec<u8> {
+        vec![index; 32]
+    }
+
+    fn group_id() -> Vec<u8> {
+        vec![7; 32]
+    }
+
+    fn arb_content() -> impl Strategy<Value = Content> {
+        prop_oneof![
+            (0..2_u8, "[ab]", any::<bool>()).prop_map(|(account_index, name, friend)| {
+                let mut peer = Peer {
+                    account_id: account(account_index),
+                    name,
+                    ..Default::default()
+                };
+                peer.set_role(if friend {
+                    PeerRole::Friend
+                } else {
+                    PeerRole::Blocked
+                });
+                Content::AddPeer(peer)
+            }),
+            (0..2_u8, "[ab]").prop_map(|(account_index, name)| {
+                Content::AddChatroom(Chatroom {
+                    name,
+                    members: vec![account(9), account(account_index)],
+                    chatroom_id: vec![],
+                })
+            }),
+            (0..2_u8, "[ab]").prop_map(|(account_index, name)| {
+                Content::AddChatroom(Chatroom {
+                    name,
+                    members: vec![account(9), account(account_index)],
+                    chatroom_id: group_id(),
+                })
+            }),
+            (0..2_u8, 0..4_i32, any::<bool>()).prop_map(|(account_index, time, invite)| {
+                let mut change = MembershipChange {
+                    chatroom_id: group_id(),
+                    issuer: account(9),
+                    time: time.into(),
+                    account_id: account(account_index),
+                    members: vec![account(9)],
+                    ..Default::default()
+                };
+                if invite {
+                    change.members.push(account(account_index));
+                    change.set_operation(MembershipOperation::Invite);
+                } else {
+                    change.set_operation(MembershipOperation::Remove);
+                }
+                Content::ChangeMembership(change)
+            }),
+            (0..2_u8, "[ab]").prop_map(|(account_index, name)| {
+                Content::AddVcard(Vcard {
+                    account_id: account(account_index),
+                    name,
+                    photo: None,
+                })
+            }),
+            (0..2_u8, 0..4_i32, "[ab]").prop_map(|(account_index, time, content)| {
+                Content::AddMessage(Message {
+                    time: time.into(),
+                    sender: account(account_index),
+                    recipients: vec![account(9)],
+                    content,
+                    attachment: None,
+                    attachment_reference: None,
+                    in_reply_to: vec![],
+                    chatroom_id: vec![],
+                })
+            }),
+        ]
+    }
+
+    /// Entries committed on 3 devices whose wall clocks often collide.
+    fn arb_entries() -> impl Strategy<Value = Vec<ChangelogEntry>> {
+        prop::collection::vec((0..3_u8, 0..4_u64, arb_content()), 1..24).prop_map(|changes| {
+            changes
+                .into_iter()
+                .enumerate()
+                .map(|(index, (device, physical, content))| ChangelogEntry {
+                    device_id: vec![device; 16],
+                    sequence: index as u64 + 1,
+                    payload: ChangelogPayload {
+                        content: content.into(),
+                    }
+                    .into(),
+                    timestamp: HybridTimestamp {
+                        physical,
+                        logical: index as u32,
+                        device_id: vec![device; 16],
+                    }
+                    .into(),
+                })
+                .collect()
+        })
+    }
+
+    fn merge_into_new_database(
+        entries: Vec<ChangelogEntry>,
+    ) -> anyhow::Result<(Vec<Peer>, Vec<Vcard>, Vec<Chatroom>, Vec<Message>)> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let merger = ChangelogMerger {
+            clock: HybridClock::new(vec![9; 16], None),
+            peer_service: PeerService { verifier: None }.into(),
+            objects: database.objects.clone(),
+        };
+        merger.merge(&connection, entries.into_iter())?;
+        Ok((
+            PeerService::export(&connection)?,
+            VcardService::export(&connection, &database.objects)?,
+            ChatroomService::export(&connection)?,
+            MessageService::export(&connection)?,
+        ))
+    }
+
+    proptest! {
+        #[test]
+        fn merge_converges(
+            (entries, shuffled) in arb_entries()
+                .prop_flat_map(|entries| (Just(entries.clone()), Just(entries).prop_shuffle()))
+        ) {
+            prop_assert_eq!(
+                merge_into_new_database(entries).unwrap(),
+                merge_into_new_database(shuffled).unwrap()
+            );
+        }
+    }
+}
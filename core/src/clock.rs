@@ -0,0 +1,145 @@
+//! Hybrid logical clock for ordering [ChangelogEntry](crate::changelog::ChangelogEntry)s across
+//! devices.
+//!
+//! A [HybridTimestamp] stays close to the wall clock but never goes backwards, even if the wall
+//! clocks of the devices disagree. Concurrent [HybridTimestamp]s are ordered by the device ID.
+
+use crate::changelog::HybridTimestamp;
+use chrono::Utc;
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::sync::Mutex;
+
+pub(crate) struct HybridClock {
+    device_id: Vec<u8>,
+
+    /// Physical and logical parts of the latest [HybridTimestamp] generated or observed.
+    latest: Mutex<(u64, u32)>,
+}
+
+impl HybridClock {
+    /// Constructor.
+    ///
+    /// * `latest`: The latest [HybridTimestamp] ever generated or observed, so that the clock does
+    ///   not go backwards after a restart.
+    pub fn new(device_id: Vec<u8>, latest: Option<&HybridTimestamp>) -> Self {
+        Self {
+            device_id,
+            latest: latest
+                .map(|timestamp| (timestamp.physical, timestamp.logical))
+                .unwrap_or_default()
+                .into(),
+        }
+    }
+
+    pub fn device_id(&self) -> &[u8] {
+        &self.device_id
+    }
+
+    /// Generates a [HybridTimestamp] for a local event.
+    pub fn now(&self) -> HybridTimestamp {
+        let wall = wall_clock();
+        let mut latest = self.latest.lock().unwrap();
+        *latest = if wall > latest.0 {
+            (wall, 0)
+        } else {
+            (latest.0, latest.1 + 1)
+        };
+        HybridTimestamp {
+            physical: latest.0,
+            logical: latest.1,
+            device_id: self.device_id.clone(),
+        }
+    }
+
+    /// Advances the clock past a [HybridTimestamp] from another device.
+    pub fn observe(&self, remote: &HybridTimestamp) {
+        let mut latest = self.latest.lock().unwrap();
+        if (remote.physical, remote.logical) > *latest {
+            *latest = (remote.physical, remote.logical);
+        }
+    }
+}
+
+impl HybridTimestamp {
+    const PHYSICAL_LENGTH: usize = std::mem::size_of::<u64>();
+    const LOGICAL_LENGTH: usize = std::mem::size_of::<u32>();
+
+    /// Encodes into bytes whose lexicographic order is the same as [Ord].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(Self::PHYSICAL_LENGTH + Self::LOGICAL_LENGTH + self.device_id.len());
+        bytes.extend_from_slice(&self.physical.to_be_bytes());
+        bytes.extend_from_slice(&self.logical.to_be_bytes());
+        bytes.extend_from_slice(&self.device_id);
+        bytes
+    }
+
+    /// Decodes from [HybridTimestamp::to_bytes].
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::PHYSICAL_LENGTH + Self::LOGICAL_LENGTH {
+            return None;
+        }
+        let (physical, rest) = bytes.split_at(Self::PHYSICAL_LENGTH);
+        let (logical, device_id) = rest.split_at(Self::LOGICAL_LENGTH);
+        Some(Self {
+            physical: u64::from_be_bytes(physical.try_into().ok()?),
+            logical: u32::from_be_bytes(logical.try_into().ok()?),
+            device_id: device_id.into(),
+        })
+    }
+
+    /// Physical part in seconds since UNIX epoch, like the times stored in the database.
+    pub fn time(&self) -> f64 {
+        self.physical as f64 / 1000.0
+    }
+}
+
+impl Eq for HybridTimestamp {}
+
+impl PartialOrd for HybridTimestamp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HybridTimestamp {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.physical, self.logical, &self.device_id).cmp(&(
+            other.physical,
+            other.logical,
+            &other.device_id,
+        ))
+    }
+}
+
+/// Milliseconds since UNIX epoch.
+fn wall_clock() -> u64 {
+    Utc::now().timestamp_millis().max(0) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn monotonic() {
+        let clock = HybridClock::new(vec![1], None);
+        let future = HybridTimestamp {
+            physical: wall_clock() + 60 * 60 * 1000,
+            logical: 7,
+            device_id: vec![0],
+        };
+        clock.observe(&future);
+
+        let first = clock.now();
+        let second = clock.now();
+        assert!(future < first);
+        assert!(first < second);
+        assert!(first.to_bytes() < second.to_bytes());
+        assert_eq!(
+            Some(second.clone()),
+            HybridTimestamp::from_bytes(&second.to_bytes())
+        );
+    }
+}
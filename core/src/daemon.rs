@@ -1,18 +1,34 @@
 tonic::include_proto!("viska.daemon");
 
+use crate::changelog::changelog_payload::Content;
+use crate::changelog::BlobReference;
+use crate::changelog::ChangelogMerger;
+use crate::changelog::ChangelogPayload;
+use crate::changelog::MembershipChange;
+use crate::changelog::MembershipOperation;
+use crate::changelog::PeerRole;
 use crate::database::chatroom::ChatroomService;
+use crate::database::delivery::DeliveryService;
 use crate::database::message::MessageService;
+use crate::database::object::ObjectService;
 use crate::database::peer::PeerService;
 use crate::database::vcard::VcardService;
 use crate::database::Database;
 use crate::database::Event as DatabaseEvent;
+use crate::outbox::Outbox;
+use crate::pki::CanonicalId;
+use crate::presence::PresenceTracker;
+use crate::sync::DeviceSync;
+use crate::transfer::MAX_INLINE_SIZE_BYTES;
 use crate::util::TaskSink;
 use async_trait::async_trait;
+use chrono::Utc;
 use diesel::prelude::*;
 use futures_channel::mpsc::UnboundedReceiver as MpscReceiver;
 use futures_util::FutureExt;
 use node_server::NodeServer;
 use std::any::Any;
+use std::collections::BTreeSet;
 use std::future::Future;
 use std::sync::Arc;
 use tokio::sync::broadcast::error::RecvError;
@@ -46,9 +62,14 @@ impl<T: prost::Message> NullableResponse<T> for Result<tonic::Response<T>, Statu
 
 /// The standard implementation of a "Node" gRPC daemon.
 pub(crate) struct StandardNode {
+    account_id: Vec<u8>,
     event_sink_database: BroadcastSender<Arc<DatabaseEvent>>,
     event_sink_daemon: BroadcastSender<Arc<Event>>,
     database: Arc<Database>,
+    changelog_merger: Arc<ChangelogMerger>,
+    outbox: Arc<Outbox>,
+    device_sync: Arc<DeviceSync>,
+    presence: Arc<PresenceTracker>,
     task_sink: TaskSink,
 }
 
@@ -57,19 +78,30 @@ impl StandardNode {
     ///
     /// Returns a [Future] to drive the gRPC service and a token for shutting down
     /// the service manually. Drop the token to shut it down.
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
         node_grpc_port: u16,
+        account_id: Vec<u8>,
         event_sink_database: BroadcastSender<Arc<DatabaseEvent>>,
         event_sink_daemon: BroadcastSender<Arc<Event>>,
         database: Arc<Database>,
+        changelog_merger: Arc<ChangelogMerger>,
+        outbox: Arc<Outbox>,
+        device_sync: Arc<DeviceSync>,
+        presence: Arc<PresenceTracker>,
     ) -> (impl Future<Output = ()>, impl Any + Send + 'static) {
         // Handlers
         let (task_sink, dynamic_task) = TaskSink::new();
 
         let instance = Self {
+            account_id,
             event_sink_database,
             event_sink_daemon,
             database,
+            changelog_merger,
+            outbox,
+            device_sync,
+            presence,
             task_sink,
         };
 
@@ -106,6 +138,174 @@ impl StandardNode {
         query(&connection).map_err(IntoTonicStatus::into_tonic_status)
     }
 
+    /// Runs a mutation in a transaction and publishes the resulting [DatabaseEvent]s after it is
+    /// committed.
+    fn run_mutation<M, T>(&self, mutation: M) -> Result<T, Status>
+    where
+        M: FnOnce(&'_ SqliteConnection) -> QueryResult<(T, Vec<DatabaseEvent>)>,
+    {
+        let (result, events) = {
+            let connection = self.database.connection.lock().unwrap();
+            connection
+                .transaction::<_, diesel::result::Error, _>(|| mutation(&connection))
+                .map_err(IntoTonicStatus::into_tonic_status)?
+        };
+        for event in events {
+            let _ = self.event_sink_database.send(event.into());
+        }
+        Ok(result)
+    }
+
+    fn commit_changelog(&self, content: Content) -> Result<(), Status> {
+        let payload = ChangelogPayload {
+            content: content.into(),
+        };
+        crate::sync::verify_size(&payload).map_err(Status::invalid_argument)?;
+        let entries = self.run_mutation(|connection| {
+            self.changelog_merger
+                .commit(connection, std::iter::once(payload))
+        })?;
+        self.device_sync.push(entries);
+        Ok(())
+    }
+
+    /// Writes the content of a [Blob](crate::changelog::Blob) ahead of committing what references
+    /// it, without holding the database connection.
+    async fn write_object(
+        &self,
+        blob: crate::changelog::Blob,
+    ) -> Result<crate::changelog::Blob, Status> {
+        self.database
+            .objects
+            .run(move |objects| objects.write_blob(&blob).map(|()| blob))
+            .await
+            .map_err(IntoTonicStatus::into_tonic_status)
+    }
+
+    /// Finds the members of a [Chatroom] that must include the local account.
+    fn find_own_chatroom_members(&self, chatroom_id: &[u8]) -> Result<Vec<Vec<u8>>, Status> {
+        let members = Self::run_query(&self.database, |connection| {
+            ChatroomService::find_members(connection, chatroom_id)
+        })?;
+        if members.is_empty() {
+            Err(Status::not_found("No such chatroom"))
+        } else if !members.contains(&self.account_id) {
+            Err(Status::failed_precondition(
+                "Local account is not a member of the chatroom",
+            ))
+        } else {
+            Ok(members)
+        }
+    }
+
+    /// Finds a [Message](crate::changelog::Message) that must be sent by the local account.
+    fn find_own_message(&self, message_id: &[u8]) -> Result<crate::changelog::Message, Status> {
+        let message = Self::run_query(&self.database, |connection| {
+            MessageService::find_by_id(connection, message_id)
+        })?
+        .ok_or_else(|| Status::not_found("No such message"))?;
+        if message.sender == self.account_id {
+            Ok(message)
+        } else {
+            Err(Status::permission_denied(
+                "Only the sender of a message may revise it",
+            ))
+        }
+    }
+
+    /// Commits a change to a [Message](crate::changelog::Message) and sends it to the other
+    /// members of the chatroom.
+    fn commit_for_message(
+        &self,
+        message: &crate::changelog::Message,
+        content: Content,
+        request: crate::proto::request::Payload,
+    ) -> Result<(), Status> {
+        let payload = ChangelogPayload {
+            content: content.into(),
+        };
+        crate::sync::verify_size(&payload).map_err(Status::invalid_argument)?;
+        let request = crate::proto::Request {
+            payload: request.into(),
+        };
+        let entries = self.run_mutation(|connection| {
+            let (entries, events) = self
+                .changelog_merger
+                .commit(connection, std::iter::once(payload))?;
+            crate::outbox::enqueue_for_message(connection, &self.account_id, message, &request)?;
+            Ok((entries, events))
+        })?;
+        self.outbox.wake();
+        self.device_sync.push(entries);
+        Ok(())
+    }
+
+    /// Commits a [MembershipChange] to a group chatroom issued by the local account and sends it to
+    /// the members both before and after it.
+    fn change_membership(
+        &self,
+        chatroom_id: Vec<u8>,
+        operation: MembershipOperation,
+        account_id: Vec<u8>,
+    ) -> Result<(), Status> {
+        let members = self.find_own_chatroom_members(&chatroom_id)?;
+        let is_group = Self::run_query(&self.database, |connection| {
+            ChatroomService::is_group(connection, &chatroom_id)
+        })?;
+        if !is_group {
+            return Err(Status::failed_precondition(
+                "Members of a chatroom without a stable ID never change",
+            ));
+        }
+        let is_member = members.contains(&account_id);
+        let members_changed = match operation {
+            MembershipOperation::Invite if is_member => {
+                return Err(Status::already_exists("Already a member of the chatroom"))
+            }
+            MembershipOperation::Invite => members
+                .iter()
+                .cloned()
+                .chain(std::iter::once(account_id.clone()))
+                .collect(),
+            _ if !is_member => return Err(Status::not_found("Not a member of the chatroom")),
+            _ => members
+                .iter()
+                .filter(|member| **member != account_id)
+                .cloned()
+                .collect(),
+        };
+
+        let mut change = MembershipChange {
+            chatroom_id,
+            issuer: self.account_id.clone(),
+            time: crate::database::float_from_time(Utc::now()),
+            account_id,
+            members: members_changed,
+            ..Default::default()
+        };
+        change.set_operation(operation);
+        let recipients: BTreeSet<_> = members
+            .into_iter()
+            .chain(change.members.iter().cloned())
+            .collect();
+        let request = crate::proto::Request {
+            payload: crate::proto::request::Payload::MembershipChange(change.clone()).into(),
+        };
+        let payload = ChangelogPayload {
+            content: Content::ChangeMembership(change).into(),
+        };
+        let entries = self.run_mutation(|connection| {
+            let (entries, events) = self
+                .changelog_merger
+                .commit(connection, std::iter::once(payload))?;
+            crate::outbox::enqueue_to(connection, &self.account_id, &recipients, &request)?;
+            Ok((entries, events))
+        })?;
+        self.outbox.wake();
+        self.device_sync.push(entries);
+        Ok(())
+    }
+
     fn run_subscription<F, T, Q>(
         &self,
         event_filter: F,
@@ -197,15 +397,49 @@ impl node_server::Node for StandardNode {
 
     async fn watch_chatroom_messages(
         &self,
-        request: tonic::Request<Vec<u8>>,
+        request: tonic::Request<ChatroomMessagesRequest>,
     ) -> Result<tonic::Response<Self::WatchChatroomMessagesStream>, Status> {
-        let requested_chatroom_id = request.into_inner();
-        let requested_chatroom_id_for_filter = requested_chatroom_id.clone();
+        let request = request.into_inner();
+        let requested_chatroom_id_for_filter = request.chatroom_id.clone();
         let result = self.run_subscription(
-            move |event| {
-                matches!(event, DatabaseEvent::Message { chatroom_id } if chatroom_id == &requested_chatroom_id_for_filter)
+            move |event| match event {
+                DatabaseEvent::Message { chatroom_id }
+                | DatabaseEvent::Delivery { chatroom_id, .. } => {
+                    chatroom_id == &requested_chatroom_id_for_filter
+                }
+                _ => false,
+            },
+            move |connection| {
+                MessageService::find_by_chatroom(
+                    connection,
+                    &request.chatroom_id,
+                    request.cursor.as_ref(),
+                    request.limit,
+                )
+            },
+        );
+        Ok(result)
+    }
+
+    type WatchRepliesStream = MpscReceiver<Result<ChatroomMessagesSubscription, Status>>;
+
+    async fn watch_replies(
+        &self,
+        request: tonic::Request<Vec<u8>>,
+    ) -> Result<tonic::Response<Self::WatchRepliesStream>, Status> {
+        let requested_message_id = request.into_inner();
+
+        // Replies are in the same chatroom unless the message is not received yet
+        let chatroom_id_for_filter = Self::run_query(&self.database, |connection| {
+            MessageService::find_chatroom_id(connection, &requested_message_id)
+        })?;
+        let result = self.run_subscription(
+            move |event| match (event, &chatroom_id_for_filter) {
+                (DatabaseEvent::Message { chatroom_id }, Some(expected)) => chatroom_id == expected,
+                (DatabaseEvent::Message { .. }, None) => true,
+                _ => false,
             },
-            move |connection| MessageService::find_by_chatroom(connection, &requested_chatroom_id),
+            move |connection| MessageService::find_replies(connection, &requested_message_id),
         );
         Ok(result)
     }
@@ -218,12 +452,17 @@ impl node_server::Node for StandardNode {
     ) -> Result<tonic::Response<Self::WatchChatroomStream>, Status> {
         let requested_chatroom_id = request.into_inner();
         let requested_chatroom_id_for_filter = requested_chatroom_id.clone();
+        let account_id = self.account_id.clone();
         let result = self.run_subscription(
-            move |event| {
-                matches!(event, DatabaseEvent::Chatroom { chatroom_id } if chatroom_id == &requested_chatroom_id_for_filter)
+            move |event| match event {
+                DatabaseEvent::Chatroom { chatroom_id }
+                | DatabaseEvent::Message { chatroom_id } => {
+                    chatroom_id == &requested_chatroom_id_for_filter
+                }
+                _ => false,
             },
             move |connection| {
-                ChatroomService::find_by_id(connection, &requested_chatroom_id)
+                ChatroomService::find_by_id(connection, &account_id, &requested_chatroom_id)
                     .map(Option::unwrap_or_default)
             },
         );
@@ -236,9 +475,15 @@ impl node_server::Node for StandardNode {
         &self,
         _: tonic::Request<()>,
     ) -> Result<tonic::Response<Self::WatchChatroomsStream>, Status> {
+        let account_id = self.account_id.clone();
         let result = self.run_subscription(
-            |event| matches!(event, DatabaseEvent::Chatroom { chatroom_id: _ }),
-            move |connection| ChatroomService::find_all(connection),
+            |event| {
+                matches!(
+                    event,
+                    DatabaseEvent::Chatroom { .. } | DatabaseEvent::Message { .. }
+                )
+            },
+            move |connection| ChatroomService::find_all(connection, &account_id),
         );
         Ok(result)
     }
@@ -255,6 +500,407 @@ impl node_server::Node for StandardNode {
         );
         Ok(result)
     }
+
+    type WatchMessageDeliveryStream = MpscReceiver<Result<MessageDelivery, Status>>;
+
+    async fn watch_message_delivery(
+        &self,
+        request: tonic::Request<Vec<u8>>,
+    ) -> Result<tonic::Response<Self::WatchMessageDeliveryStream>, Status> {
+        let requested_message_id = request.into_inner();
+        let requested_message_id_for_filter = requested_message_id.clone();
+        let result = self.run_subscription(
+            move |event| {
+                matches!(event, DatabaseEvent::Delivery { message_id, .. } if message_id == &requested_message_id_for_filter)
+            },
+            move |connection| DeliveryService::find_by_message(connection, &requested_message_id),
+        );
+        Ok(result)
+    }
+
+    async fn search_messages(
+        &self,
+        request: tonic::Request<SearchMessagesRequest>,
+    ) -> Result<Response<SearchMessagesResponse>, Status> {
+        let request = request.into_inner();
+        Self::run_query(&self.database, |connection| {
+            MessageService::search(connection, &request)
+        })
+        .map(Response::new)
+    }
+
+    type WatchPresenceStream = MpscReceiver<Result<PresenceSubscription, Status>>;
+
+    async fn watch_presence(
+        &self,
+        _: tonic::Request<()>,
+    ) -> Result<tonic::Response<Self::WatchPresenceStream>, Status> {
+        let (sender, receiver) = futures_channel::mpsc::unbounded();
+        let mut subscription = self.presence.subscribe();
+        let presence = self.presence.clone();
+        let task = async move {
+            if sender.unbounded_send(Ok(presence.snapshot())).is_err() {
+                return;
+            }
+            loop {
+                match subscription.recv().await {
+                    Ok(_) | Err(RecvError::Lagged(_)) => {
+                        if sender.unbounded_send(Ok(presence.snapshot())).is_err() {
+                            return;
+                        }
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        };
+        self.task_sink.submit(task);
+        Ok(Response::new(receiver))
+    }
+
+    async fn get_attachment(
+        &self,
+        request: tonic::Request<Vec<u8>>,
+    ) -> Result<Response<crate::changelog::Blob>, Status> {
+        let object_id = request.into_inner();
+        let reference = Self::run_query(&self.database, |connection| {
+            ObjectService::find_by_id(connection, &object_id)
+        })?
+        .ok_or_else(|| Status::not_found("No such attachment"))?;
+        let content = self
+            .database
+            .objects
+            .run(move |objects| objects.read(&object_id))
+            .await
+            .map_err(IntoTonicStatus::into_tonic_status)?
+            .ok_or_else(|| Status::not_found("No such attachment"))?;
+        Ok(Response::new(crate::changelog::Blob {
+            mime: reference.mime,
+            content,
+        }))
+    }
+
+    async fn send_message(
+        &self,
+        request: tonic::Request<SendMessageRequest>,
+    ) -> Result<Response<Vec<u8>>, Status> {
+        let request = request.into_inner();
+        let members = self.find_own_chatroom_members(&request.chatroom_id)?;
+        let is_group = Self::run_query(&self.database, |connection| {
+            ChatroomService::is_group(connection, &request.chatroom_id)
+        })?;
+        if !request.in_reply_to.is_empty() {
+            let parent_chatroom_id = Self::run_query(&self.database, |connection| {
+                MessageService::find_chatroom_id(connection, &request.in_reply_to)
+            })?;
+            if parent_chatroom_id.as_ref() != Some(&request.chatroom_id) {
+                return Err(Status::invalid_argument(
+                    "Message replied to is not in the chatroom",
+                ));
+            }
+        }
+
+        let attachment = match request.attachment {
+            Some(attachment) => Some(self.write_object(attachment).await?),
+            None => None,
+        };
+
+        // Large attachments are fetched separately by the recipients
+        let (attachment, large_attachment) = match attachment {
+            Some(attachment) if attachment.content.len() > MAX_INLINE_SIZE_BYTES => {
+                (None, Some(attachment))
+            }
+            attachment => (attachment, None),
+        };
+        let attachment_reference = large_attachment.as_ref().map(|attachment| BlobReference {
+            object_id: crate::database::bytes_from_hash(attachment.canonical_id()),
+            mime: attachment.mime.clone(),
+            size: attachment.content.len() as u64,
+        });
+
+        let message = crate::changelog::Message {
+            time: crate::database::float_from_time(Utc::now()),
+            sender: self.account_id.clone(),
+            recipients: members
+                .into_iter()
+                .filter(|member| member != &self.account_id)
+                .collect(),
+            content: request.content,
+            attachment,
+            attachment_reference,
+            in_reply_to: request.in_reply_to,
+            chatroom_id: if is_group {
+                request.chatroom_id
+            } else {
+                vec![]
+            },
+        };
+        let message_id = crate::database::bytes_from_hash(message.canonical_id());
+        let payload = ChangelogPayload {
+            content: Content::AddMessage(message.clone()).into(),
+        };
+        crate::sync::verify_size(&payload).map_err(Status::invalid_argument)?;
+        let entries = self.run_mutation(|connection| {
+            if let Some(attachment) = &large_attachment {
+                ObjectService::save(connection, &self.database.objects, attachment)?;
+            }
+            let (entries, mut events) = self
+                .changelog_merger
+                .commit(connection, std::iter::once(payload))?;
+            events.extend(crate::outbox::enqueue_message(
+                connection,
+                &self.account_id,
+                &message,
+            )?);
+            Ok((entries, events))
+        })?;
+        self.outbox.wake();
+        self.device_sync.push(entries);
+        Ok(Response::new(message_id))
+    }
+
+    async fn edit_message(
+        &self,
+        request: tonic::Request<EditMessageRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let message = self.find_own_message(&request.message_id)?;
+        let edit = crate::changelog::EditMessage {
+            message_id: request.message_id,
+            sender: self.account_id.clone(),
+            time: crate::database::float_from_time(Utc::now()),
+            content: request.content,
+        };
+        self.commit_for_message(
+            &message,
+            Content::EditMessage(edit.clone()),
+            crate::proto::request::Payload::EditMessage(edit),
+        )?;
+        Ok(Response::new(()))
+    }
+
+    async fn delete_message(
+        &self,
+        request: tonic::Request<Vec<u8>>,
+    ) -> Result<Response<()>, Status> {
+        let message_id = request.into_inner();
+        let message = self.find_own_message(&message_id)?;
+        let delete = crate::changelog::DeleteMessage {
+            message_id,
+            sender: self.account_id.clone(),
+            time: crate::database::float_from_time(Utc::now()),
+        };
+        self.commit_for_message(
+            &message,
+            Content::DeleteMessage(delete.clone()),
+            crate::proto::request::Payload::DeleteMessage(delete),
+        )?;
+        Ok(Response::new(()))
+    }
+
+    async fn react(&self, request: tonic::Request<ReactRequest>) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        crate::database::reaction::verify_emoji(&request.emoji)
+            .map_err(Status::invalid_argument)?;
+        let message = Self::run_query(&self.database, |connection| {
+            MessageService::find_by_id(connection, &request.message_id)
+        })?
+        .ok_or_else(|| Status::not_found("No such message"))?;
+        if message.sender != self.account_id && !message.recipients.contains(&self.account_id) {
+            return Err(Status::failed_precondition(
+                "Local account is not a member of the chatroom",
+            ));
+        }
+        let reaction = crate::changelog::Reaction {
+            message_id: request.message_id,
+            account_id: self.account_id.clone(),
+            emoji: request.emoji,
+            removed: request.removed,
+        };
+        self.commit_for_message(
+            &message,
+            Content::Reaction(reaction.clone()),
+            crate::proto::request::Payload::Reaction(reaction),
+        )?;
+        Ok(Response::new(()))
+    }
+
+    async fn mark_chatroom_read(
+        &self,
+        request: tonic::Request<MarkChatroomReadRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let chatroom_id = request.chatroom_id;
+        self.find_own_chatroom_members(&chatroom_id)?;
+        let message_id = Some(request.message_id.as_slice()).filter(|id| !id.is_empty());
+        if let Some(message_id) = message_id {
+            let message_chatroom_id = Self::run_query(&self.database, |connection| {
+                MessageService::find_chatroom_id(connection, message_id)
+            })?;
+            if message_chatroom_id.as_ref() != Some(&chatroom_id) {
+                return Err(Status::invalid_argument("Message is not in the chatroom"));
+            }
+        }
+        self.run_mutation(|connection| {
+            let receipts =
+                MessageService::mark_read(connection, &chatroom_id, &self.account_id, message_id)?;
+            let mut events = vec![DatabaseEvent::Chatroom {
+                chatroom_id: chatroom_id.clone(),
+            }];
+            if !receipts.is_empty() {
+                crate::outbox::enqueue_receipts(connection, receipts, true)?;
+                events.push(DatabaseEvent::Message { chatroom_id });
+            }
+            Ok(((), events))
+        })?;
+        self.outbox.wake();
+        Ok(Response::new(()))
+    }
+
+    async fn set_typing(
+        &self,
+        request: tonic::Request<SetTypingRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let members = self.find_own_chatroom_members(&request.chatroom_id)?;
+        self.presence
+            .send_typing(&request.chatroom_id, request.typing, &members)
+            .await;
+        Ok(Response::new(()))
+    }
+
+    async fn set_away(&self, request: tonic::Request<bool>) -> Result<Response<()>, Status> {
+        self.presence.set_away(request.into_inner());
+        self.presence.announce().await;
+        Ok(Response::new(()))
+    }
+
+    async fn save_peer(
+        &self,
+        request: tonic::Request<crate::changelog::Peer>,
+    ) -> Result<Response<()>, Status> {
+        self.commit_changelog(Content::AddPeer(request.into_inner()))?;
+        Ok(Response::new(()))
+    }
+
+    async fn block_peer(&self, request: tonic::Request<Vec<u8>>) -> Result<Response<()>, Status> {
+        let account_id = request.into_inner();
+        let mut peer = Self::run_query(&self.database, |connection| {
+            PeerService::find_by_account_id(connection, &account_id)
+        })?
+        .unwrap_or_else(|| crate::changelog::Peer {
+            account_id,
+            ..Default::default()
+        });
+        peer.set_role(PeerRole::Blocked);
+        self.commit_changelog(Content::AddPeer(peer))?;
+        Ok(Response::new(()))
+    }
+
+    async fn create_chatroom(
+        &self,
+        request: tonic::Request<CreateChatroomRequest>,
+    ) -> Result<Response<Vec<u8>>, Status> {
+        let request = request.into_inner();
+        let mut members = request.members;
+        members.push(self.account_id.clone());
+        let chatroom = crate::changelog::Chatroom {
+            name: request.name,
+            members,
+            chatroom_id: if request.group {
+                crate::database::bytes_from_hash(crate::database::chatroom::generate_group_id(
+                    &self.account_id,
+                ))
+            } else {
+                vec![]
+            },
+        };
+        let chatroom_id = crate::database::bytes_from_hash(chatroom.chatroom_id());
+        self.commit_changelog(Content::AddChatroom(chatroom))?;
+        Ok(Response::new(chatroom_id))
+    }
+
+    async fn rename_chatroom(
+        &self,
+        request: tonic::Request<RenameChatroomRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let members = self.find_own_chatroom_members(&request.chatroom_id)?;
+        let is_group = Self::run_query(&self.database, |connection| {
+            ChatroomService::is_group(connection, &request.chatroom_id)
+        })?;
+        let chatroom = crate::changelog::Chatroom {
+            members,
+            name: request.name,
+            chatroom_id: if is_group {
+                request.chatroom_id
+            } else {
+                vec![]
+            },
+        };
+        self.commit_changelog(Content::AddChatroom(chatroom))?;
+        Ok(Response::new(()))
+    }
+
+    async fn invite_to_chatroom(
+        &self,
+        request: tonic::Request<ChatroomMemberRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        self.change_membership(
+            request.chatroom_id,
+            MembershipOperation::Invite,
+            request.account_id,
+        )?;
+        Ok(Response::new(()))
+    }
+
+    async fn remove_from_chatroom(
+        &self,
+        request: tonic::Request<ChatroomMemberRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        if request.account_id == self.account_id {
+            return Err(Status::invalid_argument(
+                "Local account leaves a chatroom instead of removing itself",
+            ));
+        }
+        self.change_membership(
+            request.chatroom_id,
+            MembershipOperation::Remove,
+            request.account_id,
+        )?;
+        Ok(Response::new(()))
+    }
+
+    async fn leave_chatroom(
+        &self,
+        request: tonic::Request<Vec<u8>>,
+    ) -> Result<Response<()>, Status> {
+        self.change_membership(
+            request.into_inner(),
+            MembershipOperation::Leave,
+            self.account_id.clone(),
+        )?;
+        Ok(Response::new(()))
+    }
+
+    async fn update_own_vcard(
+        &self,
+        request: tonic::Request<UpdateOwnVcardRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let photo = match request.photo {
+            Some(photo) => Some(self.write_object(photo).await?),
+            None => None,
+        };
+        let vcard = crate::changelog::Vcard {
+            account_id: self.account_id.clone(),
+            name: request.name,
+            photo,
+        };
+        self.commit_changelog(Content::AddVcard(vcard))?;
+        Ok(Response::new(()))
+    }
 }
 
 trait IntoTonicStatus {
@@ -270,11 +916,18 @@ impl IntoTonicStatus for diesel::result::Error {
     }
 }
 
+impl IntoTonicStatus for std::io::Error {
+    fn into_tonic_status(self) -> Status {
+        Status::internal(self.to_string())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::event::Content;
     use super::node_client::NodeClient;
     use super::*;
+    use crate::endpoint::ConnectionInfo;
     use futures_util::StreamExt;
     use tonic::transport::Channel;
 
@@ -302,4 +955,89 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn send_message() -> anyhow::Result<()> {
+        let (node, _) = crate::util::start_dummy_node().await?;
+        let mut client = grpc_client(node.grpc_port()).await?;
+
+        let chatroom_id = client
+            .create_chatroom(CreateChatroomRequest {
+                name: "Chatroom".into(),
+                members: vec![vec![0; 32]],
+                group: false,
+            })
+            .await?
+            .into_inner();
+        let mut stream = client
+            .watch_chatroom_messages(ChatroomMessagesRequest {
+                chatroom_id: chatroom_id.clone(),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        assert!(stream.next().await.unwrap()?.messages.is_empty());
+
+        let message_id = client
+            .send_message(SendMessageRequest {
+                chatroom_id,
+                content: "Hello".into(),
+                attachment: None,
+                in_reply_to: vec![],
+            })
+            .await?
+            .into_inner();
+        let messages = stream.next().await.unwrap()?.messages;
+        assert_eq!(1, messages.len());
+        assert_eq!(message_id, messages[0].message_id);
+        assert_eq!("Hello", messages[0].content);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deliver_message() -> anyhow::Result<()> {
+        let (sender, _) = crate::util::start_dummy_node().await?;
+        let (recipient, _) = crate::util::start_dummy_node().await?;
+        let recipient_address = format!("[::1]:{}", recipient.local_port()?).parse()?;
+        let recipient_account_id = sender
+            .connect(&recipient_address)
+            .await?
+            .account_id()
+            .map(crate::database::bytes_from_hash)
+            .unwrap();
+
+        let mut client = grpc_client(sender.grpc_port()).await?;
+        let chatroom_id = client
+            .create_chatroom(CreateChatroomRequest {
+                name: "Chatroom".into(),
+                members: vec![recipient_account_id.clone()],
+                group: false,
+            })
+            .await?
+            .into_inner();
+        let message_id = client
+            .send_message(SendMessageRequest {
+                chatroom_id,
+                content: "Hello".into(),
+                attachment: None,
+                in_reply_to: vec![],
+            })
+            .await?
+            .into_inner();
+
+        let mut stream = client
+            .watch_message_delivery(message_id)
+            .await?
+            .into_inner();
+        while let Some(delivery) = stream.next().await {
+            let recipients = delivery?.recipients;
+            assert_eq!(1, recipients.len());
+            assert_eq!(recipient_account_id, recipients[0].account_id);
+            if recipients[0].state() == DeliveryState::Delivered {
+                return Ok(());
+            }
+        }
+        panic!("Message not delivered")
+    }
 }
@@ -2,15 +2,25 @@
 
 diesel_migrations::embed_migrations!();
 
+pub(crate) mod changelog;
 pub(crate) mod chatroom;
+pub(crate) mod delivery;
 pub(crate) mod message;
-mod object;
+pub(crate) mod object;
+pub(crate) mod object_store;
+pub(crate) mod outbox;
 pub(crate) mod peer;
+pub(crate) mod peer_address;
+pub(crate) mod reaction;
 mod schema;
 pub(crate) mod vcard;
 
+use self::changelog::ChangelogService;
+use self::object::ObjectService;
+use self::object_store::ObjectStore;
 use self::peer::PeerService;
 use crate::changelog::ChangelogMerger;
+use crate::clock::HybridClock;
 use crate::mock_profile::MockProfileService;
 use crate::pki::CanonicalId;
 use blake3::Hash;
@@ -21,6 +31,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use serde_bytes::ByteBuf;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::sync::Mutex;
 use thiserror::Error;
 
@@ -49,8 +60,15 @@ pub(crate) fn bytes_from_hash(src: Hash) -> Vec<u8> {
 
 /// Where to store the database.
 pub enum Storage {
+    /// Objects are stored in a temporary directory.
     InMemory,
-    OnDisk(PathBuf),
+    OnDisk {
+        /// Path to the SQLite database.
+        database: PathBuf,
+
+        /// Directory of [ObjectStore].
+        objects: PathBuf,
+    },
 }
 
 impl Default for Storage {
@@ -61,22 +79,32 @@ impl Default for Storage {
 
 pub(crate) struct Database {
     pub connection: Mutex<SqliteConnection>,
+    pub objects: Arc<ObjectStore>,
 }
 
 impl Database {
     pub fn create(storage: &Storage) -> Result<Self, DatabaseInitializationError> {
-        let database_url = match storage {
-            Storage::InMemory => ":memory:".into(),
-            Storage::OnDisk(path) => path.display().to_string(),
+        let (database_url, objects) = match storage {
+            Storage::InMemory => (":memory:".into(), ObjectStore::temporary()?),
+            Storage::OnDisk { database, objects } => (
+                database.display().to_string(),
+                ObjectStore::open(objects.clone())?,
+            ),
         };
         log::info!("Opening database URL {}", &database_url);
         let connection = SqliteConnection::establish(&database_url)?;
 
         log::info!("Beginning database migration");
         embedded_migrations::run(&connection)?;
+        connection.transaction::<_, diesel::result::Error, _>(|| {
+            ObjectService::export(&connection, &objects)?;
+            ObjectService::collect_garbage(&connection)
+        })?;
+        ObjectService::sweep(&connection, &objects)?;
 
         Ok(Self {
             connection: connection.into(),
+            objects: objects.into(),
         })
     }
 }
@@ -87,6 +115,8 @@ impl Database {
 pub enum DatabaseInitializationError {
     DatabaseConnection(#[from] diesel::ConnectionError),
     DatabaseMigration(#[from] diesel_migrations::RunMigrationsError),
+    DatabaseQuery(#[from] diesel::result::Error),
+    ObjectStore(#[from] std::io::Error),
 }
 
 /// Configurations regarding account profiles.
@@ -103,6 +133,9 @@ pub enum DatabaseInitializationError {
 ///       - `database`
 ///         - `main.db`
 ///         - Maybe some auxiliary files generated by SQLite
+///       - `object`
+///         - `C4D2E1A2CAE1DB6A8B6C1A2EC9D8BAE10E4A1C4F4A2B1BB6B8D5A0E8D2F1C3B7` (Object ID)
+///         - Maybe some partially downloaded objects
 #[derive(Deserialize, Serialize)]
 pub struct ProfileConfig {
     pub dir_data: std::path::PathBuf,
@@ -118,6 +151,22 @@ impl ProfileConfig {
         Ok(destination)
     }
 
+    pub async fn path_objects(&self, account_id: &[u8]) -> std::io::Result<PathBuf> {
+        let mut destination = async_fs::canonicalize(&self.dir_data).await?;
+        destination.push("account");
+        destination.push(hex::encode_upper(account_id));
+        destination.push("object");
+        Ok(destination)
+    }
+
+    /// Resolves where to store the database and objects of an account.
+    pub async fn storage(&self, account_id: &[u8]) -> std::io::Result<Storage> {
+        Ok(Storage::OnDisk {
+            database: self.path_database(account_id).await?,
+            objects: self.path_objects(account_id).await?,
+        })
+    }
+
     pub async fn path_certificate(&self, account_id: &[u8]) -> std::io::Result<PathBuf> {
         let mut destination = async_fs::canonicalize(&self.dir_data).await?;
         destination.push("account");
@@ -173,9 +222,7 @@ pub async fn create_standard_profile(
             .unwrap(),
     )
     .await?;
-    Database::create(&Storage::OnDisk(
-        profile_config.path_database(account_id.as_bytes()).await?,
-    ))?;
+    Database::create(&profile_config.storage(account_id.as_bytes()).await?)?;
 
     Ok(ByteBuf::from(account_id.as_bytes().to_vec()))
 }
@@ -196,11 +243,12 @@ pub async fn create_mock_profile(
     };
     let account_id = create_standard_profile(dir_data).await?;
 
-    let database = Database::create(&Storage::OnDisk(
-        profile_config.path_database(&account_id).await?,
-    ))?;
+    let database = Database::create(&profile_config.storage(&account_id).await?)?;
+    let device_id = ChangelogService::device_id(&database.connection.lock().unwrap())?;
     let changelog_merger = ChangelogMerger {
+        clock: HybridClock::new(device_id, None),
         peer_service: PeerService { verifier: None }.into(),
+        objects: database.objects.clone(),
     }
     .into();
     let mock_profile_service = MockProfileService {
@@ -232,8 +280,18 @@ impl CanonicalId for crate::changelog::Blob {
 }
 
 pub(crate) enum Event {
-    Chatroom { chatroom_id: Vec<u8> },
-    Message { chatroom_id: Vec<u8> },
+    Chatroom {
+        chatroom_id: Vec<u8>,
+    },
+    Delivery {
+        message_id: Vec<u8>,
+        chatroom_id: Vec<u8>,
+    },
+    Message {
+        chatroom_id: Vec<u8>,
+    },
     Roster,
-    Vcard { account_id: Vec<u8> },
+    Vcard {
+        account_id: Vec<u8>,
+    },
 }
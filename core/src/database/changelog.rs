@@ -0,0 +1,298 @@
+use super::chatroom::ChatroomService;
+use super::message::MessageService;
+use super::object_store::ObjectStore;
+use super::peer::PeerService;
+use super::schema::changelog as Schema;
+use super::schema::local_device as SchemaLocalDevice;
+use super::vcard::VcardService;
+use crate::changelog::changelog_payload::Content;
+use crate::changelog::ChangelogEntry;
+use crate::changelog::ChangelogPayload;
+use crate::changelog::HybridTimestamp;
+use crate::changelog::SequenceNumber;
+use crate::clock::HybridClock;
+use diesel::dsl::max;
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use diesel::sql_types::Binary;
+use diesel::sql_types::Bool;
+use diesel::sqlite::Sqlite;
+use prost::Message as _;
+use uuid::Uuid;
+
+/// Finds the end of the run of sequence numbers starting from 1 of each device, or 0 if the first
+/// entry is missing.
+const CONTIGUOUS_QUERY: &str = "
+    SELECT
+        device_id,
+        CASE WHEN MIN(sequence) = 1 THEN MIN(
+            CASE WHEN NOT EXISTS (
+                SELECT 1 FROM changelog AS next
+                WHERE next.device_id = changelog.device_id
+                    AND next.sequence = changelog.sequence + 1
+            ) THEN sequence END
+        ) ELSE 0 END AS sequence
+    FROM changelog
+    GROUP BY device_id
+";
+
+#[derive(QueryableByName)]
+struct ContiguousRow {
+    #[sql_type = "Binary"]
+    device_id: Vec<u8>,
+    #[sql_type = "BigInt"]
+    sequence: i64,
+}
+
+/// Append-only log of every committed [ChangelogPayload].
+pub(crate) struct ChangelogService;
+
+impl ChangelogService {
+    /// Gets the ID of the local device, generating one if absent.
+    pub fn device_id(connection: &'_ SqliteConnection) -> QueryResult<Vec<u8>> {
+        if let Some(device_id) = SchemaLocalDevice::table
+            .select(SchemaLocalDevice::device_id)
+            .first(connection)
+            .optional()?
+        {
+            return Ok(device_id);
+        }
+        let device_id = Uuid::new_v4().as_bytes().to_vec();
+        diesel::insert_into(SchemaLocalDevice::table)
+            .values(SchemaLocalDevice::device_id.eq(&device_id))
+            .execute(connection)?;
+        Ok(device_id)
+    }
+
+    /// Appends a [ChangelogPayload] committed on a device.
+    pub fn append(
+        connection: &'_ SqliteConnection,
+        device_id: &[u8],
+        payload: ChangelogPayload,
+        timestamp: HybridTimestamp,
+    ) -> QueryResult<ChangelogEntry> {
+        let sequence = Schema::table
+            .filter(Schema::device_id.eq(device_id))
+            .select(max(Schema::sequence))
+            .first::<Option<i64>>(connection)?
+            .unwrap_or_default()
+            + 1;
+        let entry = ChangelogEntry {
+            device_id: device_id.into(),
+            sequence: sequence as u64,
+            payload: payload.into(),
+            timestamp: timestamp.into(),
+        };
+        Self::insert(connection, &entry)?;
+        Ok(entry)
+    }
+
+    /// Records a [ChangelogEntry] committed on another device.
+    ///
+    /// Returns `false` if it is already recorded.
+    pub fn insert(connection: &'_ SqliteConnection, entry: &ChangelogEntry) -> QueryResult<bool> {
+        let mut raw_payload = Vec::<u8>::new();
+        entry
+            .payload
+            .clone()
+            .unwrap_or_default()
+            .encode(&mut raw_payload)
+            .unwrap_or_else(|err| panic!("Failed to encode a changelog payload: {}", err));
+        diesel::insert_or_ignore_into(Schema::table)
+            .values((
+                Schema::device_id.eq(&entry.device_id),
+                Schema::sequence.eq(entry.sequence as i64),
+                Schema::payload.eq(raw_payload),
+                Schema::clock.eq(entry
+                    .timestamp
+                    .as_ref()
+                    .map(HybridTimestamp::to_bytes)
+                    .unwrap_or_default()),
+            ))
+            .execute(connection)
+            .map(|count| count > 0)
+    }
+
+    /// Finds the latest [HybridTimestamp] ever recorded.
+    pub fn latest_timestamp(
+        connection: &'_ SqliteConnection,
+    ) -> QueryResult<Option<HybridTimestamp>> {
+        Schema::table
+            .select(Schema::clock)
+            .order(Schema::clock.desc())
+            .first::<Vec<u8>>(connection)
+            .optional()
+            .map(|clock| clock.and_then(|clock| HybridTimestamp::from_bytes(&clock)))
+    }
+
+    /// Finds the latest [SequenceNumber] of every device up to which no entry is missing.
+    ///
+    /// Entries arriving out of order, such as those pushed during a pull, leave gaps that are
+    /// filled by the next pull starting from here.
+    pub fn latest(connection: &'_ SqliteConnection) -> QueryResult<Vec<SequenceNumber>> {
+        diesel::sql_query(CONTIGUOUS_QUERY)
+            .load::<ContiguousRow>(connection)
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| SequenceNumber {
+                        device_id: row.device_id,
+                        sequence: row.sequence as u64,
+                    })
+                    .collect()
+            })
+    }
+
+    /// Finds the [ChangelogEntry]s newer than `since`.
+    ///
+    /// All entries of the devices absent from `since` are included. Entries of each device are in
+    /// order.
+    pub fn find_since(
+        connection: &'_ SqliteConnection,
+        since: &[SequenceNumber],
+    ) -> QueryResult<Vec<ChangelogEntry>> {
+        let known_devices: Vec<_> = since.iter().map(|s| s.device_id.clone()).collect();
+        let mut condition: Box<dyn BoxableExpression<Schema::table, Sqlite, SqlType = Bool>> =
+            Box::new(Schema::device_id.ne_all(known_devices));
+        for sequence_number in since {
+            condition = Box::new(
+                condition.or(Schema::device_id
+                    .eq(sequence_number.device_id.clone())
+                    .and(Schema::sequence.gt(sequence_number.sequence as i64))),
+            );
+        }
+
+        let rows = Schema::table
+            .filter(condition)
+            .order((Schema::device_id.asc(), Schema::sequence.asc()))
+            .select((
+                Schema::device_id,
+                Schema::sequence,
+                Schema::payload,
+                Schema::clock,
+            ))
+            .load::<(Vec<u8>, i64, Vec<u8>, Vec<u8>)>(connection)?;
+        let mut entries = Vec::with_capacity(rows.len());
+        for (device_id, sequence, raw_payload, clock) in rows {
+            match ChangelogPayload::decode(raw_payload.as_slice()) {
+                Ok(payload) => entries.push(ChangelogEntry {
+                    device_id,
+                    sequence: sequence as u64,
+                    payload: payload.into(),
+                    timestamp: HybridTimestamp::from_bytes(&clock),
+                }),
+                Err(err) => log::error!("Skipping a corrupted changelog entry: {:?}", err),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Records all existing data as committed on the local device if the changelog is empty.
+    ///
+    /// Data created before the changelog existed would otherwise never reach other devices.
+    pub fn backfill(
+        connection: &'_ SqliteConnection,
+        objects: &ObjectStore,
+        clock: &HybridClock,
+    ) -> QueryResult<()> {
+        let recorded: bool =
+            diesel::select(diesel::dsl::exists(Schema::table.select(Schema::sequence)))
+                .first(connection)?;
+        if recorded {
+            return Ok(());
+        }
+
+        let peers = PeerService::export(connection)?
+            .into_iter()
+            .map(Content::AddPeer);
+        let vcards = VcardService::export(connection, objects)?
+            .into_iter()
+            .map(Content::AddVcard);
+        let chatrooms = ChatroomService::export(connection)?
+            .into_iter()
+            .map(Content::AddChatroom);
+        let messages = MessageService::export(connection)?
+            .into_iter()
+            .map(Content::AddMessage);
+        for content in peers.chain(vcards).chain(chatrooms).chain(messages) {
+            let payload = ChangelogPayload {
+                content: content.into(),
+            };
+            Self::append(connection, clock.device_id(), payload, clock.now())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::changelog::Chatroom;
+    use crate::database::Database;
+    use crate::database::Storage;
+
+    #[test]
+    fn find_since() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let payload = |name: &str| ChangelogPayload {
+            content: Content::AddChatroom(Chatroom {
+                name: name.into(),
+                members: vec![],
+                chatroom_id: vec![],
+            })
+            .into(),
+        };
+
+        let local = ChangelogService::device_id(&connection)?;
+        assert_eq!(local, ChangelogService::device_id(&connection)?);
+        let clock = HybridClock::new(local.clone(), None);
+        for name in ["L1", "L2", "L3"].iter() {
+            ChangelogService::append(&connection, &local, payload(name), clock.now())?;
+        }
+        let remote = ChangelogEntry {
+            device_id: vec![0; 16],
+            sequence: 1,
+            payload: payload("R1").into(),
+            timestamp: None,
+        };
+        assert!(ChangelogService::insert(&connection, &remote)?);
+        assert!(!ChangelogService::insert(&connection, &remote)?);
+
+        let names = |entries: Vec<ChangelogEntry>| {
+            entries
+                .into_iter()
+                .map(|entry| match entry.payload.unwrap().content {
+                    Some(Content::AddChatroom(chatroom)) => chatroom.name,
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let since = vec![SequenceNumber {
+            device_id: local.clone(),
+            sequence: 1,
+        }];
+        assert_eq!(
+            vec!["R1", "L2", "L3"],
+            names(ChangelogService::find_since(&connection, &since)?)
+        );
+
+        let latest = ChangelogService::latest(&connection)?;
+        assert_eq!(2, latest.len());
+        assert!(ChangelogService::find_since(&connection, &latest)?.is_empty());
+
+        // Entries after a gap are asked again
+        let remote_3 = ChangelogEntry {
+            sequence: 3,
+            payload: payload("R3").into(),
+            ..remote
+        };
+        assert!(ChangelogService::insert(&connection, &remote_3)?);
+        let latest = ChangelogService::latest(&connection)?;
+        assert_eq!(
+            vec!["R3"],
+            names(ChangelogService::find_since(&connection, &latest)?)
+        );
+
+        Ok(())
+    }
+}
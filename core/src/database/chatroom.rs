@@ -1,18 +1,35 @@
+use super::message::MessageService;
 use super::schema::chatroom as Schema;
 use super::schema::chatroom_members as SchemaMembers;
+use super::schema::chatroom_membership as SchemaMembership;
+use super::schema::peer as SchemaPeer;
+use super::schema::vcard as SchemaVcard;
 use super::Event;
 use crate::changelog::Chatroom;
+use crate::changelog::HybridTimestamp;
+use crate::changelog::MembershipChange;
+use crate::changelog::MembershipOperation;
 use crate::changelog::Message;
 use crate::daemon::ChatroomsSubscription;
+use crate::daemon::MessageSnippet;
+use crate::pki::CanonicalId;
 use blake3::Hash;
 use blake3::Hasher;
 use chrono::Utc;
 use diesel::prelude::*;
 use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use uuid::Uuid;
 
+/// Bytes of an account ID shown in a derived name when the account has no name.
+const ACCOUNT_ID_PREFIX_LENGTH: usize = 4;
+
 pub(crate) struct ChatroomService;
 
+/// Chatroom ID, name, time of the latest activity and whether it is a group.
+type Row = (Vec<u8>, String, f64, bool);
+
 impl ChatroomService {
     /// Creates a [Chatroom](super::Chatroom) when receiving or sending a message belonging to a
     /// non-existing [Chatroom](super::Chatroom).
@@ -20,52 +37,316 @@ impl ChatroomService {
         let mut members = message.recipients.clone();
         members.push(message.sender.clone());
 
-        // TODO: New name by fetching Vcard
-        let name = "New chatroom".to_string();
-
-        Chatroom { members, name }
+        // Named after its members until renamed
+        Chatroom {
+            members,
+            name: String::new(),
+            chatroom_id: message.chatroom_id.clone(),
+        }
     }
 
     /// Updates the [Chatroom](super::Chatroom) that is supposed to hold a new [Message].
+    ///
+    /// Its last activity moves forward to the time of the [Message], which may arrive out of order.
     pub fn update_for_message(
         connection: &'_ SqliteConnection,
         message: &crate::changelog::Message,
     ) -> QueryResult<Option<Event>> {
-        let chatroom_id = message.chatroom_id();
-        // TODO: Simplify to 1 SQL
-        if let Some(time_updated) = Schema::table
-            .find(chatroom_id.as_bytes().as_ref())
-            .select(Schema::time_updated)
-            .first(connection)
+        let chatroom_id = super::bytes_from_hash(message.chatroom_id());
+        let exists = Schema::table
+            .find(&chatroom_id)
+            .select(Schema::chatroom_id)
+            .first::<Vec<u8>>(connection)
             .optional()?
-        {
-            if message.time > time_updated {
-                diesel::update(Schema::table.find(chatroom_id.as_bytes().as_ref()))
-                    .set(Schema::time_updated.eq(time_updated))
-                    .execute(connection)?;
-                Ok(Event::Chatroom {
-                    chatroom_id: chatroom_id.as_bytes().to_vec(),
-                }
-                .into())
-            } else {
-                Ok(None)
-            }
+            .is_some();
+        if exists {
+            Self::touch(connection, chatroom_id, message.time)
         } else {
-            Self::save(connection, &ChatroomService::create_for_message(message)).map(Into::into)
+            // Any explicit change to the chatroom overrides this one
+            Self::save_at(
+                connection,
+                &ChatroomService::create_for_message(message),
+                &[],
+                message.time,
+            )
         }
     }
 
-    pub fn save(connection: &'_ SqliteConnection, payload: &Chatroom) -> QueryResult<Event> {
+    /// Moves the last activity of a chatroom forward to `time`, or does nothing if it is later.
+    fn touch(
+        connection: &'_ SqliteConnection,
+        chatroom_id: Vec<u8>,
+        time: f64,
+    ) -> QueryResult<Option<Event>> {
+        let updated = diesel::update(
+            Schema::table
+                .find(&chatroom_id)
+                .filter(Schema::time_updated.lt(time)),
+        )
+        .set(Schema::time_updated.eq(time))
+        .execute(connection)?;
+        Ok(if updated > 0 {
+            Some(Event::Chatroom { chatroom_id })
+        } else {
+            None
+        })
+    }
+
+    /// Saves a [Chatroom] unless a newer version is already saved.
+    ///
+    /// The change counts as activity at the time of `clock`, or now if there is none.
+    ///
+    /// * `clock`: Encoded [HybridTimestamp] of the change.
+    pub fn save(
+        connection: &'_ SqliteConnection,
+        payload: &Chatroom,
+        clock: &[u8],
+    ) -> QueryResult<Option<Event>> {
+        let time = HybridTimestamp::from_bytes(clock)
+            .map_or_else(|| super::float_from_time(Utc::now()), |clock| clock.time());
+        Self::save_at(connection, payload, clock, time)
+    }
+
+    fn save_at(
+        connection: &'_ SqliteConnection,
+        payload: &Chatroom,
+        clock: &[u8],
+        time: f64,
+    ) -> QueryResult<Option<Event>> {
         let chatroom_id = super::bytes_from_hash(payload.chatroom_id());
+        let is_group = parse_group_id(&payload.chatroom_id).is_some();
+        let saved = Schema::table
+            .find(&chatroom_id)
+            .select((Schema::clock, Schema::time_updated))
+            .first::<(Vec<u8>, f64)>(connection)
+            .optional()?;
+        let time_updated = match saved {
+            Some((clock_saved, _)) if clock_saved.as_slice() >= clock => return Ok(None),
+            Some((_, time_updated)) => time_updated.max(time),
+            None => time,
+        };
+
         diesel::replace_into(Schema::table)
             .values((
                 Schema::chatroom_id.eq(&chatroom_id),
-                Schema::time_updated.eq(super::float_from_time(Utc::now())),
+                Schema::time_updated.eq(time_updated),
                 Schema::name.eq(&payload.name),
+                Schema::clock.eq(clock),
+                Schema::is_group.eq(is_group),
+            ))
+            .execute(connection)?;
+
+        Self::replace_members_changed(connection, &chatroom_id, payload.members.iter())?;
+        Ok(Some(Event::Chatroom { chatroom_id }))
+    }
+
+    /// Applies a [MembershipChange] to a group chatroom unless a later one concerning the same
+    /// account is already applied, creating the chatroom if missing.
+    ///
+    /// Only the account concerned joins or leaves, except that the latest [MembershipChange] tells
+    /// the other members of a chatroom never saved explicitly. [MembershipChange]s at the same time
+    /// are ordered by their canonical IDs.
+    pub fn change_membership(
+        connection: &'_ SqliteConnection,
+        change: &MembershipChange,
+    ) -> QueryResult<Option<Event>> {
+        if parse_group_id(&change.chatroom_id).is_none() {
+            log::warn!("Ignoring a membership change to a chatroom without a stable ID");
+            return Ok(None);
+        }
+        if !change.time.is_finite() {
+            log::warn!("Ignoring a membership change at an invalid time");
+            return Ok(None);
+        }
+        let chatroom_id = &change.chatroom_id;
+        let version = (change.time, super::bytes_from_hash(change.canonical_id()));
+        let latest_of_account = SchemaMembership::table
+            .find((chatroom_id, &change.account_id))
+            .select((SchemaMembership::time, SchemaMembership::change_id))
+            .first::<(f64, Vec<u8>)>(connection)
+            .optional()?;
+        if latest_of_account.map_or(false, |latest| latest >= version) {
+            return Ok(None);
+        }
+        let latest = SchemaMembership::table
+            .filter(SchemaMembership::chatroom_id.eq(chatroom_id))
+            .select((SchemaMembership::time, SchemaMembership::change_id))
+            .order((
+                SchemaMembership::time.desc(),
+                SchemaMembership::change_id.desc(),
+            ))
+            .first::<(f64, Vec<u8>)>(connection)
+            .optional()?;
+        let is_latest = latest.map_or(true, |latest| latest < version);
+
+        let clock = Schema::table
+            .find(chatroom_id)
+            .select(Schema::clock)
+            .first::<Vec<u8>>(connection)
+            .optional()?;
+        if clock.is_some() {
+            Self::touch(connection, chatroom_id.clone(), change.time)?;
+        } else {
+            // Any explicit change to the chatroom overrides this one
+            diesel::insert_into(Schema::table)
+                .values((
+                    Schema::chatroom_id.eq(chatroom_id),
+                    Schema::time_updated.eq(change.time),
+                    Schema::name.eq(""),
+                    Schema::clock.eq(&[] as &[u8]),
+                    Schema::is_group.eq(true),
+                ))
+                .execute(connection)?;
+        }
+
+        let is_member = change.operation() == MembershipOperation::Invite;
+        diesel::replace_into(SchemaMembership::table)
+            .values((
+                SchemaMembership::chatroom_id.eq(chatroom_id),
+                SchemaMembership::account_id.eq(&change.account_id),
+                SchemaMembership::change_id.eq(&version.1),
+                SchemaMembership::time.eq(change.time),
+                SchemaMembership::is_member.eq(is_member),
             ))
             .execute(connection)?;
-        Self::replace_members(connection, &chatroom_id, payload.members.iter())?;
-        Ok(Event::Chatroom { chatroom_id })
+        if is_latest && clock.map_or(true, |clock| clock.is_empty()) {
+            Self::replace_members_changed(connection, chatroom_id, change.members.iter())?;
+        } else {
+            diesel::delete(
+                SchemaMembers::table
+                    .filter(SchemaMembers::chatroom_id.eq(chatroom_id))
+                    .filter(SchemaMembers::member_account_id.eq(&change.account_id)),
+            )
+            .execute(connection)?;
+            if is_member {
+                diesel::insert_into(SchemaMembers::table)
+                    .values((
+                        SchemaMembers::id.eq(Uuid::new_v4().as_bytes().to_vec()),
+                        SchemaMembers::chatroom_id.eq(chatroom_id),
+                        SchemaMembers::member_account_id.eq(&change.account_id),
+                    ))
+                    .execute(connection)?;
+            }
+        }
+        Ok(Some(Event::Chatroom {
+            chatroom_id: chatroom_id.clone(),
+        }))
+    }
+
+    /// Notifies the chatrooms named after their members when the name of a member changes.
+    pub fn find_named_after(
+        connection: &'_ SqliteConnection,
+        account_id: &[u8],
+    ) -> QueryResult<Vec<Event>> {
+        SchemaMembers::table
+            .inner_join(Schema::table)
+            .filter(SchemaMembers::member_account_id.eq(account_id))
+            .filter(Schema::name.eq(""))
+            .select(SchemaMembers::chatroom_id)
+            .load::<Vec<u8>>(connection)
+            .map(|chatroom_ids| {
+                chatroom_ids
+                    .into_iter()
+                    .map(|chatroom_id| Event::Chatroom { chatroom_id })
+                    .collect()
+            })
+    }
+
+    /// Names chatrooms after their members other than the local account, preferring the names
+    /// given to [Peer](crate::changelog::Peer)s over those in their [Vcard](crate::changelog::Vcard)s.
+    fn derive_names(
+        connection: &SqliteConnection,
+        account_id: &[u8],
+        chatroom_ids: Vec<&Vec<u8>>,
+    ) -> QueryResult<HashMap<Vec<u8>, String>> {
+        let mut members = HashMap::<Vec<u8>, Vec<Vec<u8>>>::new();
+        for (chatroom_id, member) in SchemaMembers::table
+            .filter(SchemaMembers::chatroom_id.eq_any(chatroom_ids))
+            .select((SchemaMembers::chatroom_id, SchemaMembers::member_account_id))
+            .order(SchemaMembers::member_account_id.asc())
+            .load::<(Vec<u8>, Vec<u8>)>(connection)?
+        {
+            members.entry(chatroom_id).or_default().push(member);
+        }
+        let accounts: Vec<&Vec<u8>> = members
+            .values()
+            .flatten()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let mut names: HashMap<Vec<u8>, String> = SchemaVcard::table
+            .filter(SchemaVcard::account_id.eq_any(accounts.clone()))
+            .filter(SchemaVcard::name.ne(""))
+            .select((SchemaVcard::account_id, SchemaVcard::name))
+            .load::<(Vec<u8>, String)>(connection)?
+            .into_iter()
+            .collect();
+        names.extend(
+            SchemaPeer::table
+                .filter(SchemaPeer::account_id.eq_any(accounts))
+                .filter(SchemaPeer::name.ne(""))
+                .select((SchemaPeer::account_id, SchemaPeer::name))
+                .load::<(Vec<u8>, String)>(connection)?,
+        );
+
+        Ok(members
+            .into_iter()
+            .map(|(chatroom_id, members)| {
+                let others: Vec<_> = members
+                    .iter()
+                    .filter(|member| member.as_slice() != account_id)
+                    .collect();
+
+                // Only the local account is left
+                let named = if others.is_empty() {
+                    members.iter().collect()
+                } else {
+                    others
+                };
+                let name = named
+                    .into_iter()
+                    .map(|member| {
+                        names.get(member).cloned().unwrap_or_else(|| {
+                            hex::encode_upper(&member[..member.len().min(ACCOUNT_ID_PREFIX_LENGTH)])
+                        })
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                (chatroom_id, name)
+            })
+            .collect())
+    }
+
+    /// Checks whether a chatroom has a stable ID, so that its members may change.
+    pub fn is_group(connection: &SqliteConnection, chatroom_id: &[u8]) -> QueryResult<bool> {
+        Schema::table
+            .find(chatroom_id)
+            .select(Schema::is_group)
+            .first(connection)
+            .optional()
+            .map(|is_group| is_group.unwrap_or_default())
+    }
+
+    /// Replaces the members of a chatroom, except for the accounts whose membership is decided by
+    /// a [MembershipChange].
+    fn replace_members_changed<'m>(
+        connection: &'_ SqliteConnection,
+        chatroom_id: &[u8],
+        members: impl Iterator<Item = &'m Vec<u8>>,
+    ) -> QueryResult<()> {
+        let changed = SchemaMembership::table
+            .filter(SchemaMembership::chatroom_id.eq(chatroom_id))
+            .select((SchemaMembership::account_id, SchemaMembership::is_member))
+            .load::<(Vec<u8>, bool)>(connection)?;
+        let mut members: BTreeSet<_> = members.cloned().collect();
+        for (account_id, is_member) in changed {
+            if is_member {
+                members.insert(account_id);
+            } else {
+                members.remove(&account_id);
+            }
+        }
+        Self::replace_members(connection, chatroom_id, members.iter())
     }
 
     fn replace_members<'m>(
@@ -92,41 +373,194 @@ impl ChatroomService {
         Ok(())
     }
 
+    /// Finds a chatroom along with its latest message and what the local account has read.
     pub fn find_by_id(
         connection: &SqliteConnection,
+        account_id: &[u8],
         id: &[u8],
     ) -> QueryResult<Option<crate::daemon::Chatroom>> {
-        Schema::table
+        let row = match Schema::table
             .find(id)
-            .select(Schema::name)
-            .first::<String>(connection)
-            .map(|name| crate::daemon::Chatroom {
-                name,
-                chatroom_id: id.into(),
-            })
-            .optional()
+            .select((
+                Schema::chatroom_id,
+                Schema::name,
+                Schema::time_updated,
+                Schema::is_group,
+            ))
+            .first::<Row>(connection)
+            .optional()?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let mut derived_names = Self::derive_names(connection, account_id, vec![&row.0])?;
+        let mut unread_counts = MessageService::count_unread(connection, account_id, Some(id))?;
+        let mut latest = MessageService::find_latest(connection, Some(id))?;
+        let mut last_read = MessageService::find_last_read(connection, Some(id))?;
+        Ok(Some(Self::present(
+            row,
+            &mut derived_names,
+            &mut unread_counts,
+            &mut latest,
+            &mut last_read,
+        )))
+    }
+
+    /// Presents a chatroom, which is named after its members unless it has a name.
+    fn present(
+        (chatroom_id, name, time_updated, group): Row,
+        derived_names: &mut HashMap<Vec<u8>, String>,
+        unread_counts: &mut HashMap<Vec<u8>, u32>,
+        latest: &mut HashMap<Vec<u8>, MessageSnippet>,
+        last_read: &mut HashMap<Vec<u8>, (Vec<u8>, f64)>,
+    ) -> crate::daemon::Chatroom {
+        let (last_read_message_id, time_last_read) =
+            last_read.remove(&chatroom_id).unwrap_or_default();
+        crate::daemon::Chatroom {
+            name: if name.is_empty() {
+                derived_names.remove(&chatroom_id).unwrap_or_default()
+            } else {
+                name
+            },
+            unread_count: unread_counts.remove(&chatroom_id).unwrap_or_default(),
+            last_message: latest.remove(&chatroom_id),
+            time_last_activity: time_updated,
+            last_read_message_id,
+            time_last_read,
+            group,
+            chatroom_id,
+        }
+    }
+
+    pub fn find_members(
+        connection: &SqliteConnection,
+        chatroom_id: &[u8],
+    ) -> QueryResult<Vec<Vec<u8>>> {
+        SchemaMembers::table
+            .filter(SchemaMembers::chatroom_id.eq(chatroom_id))
+            .select(SchemaMembers::member_account_id)
+            .load(connection)
+    }
+
+    /// Finds the IDs of the [Chatroom]s where an account is a member.
+    pub fn find_ids_by_member(
+        connection: &SqliteConnection,
+        account_id: &[u8],
+    ) -> QueryResult<Vec<Vec<u8>>> {
+        SchemaMembers::table
+            .filter(SchemaMembers::member_account_id.eq(account_id))
+            .select(SchemaMembers::chatroom_id)
+            .load(connection)
     }
 
-    pub fn find_all(connection: &SqliteConnection) -> QueryResult<ChatroomsSubscription> {
+    /// Exports all [Chatroom]s as changelog.
+    pub fn export(connection: &SqliteConnection) -> QueryResult<Vec<Chatroom>> {
+        let mut members = HashMap::<Vec<u8>, Vec<Vec<u8>>>::new();
+        for (chatroom_id, member) in SchemaMembers::table
+            .select((SchemaMembers::chatroom_id, SchemaMembers::member_account_id))
+            .order(SchemaMembers::member_account_id.asc())
+            .load::<(Vec<u8>, Vec<u8>)>(connection)?
+        {
+            members.entry(chatroom_id).or_default().push(member);
+        }
         Schema::table
-            .select((Schema::name, Schema::chatroom_id))
-            .order(Schema::time_updated.asc())
-            .load::<(String, Vec<u8>)>(connection)
-            .map(|rows| ChatroomsSubscription {
-                chatrooms: rows
-                    .into_iter()
-                    .map(|(name, chatroom_id)| crate::daemon::Chatroom { name, chatroom_id })
-                    .collect(),
+            .select((Schema::chatroom_id, Schema::name, Schema::is_group))
+            .order(Schema::chatroom_id.asc())
+            .load::<(Vec<u8>, String, bool)>(connection)
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|(chatroom_id, name, is_group)| Chatroom {
+                        name,
+                        members: members.remove(&chatroom_id).unwrap_or_default(),
+                        chatroom_id: if is_group { chatroom_id } else { vec![] },
+                    })
+                    .collect()
+            })
+    }
+
+    /// Finds all chatrooms along with their latest messages and what the local account has read,
+    /// the most recently active first.
+    pub fn find_all(
+        connection: &SqliteConnection,
+        account_id: &[u8],
+    ) -> QueryResult<ChatroomsSubscription> {
+        let rows = Schema::table
+            .select((
+                Schema::chatroom_id,
+                Schema::name,
+                Schema::time_updated,
+                Schema::is_group,
+            ))
+            .order((Schema::time_updated.desc(), Schema::chatroom_id.asc()))
+            .load::<Row>(connection)?;
+        let unnamed = rows
+            .iter()
+            .filter(|(_, name, ..)| name.is_empty())
+            .map(|(chatroom_id, ..)| chatroom_id)
+            .collect();
+        let mut derived_names = Self::derive_names(connection, account_id, unnamed)?;
+        let mut unread_counts = MessageService::count_unread(connection, account_id, None)?;
+        let mut latest = MessageService::find_latest(connection, None)?;
+        let mut last_read = MessageService::find_last_read(connection, None)?;
+        let chatrooms = rows
+            .into_iter()
+            .map(|row| {
+                Self::present(
+                    row,
+                    &mut derived_names,
+                    &mut unread_counts,
+                    &mut latest,
+                    &mut last_read,
+                )
             })
+            .collect();
+        Ok(ChatroomsSubscription { chatrooms })
     }
 }
 
 impl crate::changelog::Chatroom {
     pub fn chatroom_id(&self) -> Hash {
-        chatroom_id(self.members.iter())
+        parse_group_id(&self.chatroom_id).unwrap_or_else(|| chatroom_id(self.members.iter()))
     }
 }
 
+impl CanonicalId for MembershipChange {
+    fn canonical_id(&self) -> Hash {
+        let mut hasher = Hasher::default();
+        hasher.update(b"Viska membership change");
+        for field in [&self.chatroom_id, &self.issuer, &self.account_id].iter() {
+            hasher.update(&field.len().to_be_bytes());
+            hasher.update(field);
+        }
+        hasher.update(&self.time.to_be_bytes());
+        hasher.update(&self.operation.to_be_bytes());
+
+        let members_sorted: BTreeSet<&Vec<u8>> = self.members.iter().collect();
+        hasher.update(&members_sorted.len().to_be_bytes());
+        for member in members_sorted {
+            hasher.update(&member.len().to_be_bytes());
+            hasher.update(member);
+        }
+
+        hasher.finalize()
+    }
+}
+
+/// Generates a stable ID for a new group chatroom, unrelated to its members.
+pub fn generate_group_id(creator: &[u8]) -> Hash {
+    let mut hasher = Hasher::default();
+    hasher.update(b"Viska group chatroom ID");
+    hasher.update(&creator.len().to_be_bytes());
+    hasher.update(creator);
+    hasher.update(Uuid::new_v4().as_bytes());
+    hasher.finalize()
+}
+
+/// Parses the ID of a group chatroom, which is empty for a chatroom identified by its members.
+pub fn parse_group_id(id: &[u8]) -> Option<Hash> {
+    <[u8; blake3::OUT_LEN]>::try_from(id).ok().map(Hash::from)
+}
+
 pub fn chatroom_id<'a>(members: impl Iterator<Item = &'a Vec<u8>>) -> Hash {
     let mut hasher = Hasher::default();
     hasher.update(b"Viska chatroom ID");
@@ -140,3 +574,231 @@ pub fn chatroom_id<'a>(members: impl Iterator<Item = &'a Vec<u8>>) -> Hash {
 
     hasher.finalize()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::changelog::Peer;
+    use crate::changelog::Vcard;
+    use crate::database::peer::PeerService;
+    use crate::database::vcard::VcardService;
+    use crate::database::Database;
+    use crate::database::Storage;
+
+    #[test]
+    fn order_by_activity() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let message = |time: f64, recipient: u8| Message {
+            time,
+            sender: vec![1],
+            recipients: vec![vec![recipient]],
+            content: "Hello".into(),
+            attachment: None,
+            attachment_reference: None,
+            in_reply_to: vec![],
+            chatroom_id: vec![],
+        };
+        let find_all = || {
+            ChatroomService::find_all(&connection, &[1]).map(|subscription| {
+                subscription
+                    .chatrooms
+                    .into_iter()
+                    .map(|chatroom| (chatroom.chatroom_id, chatroom.time_last_activity))
+                    .collect::<Vec<_>>()
+            })
+        };
+        let chatroom_2 = super::super::bytes_from_hash(message(0.0, 2).chatroom_id());
+        let chatroom_3 = super::super::bytes_from_hash(message(0.0, 3).chatroom_id());
+
+        // Created at the time of the first message, not when it arrives
+        for message in [message(20.0, 2), message(10.0, 3)].iter() {
+            MessageService::update(&connection, &database.objects, message)?;
+        }
+        assert_eq!(
+            vec![(chatroom_2.clone(), 20.0), (chatroom_3.clone(), 10.0)],
+            find_all()?
+        );
+
+        // A message arriving late does not move the chatroom back
+        assert!(ChatroomService::update_for_message(&connection, &message(5.0, 2))?.is_none());
+        assert!(ChatroomService::update_for_message(&connection, &message(30.0, 3))?.is_some());
+        assert_eq!(
+            vec![(chatroom_3.clone(), 30.0), (chatroom_2.clone(), 20.0)],
+            find_all()?
+        );
+
+        // An explicit change counts as activity at the time of its clock
+        let clock = HybridTimestamp {
+            physical: 40_000,
+            logical: 0,
+            device_id: vec![1],
+        };
+        let chatroom = Chatroom {
+            name: "Renamed".into(),
+            members: vec![vec![1], vec![2]],
+            chatroom_id: vec![],
+        };
+        ChatroomService::save(&connection, &chatroom, &clock.to_bytes())?;
+        assert_eq!(vec![(chatroom_2, 40.0), (chatroom_3, 30.0)], find_all()?);
+        Ok(())
+    }
+
+    #[test]
+    fn change_membership() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let group_id = super::super::bytes_from_hash(generate_group_id(&[1]));
+        let chatroom = |name: &str| Chatroom {
+            name: name.into(),
+            members: vec![vec![1], vec![2]],
+            chatroom_id: group_id.clone(),
+        };
+        let clock = |physical| {
+            HybridTimestamp {
+                physical,
+                logical: 0,
+                device_id: vec![1],
+            }
+            .to_bytes()
+        };
+        ChatroomService::save(&connection, &chatroom("Group"), &clock(1_000))?;
+        assert!(ChatroomService::is_group(&connection, &group_id)?);
+        let find_members = || {
+            ChatroomService::find_members(&connection, &group_id).map(|mut members| {
+                members.sort();
+                members
+            })
+        };
+
+        // Messages stay in the chatroom and keep their IDs
+        let message = Message {
+            time: 1.0,
+            sender: vec![1],
+            recipients: vec![vec![2]],
+            content: "Hello".into(),
+            attachment: None,
+            attachment_reference: None,
+            in_reply_to: vec![],
+            chatroom_id: group_id.clone(),
+        };
+        MessageService::update(&connection, &database.objects, &message)?;
+        let message_id = super::super::bytes_from_hash(message.canonical_id());
+        assert_eq!(
+            Some(message.clone()),
+            MessageService::find_by_id(&connection, &message_id)?
+        );
+        assert_ne!(
+            message.chatroom_id(),
+            Message {
+                chatroom_id: vec![],
+                ..message.clone()
+            }
+            .chatroom_id()
+        );
+
+        // The latest change concerning each account wins regardless of the order of arrival, and
+        // concurrent invites of different accounts both apply
+        let change = |time: f64, operation, account_id: u8, members: Vec<Vec<u8>>| {
+            let mut change = MembershipChange {
+                chatroom_id: group_id.clone(),
+                issuer: vec![1],
+                time,
+                account_id: vec![account_id],
+                members,
+                ..Default::default()
+            };
+            change.set_operation(operation);
+            change
+        };
+        let remove = change(3.0, MembershipOperation::Remove, 2, vec![vec![1]]);
+        let invite_2 = change(2.0, MembershipOperation::Invite, 2, vec![vec![1], vec![2]]);
+        let invite_4 = change(
+            2.0,
+            MembershipOperation::Invite,
+            4,
+            vec![vec![1], vec![2], vec![4]],
+        );
+        let invite_5 = change(
+            2.0,
+            MembershipOperation::Invite,
+            5,
+            vec![vec![1], vec![2], vec![5]],
+        );
+        assert!(ChatroomService::change_membership(&connection, &remove)?.is_some());
+        assert!(ChatroomService::change_membership(&connection, &invite_2)?.is_none());
+        assert!(ChatroomService::change_membership(&connection, &invite_5)?.is_some());
+        assert!(ChatroomService::change_membership(&connection, &invite_4)?.is_some());
+        assert_eq!(vec![vec![1], vec![4], vec![5]], find_members()?);
+
+        // Renaming keeps the members
+        ChatroomService::save(&connection, &chatroom("Renamed"), &clock(4_000))?;
+        assert_eq!(vec![vec![1], vec![4], vec![5]], find_members()?);
+        let exported = ChatroomService::export(&connection)?;
+        assert_eq!(1, exported.len());
+        assert_eq!("Renamed", exported[0].name);
+        assert_eq!(group_id, exported[0].chatroom_id);
+        Ok(())
+    }
+
+    #[test]
+    fn derive_name() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let message = Message {
+            time: 1.0,
+            sender: vec![2],
+            recipients: vec![vec![1], vec![3]],
+            content: "Hello".into(),
+            attachment: None,
+            attachment_reference: None,
+            in_reply_to: vec![],
+            chatroom_id: vec![],
+        };
+        let chatroom_id = super::super::bytes_from_hash(message.chatroom_id());
+        MessageService::update(&connection, &database.objects, &message)?;
+        let find_name = || {
+            ChatroomService::find_by_id(&connection, &[1], &chatroom_id)
+                .map(|chatroom| chatroom.unwrap().name)
+        };
+        let vcard = |account_id: u8, name: &str| Vcard {
+            account_id: vec![account_id],
+            name: name.into(),
+            photo: None,
+        };
+        let is_notified = |events: Vec<Event>| {
+            events.iter().any(
+                |event| matches!(event, Event::Chatroom { chatroom_id: id } if id == &chatroom_id),
+            )
+        };
+
+        // Excluding the local account, falling back to account IDs
+        VcardService::save(&connection, &database.objects, vcard(1, "Alice"), &[1])?;
+        assert_eq!("02, 03", find_name()?);
+
+        // Recomputed when a name changes, preferring the one given to the peer
+        let events = VcardService::save(&connection, &database.objects, vcard(2, "Bob"), &[1])?;
+        assert!(is_notified(events));
+        let peer = Peer {
+            account_id: vec![3],
+            name: "Carol".into(),
+            ..Default::default()
+        };
+        let events = PeerService { verifier: None }.save(&connection, peer, &[1])?;
+        assert!(is_notified(events));
+        VcardService::save(&connection, &database.objects, vcard(3, "Charlie"), &[1])?;
+        assert_eq!("Bob, Carol", find_name()?);
+
+        // Unless named explicitly
+        let chatroom = Chatroom {
+            name: "Chatroom".into(),
+            members: vec![vec![1], vec![2], vec![3]],
+            chatroom_id: vec![],
+        };
+        ChatroomService::save(&connection, &chatroom, &[1])?;
+        let events = VcardService::save(&connection, &database.objects, vcard(2, "Robert"), &[2])?;
+        assert!(!is_notified(events));
+        assert_eq!("Chatroom", find_name()?);
+        Ok(())
+    }
+}
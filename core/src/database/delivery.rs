@@ -0,0 +1,213 @@
+use super::message::MessageService;
+use super::schema::message_delivery as Schema;
+use super::Event;
+use crate::daemon::DeliveryState;
+use crate::daemon::MessageDelivery;
+use crate::daemon::RecipientDelivery;
+use chrono::Utc;
+use diesel::prelude::*;
+use std::collections::HashMap;
+
+/// Tracks the delivery of messages sent by the local account.
+pub(crate) struct DeliveryService;
+
+impl DeliveryService {
+    /// Saves the state of the delivery to a recipient.
+    ///
+    /// A message already read by the recipient stays read.
+    pub fn save(
+        connection: &'_ SqliteConnection,
+        message_id: &[u8],
+        recipient_account_id: &[u8],
+        state: DeliveryState,
+        reason: &str,
+    ) -> QueryResult<Event> {
+        let event = Event::Delivery {
+            message_id: message_id.into(),
+            chatroom_id: MessageService::find_chatroom_id(connection, message_id)?
+                .unwrap_or_default(),
+        };
+        let state_saved = Schema::table
+            .find((message_id, recipient_account_id))
+            .select(Schema::state)
+            .first::<i32>(connection)
+            .optional()?;
+        if state_saved == Some(DeliveryState::Read.into()) {
+            return Ok(event);
+        }
+
+        let state_i32: i32 = state.into();
+        diesel::replace_into(Schema::table)
+            .values((
+                Schema::message_id.eq(message_id),
+                Schema::recipient_account_id.eq(recipient_account_id),
+                Schema::state.eq(state_i32),
+                Schema::reason.eq(reason),
+                Schema::time_updated.eq(super::float_from_time(Utc::now())),
+            ))
+            .execute(connection)?;
+        Ok(event)
+    }
+
+    /// Saves a receipt from a recipient.
+    ///
+    /// Does nothing if the message is not sent to the recipient by the local account or if the
+    /// delivery is already more advanced.
+    pub fn acknowledge(
+        connection: &'_ SqliteConnection,
+        message_id: &[u8],
+        recipient_account_id: &[u8],
+        state: DeliveryState,
+    ) -> QueryResult<Option<Event>> {
+        let state_saved = Schema::table
+            .find((message_id, recipient_account_id))
+            .select(Schema::state)
+            .first::<i32>(connection)
+            .optional()?;
+        match state_saved.and_then(DeliveryState::from_i32) {
+            Some(state_saved) if rank(state_saved) < rank(state) => {
+                Self::save(connection, message_id, recipient_account_id, state, "").map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Summarizes the deliveries of messages to all their recipients.
+    ///
+    /// See [Message::delivery](crate::daemon::Message::delivery).
+    pub fn summarize_by_messages(
+        connection: &'_ SqliteConnection,
+        message_ids: &[Vec<u8>],
+    ) -> QueryResult<HashMap<Vec<u8>, DeliveryState>> {
+        let mut summaries = HashMap::<Vec<u8>, DeliveryState>::new();
+        for (message_id, state) in Schema::table
+            .filter(Schema::message_id.eq_any(message_ids))
+            .select((Schema::message_id, Schema::state))
+            .load::<(Vec<u8>, i32)>(connection)?
+        {
+            let state = DeliveryState::from_i32(state).unwrap_or(DeliveryState::Pending);
+            summaries
+                .entry(message_id)
+                .and_modify(|summary| *summary = summarize(*summary, state))
+                .or_insert(state);
+        }
+        Ok(summaries)
+    }
+
+    pub fn find_by_message(
+        connection: &'_ SqliteConnection,
+        message_id: &[u8],
+    ) -> QueryResult<MessageDelivery> {
+        let recipients = Schema::table
+            .filter(Schema::message_id.eq(message_id))
+            .select((
+                Schema::recipient_account_id,
+                Schema::state,
+                Schema::reason,
+                Schema::time_updated,
+            ))
+            .order(Schema::recipient_account_id.asc())
+            .load::<(Vec<u8>, i32, String, f64)>(connection)?
+            .into_iter()
+            .map(
+                |(account_id, state, reason, time_updated)| RecipientDelivery {
+                    account_id,
+                    state,
+                    reason,
+                    time_updated,
+                },
+            )
+            .collect();
+        Ok(MessageDelivery { recipients })
+    }
+}
+
+/// Orders [DeliveryState]s by how far the delivery proceeds.
+fn rank(state: DeliveryState) -> u8 {
+    match state {
+        DeliveryState::Pending | DeliveryState::Rejected | DeliveryState::Failed => 0,
+        DeliveryState::Delivered => 1,
+        DeliveryState::Read => 2,
+    }
+}
+
+fn summarize(a: DeliveryState, b: DeliveryState) -> DeliveryState {
+    match (a, b) {
+        (DeliveryState::Rejected, _) | (_, DeliveryState::Rejected) => DeliveryState::Rejected,
+        (DeliveryState::Failed, _) | (_, DeliveryState::Failed) => DeliveryState::Failed,
+        _ if rank(a) <= rank(b) => a,
+        _ => b,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::changelog::Message;
+    use crate::database::Database;
+    use crate::database::Storage;
+    use crate::pki::CanonicalId;
+
+    #[test]
+    fn acknowledge() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let message = Message {
+            time: 0.0,
+            sender: vec![1],
+            recipients: vec![vec![2], vec![3]],
+            content: "Hello".into(),
+            attachment: None,
+            attachment_reference: None,
+            in_reply_to: vec![],
+            chatroom_id: vec![],
+        };
+        MessageService::update(&connection, &database.objects, &message)?;
+        let message_id = crate::database::bytes_from_hash(message.canonical_id());
+        for recipient in message.recipients.iter() {
+            DeliveryService::save(
+                &connection,
+                &message_id,
+                recipient,
+                DeliveryState::Pending,
+                "",
+            )?;
+        }
+        let summary = |connection: &SqliteConnection| -> QueryResult<DeliveryState> {
+            Ok(
+                DeliveryService::summarize_by_messages(connection, &[message_id.clone()])?
+                    [&message_id],
+            )
+        };
+
+        // Only the recipients may acknowledge
+        assert!(
+            DeliveryService::acknowledge(&connection, &message_id, &[4], DeliveryState::Read)?
+                .is_none()
+        );
+
+        // Read before the delivery is confirmed
+        DeliveryService::acknowledge(&connection, &message_id, &[2], DeliveryState::Read)?;
+        DeliveryService::save(&connection, &message_id, &[2], DeliveryState::Delivered, "")?;
+        assert!(DeliveryService::acknowledge(
+            &connection,
+            &message_id,
+            &[2],
+            DeliveryState::Delivered
+        )?
+        .is_none());
+        assert_eq!(DeliveryState::Pending, summary(&connection)?);
+
+        DeliveryService::acknowledge(&connection, &message_id, &[3], DeliveryState::Delivered)?;
+        assert_eq!(DeliveryState::Delivered, summary(&connection)?);
+
+        let states: Vec<_> = DeliveryService::find_by_message(&connection, &message_id)?
+            .recipients
+            .iter()
+            .map(RecipientDelivery::state)
+            .collect();
+        assert_eq!(vec![DeliveryState::Read, DeliveryState::Delivered], states);
+
+        Ok(())
+    }
+}
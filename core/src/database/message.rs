@@ -1,50 +1,425 @@
 use super::chatroom::ChatroomService;
+use super::delivery::DeliveryService;
 use super::object::ObjectService;
+use super::object_store::ObjectStore;
+use super::reaction::ReactionService;
+use super::schema::chatroom_read as SchemaChatroomRead;
 use super::schema::message as Schema;
+use super::schema::message_read as SchemaRead;
 use super::schema::message_recipients as SchemaRecipients;
+use super::schema::message_revision as SchemaRevision;
+use super::schema::object as SchemaObject;
+use super::schema::vcard as SchemaVcard;
 use super::Event;
+use crate::changelog::DeleteMessage;
+use crate::changelog::EditMessage;
 use crate::changelog::Message;
+use crate::daemon::message_cursor::Position;
 use crate::daemon::ChatroomMessagesSubscription;
+use crate::daemon::DeliveryState;
+use crate::daemon::MessageCursor;
+use crate::daemon::MessageSnippet;
+use crate::daemon::SearchHit;
+use crate::daemon::SearchMessagesRequest;
+use crate::daemon::SearchMessagesResponse;
 use crate::pki::CanonicalId;
 use blake3::Hash;
 use blake3::Hasher;
+use chrono::Utc;
 use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use diesel::sql_types::Binary;
+use diesel::sql_types::Double;
+use diesel::sql_types::Integer;
+use diesel::sql_types::Text;
 use std::collections::BTreeSet;
+use std::collections::HashMap;
 use uuid::Uuid;
 
+/// Number of messages in a page when the client does not specify one.
+const DEFAULT_PAGE_SIZE: u32 = 64;
+
+/// Maximum number of characters in a [MessageSnippet].
+const SNIPPET_LENGTH: usize = 100;
+
+/// Maximum number of words in the snippet of a [SearchHit].
+const SEARCH_SNIPPET_WORDS: i32 = 16;
+
+/// Marks the beginning of a match in the snippet of a [SearchHit].
+const HIGHLIGHT_START: &str = "\u{2}";
+
+/// Marks the end of a match in the snippet of a [SearchHit].
+const HIGHLIGHT_END: &str = "\u{3}";
+
+/// Searches the full-text index with optional filters, where an empty blob or a zero disables a
+/// filter.
+const SEARCH_QUERY: &str = "
+    SELECT
+        message.message_id,
+        message.chatroom_id,
+        message.time,
+        message.sender,
+        snippet(message_search, 0, ?2, ?3, '…', ?4) AS snippet
+    FROM message_search
+    INNER JOIN message ON message.search_id = message_search.rowid
+    WHERE message_search MATCH ?1
+        AND (length(?5) = 0 OR message.chatroom_id = ?5)
+        AND (length(?6) = 0 OR message.sender = ?6)
+        AND (?7 = 0 OR message.time >= ?7)
+        AND (?8 = 0 OR message.time < ?8)
+    ORDER BY message.time DESC, message.message_id DESC
+    LIMIT ?9 OFFSET ?10
+";
+
+/// Counts the messages from others not read yet in each chatroom, or in one if `?2` is not empty.
+/// A message retracted by its sender does not count.
+const UNREAD_QUERY: &str = "
+    SELECT message.chatroom_id, COUNT(*) AS count
+    FROM message
+    LEFT JOIN message_read ON message_read.message_id = message.message_id
+    WHERE message_read.message_id IS NULL
+        AND message.sender != ?1
+        AND (length(?2) = 0 OR message.chatroom_id = ?2)
+        AND NOT EXISTS (
+            SELECT 1 FROM message_revision
+            WHERE message_revision.message_id = message.message_id
+                AND message_revision.sender = message.sender
+                AND message_revision.content IS NULL
+        )
+    GROUP BY message.chatroom_id
+";
+
+/// Finds the latest message in each chatroom, or in one if `?1` is not empty.
+const LATEST_QUERY: &str = "
+    SELECT message.chatroom_id, message.message_id, message.sender, message.content
+    FROM message
+    WHERE (length(?1) = 0 OR message.chatroom_id = ?1)
+        AND NOT EXISTS (
+            SELECT 1 FROM message AS newer
+            WHERE newer.chatroom_id = message.chatroom_id
+                AND (
+                    newer.time > message.time
+                    OR (newer.time = message.time AND newer.message_id > message.message_id)
+                )
+        )
+";
+
 pub(crate) struct MessageService;
 
+/// Resolved [MessageCursor].
+///
+/// Messages are sorted by time. Messages with the same time are then sorted by their IDs.
+enum Boundary {
+    Latest,
+    Before(f64, Option<Vec<u8>>),
+    After(f64, Option<Vec<u8>>),
+}
+
+#[derive(QueryableByName)]
+struct SearchRow {
+    #[sql_type = "Binary"]
+    message_id: Vec<u8>,
+    #[sql_type = "Binary"]
+    chatroom_id: Vec<u8>,
+    #[sql_type = "Double"]
+    time: f64,
+    #[sql_type = "Binary"]
+    sender: Vec<u8>,
+    #[sql_type = "Text"]
+    snippet: String,
+}
+
+#[derive(QueryableByName)]
+struct RowidRow {
+    #[sql_type = "BigInt"]
+    rowid: i64,
+}
+
+#[derive(QueryableByName)]
+struct UnreadRow {
+    #[sql_type = "Binary"]
+    chatroom_id: Vec<u8>,
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+#[derive(QueryableByName)]
+struct LatestRow {
+    #[sql_type = "Binary"]
+    chatroom_id: Vec<u8>,
+    #[sql_type = "Binary"]
+    message_id: Vec<u8>,
+    #[sql_type = "Binary"]
+    sender: Vec<u8>,
+    #[sql_type = "Text"]
+    content: String,
+}
+
+/// Message ID, time, sender, content, attachment ID, attachment MIME and the ID of the message
+/// replied to.
+type Row = (
+    Vec<u8>,
+    f64,
+    Vec<u8>,
+    String,
+    Option<Vec<u8>>,
+    Option<String>,
+    Option<Vec<u8>>,
+);
+
+/// Latest revision of a message issued by its sender.
+enum Revision {
+    Edited { time: f64, content: String },
+    Deleted,
+}
+
 impl MessageService {
-    fn save(connection: &'_ SqliteConnection, payload: &Message) -> QueryResult<Event> {
+    fn save(
+        connection: &'_ SqliteConnection,
+        objects: &ObjectStore,
+        payload: &Message,
+    ) -> QueryResult<Event> {
         let message_id = super::bytes_from_hash(payload.canonical_id());
         let chatroom_id = super::bytes_from_hash(payload.chatroom_id());
-        let attachment_id: Option<Vec<u8>> = payload
-            .attachment
-            .as_ref()
-            .map(|obj| ObjectService::save(connection, obj))
-            .transpose()?
-            .map(|id| id.as_bytes().as_ref().into());
+        let attachment_id = match (&payload.attachment, &payload.attachment_reference) {
+            (Some(attachment), _) => Some(ObjectService::save(connection, objects, attachment)?),
+            (None, Some(reference)) => Some(ObjectService::save_reference(connection, reference)?),
+            (None, None) => None,
+        };
+
+        // A message deleted before it arrives never has its content stored
+        let content = if Self::is_deleted(connection, &message_id, &payload.sender)? {
+            ""
+        } else {
+            &payload.content
+        };
 
+        let search_id = Self::find_search_id(connection, &message_id)?;
         diesel::replace_into(Schema::table)
             .values((
                 Schema::message_id.eq(&message_id),
                 Schema::chatroom_id.eq(&chatroom_id),
                 Schema::attachment.eq(attachment_id),
-                Schema::content.eq(&payload.content),
+                Schema::content.eq(content),
                 Schema::sender.eq(&payload.sender),
                 Schema::time.eq(&payload.time),
+                Schema::in_reply_to.eq(Some(&payload.in_reply_to).filter(|id| !id.is_empty())),
             ))
             .execute(connection)?;
         Self::replace_recipients(connection, &message_id, payload.recipients.iter())?;
+        Self::reindex(connection, &message_id, search_id)?;
         Ok(Event::Message { chatroom_id })
     }
 
-    pub fn update(connection: &'_ SqliteConnection, payload: &Message) -> QueryResult<Event> {
+    pub fn update(
+        connection: &'_ SqliteConnection,
+        objects: &ObjectStore,
+        payload: &Message,
+    ) -> QueryResult<Event> {
         // Update chatroom
         ChatroomService::update_for_message(connection, &payload)?;
 
         // Update message
-        Self::save(connection, &payload)
+        Self::save(connection, objects, &payload)
+    }
+
+    /// Saves a new revision of the content of a [Message].
+    pub fn edit(
+        connection: &'_ SqliteConnection,
+        payload: &EditMessage,
+    ) -> QueryResult<Option<Event>> {
+        Self::save_revision(
+            connection,
+            &payload.message_id,
+            &payload.sender,
+            payload.time,
+            Some(&payload.content),
+        )
+    }
+
+    /// Retracts a [Message], leaving a tombstone and erasing its content and edits.
+    pub fn delete(
+        connection: &'_ SqliteConnection,
+        payload: &DeleteMessage,
+    ) -> QueryResult<Option<Event>> {
+        Self::save_revision(
+            connection,
+            &payload.message_id,
+            &payload.sender,
+            payload.time,
+            None,
+        )
+    }
+
+    /// Saves a revision unless it is not issued by the sender of the [Message].
+    ///
+    /// A revision of a [Message] not yet saved is kept and only applies if it turns out to be
+    /// issued by the sender.
+    fn save_revision(
+        connection: &'_ SqliteConnection,
+        message_id: &[u8],
+        sender: &[u8],
+        time: f64,
+        content: Option<&str>,
+    ) -> QueryResult<Option<Event>> {
+        let message = Schema::table
+            .find(message_id)
+            .select((Schema::chatroom_id, Schema::sender))
+            .first::<(Vec<u8>, Vec<u8>)>(connection)
+            .optional()?;
+        if let Some((_, message_sender)) = &message {
+            if message_sender != sender {
+                log::warn!(
+                    "Ignoring a revision of message {} not issued by its sender",
+                    hex::encode_upper(message_id)
+                );
+                return Ok(None);
+            }
+        }
+
+        // Nothing revives a deleted message, and deleting it erases its edits
+        if Self::is_deleted(connection, message_id, sender)? {
+            return Ok(None);
+        }
+        if content.is_none() {
+            diesel::delete(
+                SchemaRevision::table
+                    .filter(SchemaRevision::message_id.eq(message_id))
+                    .filter(SchemaRevision::sender.eq(sender)),
+            )
+            .execute(connection)?;
+        }
+        diesel::insert_or_ignore_into(SchemaRevision::table)
+            .values((
+                SchemaRevision::message_id.eq(message_id),
+                SchemaRevision::sender.eq(sender),
+                SchemaRevision::time.eq(time),
+                SchemaRevision::content.eq(content),
+            ))
+            .execute(connection)?;
+        if message.is_some() {
+            if content.is_none() {
+                diesel::update(Schema::table.find(message_id))
+                    .set(Schema::content.eq(""))
+                    .execute(connection)?;
+            }
+            let search_id = Self::find_search_id(connection, message_id)?;
+            Self::reindex(connection, message_id, search_id)?;
+        }
+        Ok(message.map(|(chatroom_id, _)| Event::Message { chatroom_id }))
+    }
+
+    /// Checks whether a message is deleted by `sender`, whether or not it is saved yet.
+    fn is_deleted(
+        connection: &'_ SqliteConnection,
+        message_id: &[u8],
+        sender: &[u8],
+    ) -> QueryResult<bool> {
+        diesel::select(diesel::dsl::exists(
+            SchemaRevision::table
+                .filter(SchemaRevision::message_id.eq(message_id))
+                .filter(SchemaRevision::sender.eq(sender))
+                .filter(SchemaRevision::content.is_null()),
+        ))
+        .get_result(connection)
+    }
+
+    /// Finds the row of a message in the full-text index.
+    fn find_search_id(
+        connection: &'_ SqliteConnection,
+        message_id: &[u8],
+    ) -> QueryResult<Option<i64>> {
+        Schema::table
+            .find(message_id)
+            .select(Schema::search_id)
+            .first::<Option<i64>>(connection)
+            .optional()
+            .map(Option::flatten)
+    }
+
+    /// Updates the full-text index of a message to its latest revision, replacing its row at
+    /// `search_id` if it is already indexed.
+    fn reindex(
+        connection: &'_ SqliteConnection,
+        message_id: &[u8],
+        search_id: Option<i64>,
+    ) -> QueryResult<()> {
+        if let Some(search_id) = search_id {
+            diesel::sql_query("DELETE FROM message_search WHERE rowid = ?")
+                .bind::<BigInt, _>(search_id)
+                .execute(connection)?;
+            diesel::update(Schema::table.find(message_id))
+                .set(Schema::search_id.eq(None::<i64>))
+                .execute(connection)?;
+        }
+        let (sender, content) = Schema::table
+            .find(message_id)
+            .select((Schema::sender, Schema::content))
+            .first::<(Vec<u8>, String)>(connection)?;
+        let content = match Self::find_revisions(connection, vec![message_id.into()])?
+            .remove(&(message_id.into(), sender))
+        {
+            Some(Revision::Edited { content, .. }) => content,
+            Some(Revision::Deleted) => return Ok(()),
+            None => content,
+        };
+        diesel::sql_query("INSERT INTO message_search (content) VALUES (?)")
+            .bind::<Text, _>(content)
+            .execute(connection)?;
+        let search_id = diesel::sql_query("SELECT last_insert_rowid() AS rowid")
+            .get_result::<RowidRow>(connection)?
+            .rowid;
+        diesel::update(Schema::table.find(message_id))
+            .set(Schema::search_id.eq(search_id))
+            .execute(connection)
+            .map(drop)
+    }
+
+    /// Searches the latest revision of messages, newest first.
+    ///
+    /// Retracted messages are never found.
+    pub fn search(
+        connection: &SqliteConnection,
+        request: &SearchMessagesRequest,
+    ) -> QueryResult<SearchMessagesResponse> {
+        let expression = match match_expression(&request.query) {
+            Some(expression) => expression,
+            None => return Ok(Default::default()),
+        };
+        let limit = if request.limit == 0 {
+            DEFAULT_PAGE_SIZE
+        } else {
+            request.limit
+        };
+        let rows = diesel::sql_query(SEARCH_QUERY)
+            .bind::<Text, _>(expression)
+            .bind::<Text, _>(HIGHLIGHT_START)
+            .bind::<Text, _>(HIGHLIGHT_END)
+            .bind::<Integer, _>(SEARCH_SNIPPET_WORDS)
+            .bind::<Binary, _>(&request.chatroom_id)
+            .bind::<Binary, _>(&request.sender)
+            .bind::<Double, _>(request.time_since)
+            .bind::<Double, _>(request.time_until)
+            .bind::<BigInt, _>(i64::from(limit))
+            .bind::<BigInt, _>(i64::from(request.offset))
+            .load::<SearchRow>(connection)?;
+
+        let sender_names = Self::find_names(connection, rows.iter().map(|row| &row.sender))?;
+        let hits = rows
+            .into_iter()
+            .map(|row| SearchHit {
+                message_id: row.message_id,
+                chatroom_id: row.chatroom_id,
+                time: row.time,
+                sender: crate::daemon::Vcard {
+                    name: sender_names.get(&row.sender).cloned().unwrap_or_default(),
+                    account_id: row.sender,
+                }
+                .into(),
+                snippet: row.snippet,
+            })
+            .collect();
+        Ok(SearchMessagesResponse { hits })
     }
 
     fn replace_recipients<'m>(
@@ -71,19 +446,640 @@ impl MessageService {
         Ok(())
     }
 
+    /// Finds a page of messages in a chatroom.
+    ///
+    /// The page is positioned by `cursor` and contains at most `limit` messages. If `limit` is 0,
+    /// [DEFAULT_PAGE_SIZE] applies. Each message shows its latest revision, or a tombstone if it is
+    /// deleted.
     pub fn find_by_chatroom(
         connection: &SqliteConnection,
         chatroom_id: &[u8],
+        cursor: Option<&MessageCursor>,
+        limit: u32,
     ) -> QueryResult<ChatroomMessagesSubscription> {
-        todo!()
+        let boundary = match cursor.and_then(|c| c.position.as_ref()) {
+            None => Boundary::Latest,
+            Some(Position::BeforeTime(time)) => Boundary::Before(*time, None),
+            Some(Position::AfterTime(time)) => Boundary::After(*time, None),
+            Some(Position::BeforeMessageId(message_id)) => Boundary::Before(
+                Self::find_time(connection, message_id)?,
+                Some(message_id.clone()),
+            ),
+            Some(Position::AfterMessageId(message_id)) => Boundary::After(
+                Self::find_time(connection, message_id)?,
+                Some(message_id.clone()),
+            ),
+        };
+        let limit = if limit == 0 { DEFAULT_PAGE_SIZE } else { limit };
+
+        let query = Schema::table
+            .left_join(
+                SchemaObject::table.on(SchemaObject::object_id.nullable().eq(Schema::attachment)),
+            )
+            .filter(Schema::chatroom_id.eq(chatroom_id))
+            .select((
+                Schema::message_id,
+                Schema::time,
+                Schema::sender,
+                Schema::content,
+                Schema::attachment,
+                SchemaObject::mime.nullable(),
+                Schema::in_reply_to,
+            ))
+            .into_boxed();
+        let ascending = matches!(boundary, Boundary::After(_, _));
+        let query = match boundary {
+            Boundary::Latest => query,
+            Boundary::Before(time, None) => query.filter(Schema::time.lt(time)),
+            Boundary::Before(time, Some(message_id)) => query.filter(
+                Schema::time
+                    .lt(time)
+                    .or(Schema::time.eq(time).and(Schema::message_id.lt(message_id))),
+            ),
+            Boundary::After(time, None) => query.filter(Schema::time.gt(time)),
+            Boundary::After(time, Some(message_id)) => query.filter(
+                Schema::time
+                    .gt(time)
+                    .or(Schema::time.eq(time).and(Schema::message_id.gt(message_id))),
+            ),
+        };
+        let query = if ascending {
+            query.order((Schema::time.asc(), Schema::message_id.asc()))
+        } else {
+            query.order((Schema::time.desc(), Schema::message_id.desc()))
+        };
+        let mut rows = query.limit(limit.into()).load::<Row>(connection)?;
+        if !ascending {
+            rows.reverse();
+        }
+
+        Self::present(connection, rows).map(|messages| ChatroomMessagesSubscription { messages })
+    }
+
+    /// Finds the replies to a message, oldest first.
+    pub fn find_replies(
+        connection: &SqliteConnection,
+        message_id: &[u8],
+    ) -> QueryResult<ChatroomMessagesSubscription> {
+        let rows = Schema::table
+            .left_join(
+                SchemaObject::table.on(SchemaObject::object_id.nullable().eq(Schema::attachment)),
+            )
+            .filter(Schema::in_reply_to.eq(message_id))
+            .select((
+                Schema::message_id,
+                Schema::time,
+                Schema::sender,
+                Schema::content,
+                Schema::attachment,
+                SchemaObject::mime.nullable(),
+                Schema::in_reply_to,
+            ))
+            .order((Schema::time.asc(), Schema::message_id.asc()))
+            .load::<Row>(connection)?;
+        Self::present(connection, rows).map(|messages| ChatroomMessagesSubscription { messages })
+    }
+
+    /// Marks messages in a chatroom received from others as read, up to `message_id` or the latest
+    /// one if [None].
+    ///
+    /// The last-read marker of the chatroom only moves forward. Returns the senders and IDs of the
+    /// messages newly marked.
+    pub fn mark_read(
+        connection: &SqliteConnection,
+        chatroom_id: &[u8],
+        account_id: &[u8],
+        message_id: Option<&[u8]>,
+    ) -> QueryResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let chatroom = Schema::table
+            .filter(Schema::chatroom_id.eq(chatroom_id))
+            .select((Schema::time, Schema::message_id));
+        let (time, last_message_id) = match message_id {
+            Some(message_id) => chatroom
+                .filter(Schema::message_id.eq(message_id))
+                .first::<(f64, Vec<u8>)>(connection)?,
+            None => match chatroom
+                .order((Schema::time.desc(), Schema::message_id.desc()))
+                .first::<(f64, Vec<u8>)>(connection)
+                .optional()?
+            {
+                Some(latest) => latest,
+                None => return Ok(vec![]),
+            },
+        };
+
+        let unread = Schema::table
+            .left_join(SchemaRead::table)
+            .filter(Schema::chatroom_id.eq(chatroom_id))
+            .filter(Schema::sender.ne(account_id))
+            .filter(SchemaRead::message_id.nullable().is_null())
+            .filter(
+                Schema::time.lt(time).or(Schema::time
+                    .eq(time)
+                    .and(Schema::message_id.le(&last_message_id))),
+            )
+            .select((Schema::sender, Schema::message_id))
+            .load::<(Vec<u8>, Vec<u8>)>(connection)?;
+        let time_read = super::float_from_time(Utc::now());
+
+        let marker = SchemaChatroomRead::table
+            .find(chatroom_id)
+            .select((SchemaChatroomRead::time, SchemaChatroomRead::message_id))
+            .first::<(f64, Vec<u8>)>(connection)
+            .optional()?;
+        if marker.map_or(true, |marker| marker < (time, last_message_id.clone())) {
+            diesel::replace_into(SchemaChatroomRead::table)
+                .values((
+                    SchemaChatroomRead::chatroom_id.eq(chatroom_id),
+                    SchemaChatroomRead::message_id.eq(&last_message_id),
+                    SchemaChatroomRead::time.eq(time),
+                    SchemaChatroomRead::time_read.eq(time_read),
+                ))
+                .execute(connection)?;
+        }
+
+        let rows: Vec<_> = unread
+            .iter()
+            .map(|(_, message_id)| {
+                (
+                    SchemaRead::message_id.eq(message_id),
+                    SchemaRead::time_read.eq(time_read),
+                )
+            })
+            .collect();
+        diesel::insert_or_ignore_into(SchemaRead::table)
+            .values(rows)
+            .execute(connection)?;
+        Ok(unread)
+    }
+
+    /// Finds the last-read marker of each chatroom, or only of `chatroom_id`, as the message ID and
+    /// when it is marked.
+    ///
+    /// Chatrooms never marked read are absent.
+    pub fn find_last_read(
+        connection: &SqliteConnection,
+        chatroom_id: Option<&[u8]>,
+    ) -> QueryResult<HashMap<Vec<u8>, (Vec<u8>, f64)>> {
+        let mut query = SchemaChatroomRead::table
+            .select((
+                SchemaChatroomRead::chatroom_id,
+                SchemaChatroomRead::message_id,
+                SchemaChatroomRead::time_read,
+            ))
+            .into_boxed();
+        if let Some(chatroom_id) = chatroom_id {
+            query = query.filter(SchemaChatroomRead::chatroom_id.eq(chatroom_id));
+        }
+        query
+            .load::<(Vec<u8>, Vec<u8>, f64)>(connection)
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|(chatroom_id, message_id, time_read)| {
+                        (chatroom_id, (message_id, time_read))
+                    })
+                    .collect()
+            })
+    }
+
+    /// Counts the messages from others not read yet in each chatroom, or only in `chatroom_id`.
+    ///
+    /// Chatrooms without any such message are absent.
+    pub fn count_unread(
+        connection: &SqliteConnection,
+        account_id: &[u8],
+        chatroom_id: Option<&[u8]>,
+    ) -> QueryResult<HashMap<Vec<u8>, u32>> {
+        diesel::sql_query(UNREAD_QUERY)
+            .bind::<Binary, _>(account_id)
+            .bind::<Binary, _>(chatroom_id.unwrap_or_default())
+            .load::<UnreadRow>(connection)
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| (row.chatroom_id, row.count as u32))
+                    .collect()
+            })
+    }
+
+    /// Finds a snippet of the latest message in each chatroom, or only in `chatroom_id`.
+    ///
+    /// Chatrooms without any message are absent.
+    pub fn find_latest(
+        connection: &SqliteConnection,
+        chatroom_id: Option<&[u8]>,
+    ) -> QueryResult<HashMap<Vec<u8>, MessageSnippet>> {
+        let rows = diesel::sql_query(LATEST_QUERY)
+            .bind::<Binary, _>(chatroom_id.unwrap_or_default())
+            .load::<LatestRow>(connection)?;
+        let sender_names = Self::find_names(connection, rows.iter().map(|row| &row.sender))?;
+        let revisions = Self::find_revisions(
+            connection,
+            rows.iter().map(|row| row.message_id.clone()).collect(),
+        )?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let revision = revisions.get(&(row.message_id, row.sender.clone()));
+                let snippet = MessageSnippet {
+                    sender: crate::daemon::Vcard {
+                        name: sender_names.get(&row.sender).cloned().unwrap_or_default(),
+                        account_id: row.sender,
+                    }
+                    .into(),
+                    ..snippet(row.content, revision)
+                };
+                (row.chatroom_id, snippet)
+            })
+            .collect())
+    }
+
+    /// Finds the ID of the chatroom of a message.
+    pub fn find_chatroom_id(
+        connection: &SqliteConnection,
+        message_id: &[u8],
+    ) -> QueryResult<Option<Vec<u8>>> {
+        Schema::table
+            .find(message_id)
+            .select(Schema::chatroom_id)
+            .first(connection)
+            .optional()
+    }
+
+    /// Presents messages with their latest revisions, reactions and the messages they reply to.
+    ///
+    /// Each message shows its latest revision, or a tombstone if it is deleted.
+    fn present(
+        connection: &SqliteConnection,
+        rows: Vec<Row>,
+    ) -> QueryResult<Vec<crate::daemon::Message>> {
+        let message_ids: Vec<_> = rows
+            .iter()
+            .map(|(message_id, ..)| message_id.clone())
+            .collect();
+        let parent_ids: Vec<_> = rows
+            .iter()
+            .filter_map(|(.., in_reply_to)| in_reply_to.clone())
+            .collect();
+        let parents = Schema::table
+            .filter(Schema::message_id.eq_any(&parent_ids))
+            .select((Schema::message_id, Schema::sender, Schema::content))
+            .load::<(Vec<u8>, Vec<u8>, String)>(connection)?;
+
+        // Join with the senders' Vcard
+        let sender_names = Self::find_names(
+            connection,
+            rows.iter()
+                .map(|(_, _, sender, ..)| sender)
+                .chain(parents.iter().map(|(_, sender, _)| sender)),
+        )?;
+        let vcard = |account_id: Vec<u8>| crate::daemon::Vcard {
+            name: sender_names.get(&account_id).cloned().unwrap_or_default(),
+            account_id,
+        };
+
+        let mut revisions = Self::find_revisions(
+            connection,
+            message_ids
+                .iter()
+                .chain(parent_ids.iter())
+                .cloned()
+                .collect(),
+        )?;
+        let mut reactions = ReactionService::count_by_messages(connection, &message_ids)?;
+        let deliveries = DeliveryService::summarize_by_messages(connection, &message_ids)?;
+        let attachment_ids: Vec<_> = rows
+            .iter()
+            .filter_map(|(_, _, _, _, attachment_id, ..)| attachment_id.clone())
+            .collect();
+        let pending: HashMap<_, _> =
+            ObjectService::find_pending_by_ids(connection, &attachment_ids)?
+                .into_iter()
+                .map(|reference| (reference.object_id.clone(), reference))
+                .collect();
+
+        let parents: HashMap<Vec<u8>, MessageSnippet> = parents
+            .into_iter()
+            .map(|(message_id, sender, content)| {
+                let revision = revisions.get(&(message_id.clone(), sender.clone()));
+                let snippet = MessageSnippet {
+                    sender: vcard(sender).into(),
+                    ..snippet(content, revision)
+                };
+                (message_id, snippet)
+            })
+            .collect();
+
+        let messages = rows
+            .into_iter()
+            .map(
+                |(
+                    message_id,
+                    time,
+                    sender,
+                    content,
+                    attachment_id,
+                    attachment_mime,
+                    in_reply_to,
+                )| {
+                    let revision = revisions.remove(&(message_id.clone(), sender.clone()));
+                    let mut message = crate::daemon::Message {
+                        time,
+                        sender: vcard(sender).into(),
+                        content,
+                        attachment_mime: attachment_mime
+                            .or_else(|| {
+                                let reference = pending.get(attachment_id.as_ref()?)?;
+                                Some(reference.mime.clone())
+                            })
+                            .unwrap_or_default(),
+                        attachment_pending: attachment_id
+                            .as_ref()
+                            .map_or(false, |id| pending.contains_key(id)),
+                        attachment_id: attachment_id.unwrap_or_default(),
+                        reactions: reactions.remove(&message_id).unwrap_or_default(),
+                        delivery: deliveries
+                            .get(&message_id)
+                            .copied()
+                            .unwrap_or(DeliveryState::Pending)
+                            .into(),
+                        parent: in_reply_to
+                            .as_ref()
+                            .and_then(|in_reply_to| parents.get(in_reply_to))
+                            .cloned(),
+                        in_reply_to: in_reply_to.unwrap_or_default(),
+                        message_id,
+                        ..Default::default()
+                    };
+                    match revision {
+                        Some(Revision::Edited { time, content }) => {
+                            message.content = content;
+                            message.time_edited = time;
+                        }
+                        Some(Revision::Deleted) => {
+                            message.content.clear();
+                            message.attachment_id.clear();
+                            message.attachment_mime.clear();
+                            message.attachment_pending = false;
+                            message.reactions.clear();
+                            message.deleted = true;
+                        }
+                        None => {}
+                    }
+                    message
+                },
+            )
+            .collect();
+        Ok(messages)
+    }
+
+    /// Finds the latest revisions of messages issued by anyone, where a deletion overrides any
+    /// edit.
+    fn find_revisions(
+        connection: &SqliteConnection,
+        message_ids: Vec<Vec<u8>>,
+    ) -> QueryResult<HashMap<(Vec<u8>, Vec<u8>), Revision>> {
+        let mut revisions = HashMap::<(Vec<u8>, Vec<u8>), Revision>::new();
+        for (message_id, sender, time, content) in SchemaRevision::table
+            .filter(SchemaRevision::message_id.eq_any(message_ids))
+            .select((
+                SchemaRevision::message_id,
+                SchemaRevision::sender,
+                SchemaRevision::time,
+                SchemaRevision::content,
+            ))
+            .order((SchemaRevision::time.asc(), SchemaRevision::content.asc()))
+            .load::<(Vec<u8>, Vec<u8>, f64, Option<String>)>(connection)?
+        {
+            let key = (message_id, sender);
+            if let Some(Revision::Deleted) = revisions.get(&key) {
+                continue;
+            }
+            let revision = match content {
+                Some(content) => Revision::Edited { time, content },
+                None => Revision::Deleted,
+            };
+            revisions.insert(key, revision);
+        }
+        Ok(revisions)
+    }
+
+    /// Exports all [Message]s as changelog, oldest first.
+    ///
+    /// Deleted messages are left out, as their content is erased.
+    pub fn export(connection: &SqliteConnection) -> QueryResult<Vec<Message>> {
+        let deleted: BTreeSet<(Vec<u8>, Vec<u8>)> = SchemaRevision::table
+            .filter(SchemaRevision::content.is_null())
+            .select((SchemaRevision::message_id, SchemaRevision::sender))
+            .load(connection)?
+            .into_iter()
+            .collect();
+        Ok(Self::load(connection, None)?
+            .into_iter()
+            .filter(|(message_id, message)| {
+                !deleted.contains(&(message_id.clone(), message.sender.clone()))
+            })
+            .map(|(_, message)| message)
+            .collect())
+    }
+
+    /// Finds [Message]s by their IDs, oldest first.
+    pub fn find_by_ids(
+        connection: &SqliteConnection,
+        message_ids: &[Vec<u8>],
+    ) -> QueryResult<Vec<Message>> {
+        Self::load(connection, Some(message_ids))
+            .map(|messages| messages.into_iter().map(|(_, message)| message).collect())
+    }
+
+    pub fn find_by_id(
+        connection: &SqliteConnection,
+        message_id: &[u8],
+    ) -> QueryResult<Option<Message>> {
+        let message_ids = [message_id.to_vec()];
+        Self::load(connection, Some(&message_ids[..]))
+            .map(|mut messages| messages.pop().map(|(_, message)| message))
+    }
+
+    /// Finds the IDs of the messages in a chatroom sent by an account from `lower_bound`
+    /// (inclusive) to `upper_bound` (exclusive), in bytewise order.
+    ///
+    /// An empty `upper_bound` is unbounded. Messages deleted by the account are left out, as their
+    /// content is erased.
+    pub fn find_ids_in_range(
+        connection: &SqliteConnection,
+        chatroom_id: &[u8],
+        sender: &[u8],
+        lower_bound: &[u8],
+        upper_bound: &[u8],
+    ) -> QueryResult<Vec<Vec<u8>>> {
+        let query = Schema::table
+            .filter(Schema::chatroom_id.eq(chatroom_id))
+            .filter(Schema::sender.eq(sender))
+            .filter(Schema::message_id.ge(lower_bound))
+            .filter(
+                Schema::message_id.ne_all(
+                    SchemaRevision::table
+                        .filter(SchemaRevision::sender.eq(sender))
+                        .filter(SchemaRevision::content.is_null())
+                        .select(SchemaRevision::message_id),
+                ),
+            )
+            .select(Schema::message_id)
+            .order(Schema::message_id.asc())
+            .into_boxed();
+        if upper_bound.is_empty() {
+            query.load(connection)
+        } else {
+            query
+                .filter(Schema::message_id.lt(upper_bound))
+                .load(connection)
+        }
+    }
+
+    /// Filters out the IDs of the messages already saved.
+    pub fn find_absent(
+        connection: &SqliteConnection,
+        message_ids: impl IntoIterator<Item = Vec<u8>>,
+    ) -> QueryResult<Vec<Vec<u8>>> {
+        let message_ids: Vec<_> = message_ids.into_iter().collect();
+        let present: BTreeSet<Vec<u8>> = Schema::table
+            .filter(Schema::message_id.eq_any(&message_ids))
+            .select(Schema::message_id)
+            .load::<Vec<u8>>(connection)?
+            .into_iter()
+            .collect();
+        Ok(message_ids
+            .into_iter()
+            .filter(|message_id| !present.contains(message_id))
+            .collect())
+    }
+
+    /// Loads [Message]s along with their IDs, which differ from their canonical IDs once their
+    /// content is erased.
+    fn load(
+        connection: &SqliteConnection,
+        message_ids: Option<&[Vec<u8>]>,
+    ) -> QueryResult<Vec<(Vec<u8>, Message)>> {
+        let mut recipients_query = SchemaRecipients::table
+            .select((
+                SchemaRecipients::message_id,
+                SchemaRecipients::recipient_account_id,
+            ))
+            .order(SchemaRecipients::recipient_account_id.asc())
+            .into_boxed();
+        let mut query = Schema::table
+            .select((
+                Schema::message_id,
+                Schema::chatroom_id,
+                Schema::time,
+                Schema::sender,
+                Schema::content,
+                Schema::attachment,
+                Schema::in_reply_to,
+            ))
+            .order((Schema::time.asc(), Schema::message_id.asc()))
+            .into_boxed();
+        if let Some(message_ids) = message_ids {
+            recipients_query =
+                recipients_query.filter(SchemaRecipients::message_id.eq_any(message_ids.to_vec()));
+            query = query.filter(Schema::message_id.eq_any(message_ids.to_vec()));
+        }
+
+        let mut recipients = HashMap::<Vec<u8>, Vec<Vec<u8>>>::new();
+        for (message_id, recipient) in recipients_query.load::<(Vec<u8>, Vec<u8>)>(connection)? {
+            recipients.entry(message_id).or_default().push(recipient);
+        }
+        let rows = query.load::<(
+            Vec<u8>,
+            Vec<u8>,
+            f64,
+            Vec<u8>,
+            String,
+            Option<Vec<u8>>,
+            Option<Vec<u8>>,
+        )>(connection)?;
+
+        // Attachments are only referenced, so that their content is not read with the connection
+        // held
+        let attachment_ids: Vec<_> = rows
+            .iter()
+            .filter_map(|(_, _, _, _, _, attachment_id, ..)| attachment_id.clone())
+            .collect();
+        let mut references = ObjectService::find_references(connection, &attachment_ids)?;
+
+        let messages = rows
+            .into_iter()
+            .map(
+                |(message_id, chatroom_id, time, sender, content, attachment_id, in_reply_to)| {
+                    let mut message = Message {
+                        time,
+                        sender,
+                        recipients: recipients.remove(&message_id).unwrap_or_default(),
+                        content,
+                        attachment: None,
+                        attachment_reference: attachment_id
+                            .and_then(|attachment_id| references.remove(&attachment_id)),
+                        in_reply_to: in_reply_to.unwrap_or_default(),
+                        chatroom_id: vec![],
+                    };
+
+                    // Only a group chatroom has an ID other than the one derived from its members
+                    if message.chatroom_id().as_bytes()[..] != chatroom_id[..] {
+                        message.chatroom_id = chatroom_id;
+                    }
+                    (message_id, message)
+                },
+            )
+            .collect();
+        Ok(messages)
+    }
+
+    fn find_time(connection: &SqliteConnection, message_id: &[u8]) -> QueryResult<f64> {
+        Schema::table
+            .find(message_id)
+            .select(Schema::time)
+            .first(connection)
+    }
+
+    /// Finds the names of accounts in their [Vcard](crate::changelog::Vcard)s.
+    fn find_names<'a>(
+        connection: &SqliteConnection,
+        account_ids: impl Iterator<Item = &'a Vec<u8>>,
+    ) -> QueryResult<HashMap<Vec<u8>, String>> {
+        let account_ids: BTreeSet<&Vec<u8>> = account_ids.collect();
+        SchemaVcard::table
+            .filter(SchemaVcard::account_id.eq_any(account_ids.into_iter().collect::<Vec<_>>()))
+            .select((SchemaVcard::account_id, SchemaVcard::name))
+            .load::<(Vec<u8>, String)>(connection)
+            .map(|rows| rows.into_iter().collect())
+    }
+}
+
+/// Shows the beginning of the latest revision of a message.
+fn snippet(content: String, revision: Option<&Revision>) -> MessageSnippet {
+    match revision {
+        Some(Revision::Edited { content, .. }) => MessageSnippet {
+            content: content.chars().take(SNIPPET_LENGTH).collect(),
+            ..Default::default()
+        },
+        Some(Revision::Deleted) => MessageSnippet {
+            deleted: true,
+            ..Default::default()
+        },
+        None => MessageSnippet {
+            content: content.chars().take(SNIPPET_LENGTH).collect(),
+            ..Default::default()
+        },
     }
 }
 
 impl crate::changelog::Message {
     pub fn chatroom_id(&self) -> Hash {
-        let recipients = self.recipients.iter();
-        let members = recipients.chain(std::iter::once(&self.sender));
-        super::chatroom::chatroom_id(members)
+        super::chatroom::parse_group_id(&self.chatroom_id).unwrap_or_else(|| {
+            let recipients = self.recipients.iter();
+            let members = recipients.chain(std::iter::once(&self.sender));
+            super::chatroom::chatroom_id(members)
+        })
     }
 }
 
@@ -91,7 +1087,14 @@ impl CanonicalId for crate::changelog::Message {
     fn canonical_id(&self) -> Hash {
         let mut hasher = Hasher::default();
 
-        hasher.update(b"Viska message");
+        // Messages without newer fields keep their IDs
+        if !self.chatroom_id.is_empty() {
+            hasher.update(b"Viska message v3");
+        } else if self.in_reply_to.is_empty() {
+            hasher.update(b"Viska message");
+        } else {
+            hasher.update(b"Viska message v2");
+        }
 
         hasher.update(&self.sender.len().to_be_bytes());
         hasher.update(&self.sender);
@@ -107,11 +1110,386 @@ impl CanonicalId for crate::changelog::Message {
         hasher.update(&self.content.len().to_be_bytes());
         hasher.update(self.content.as_bytes());
 
-        if let Some(attachment) = &self.attachment {
-            hasher.update(&blake3::OUT_LEN.to_be_bytes());
-            hasher.update(attachment.canonical_id().as_bytes());
+        // A referenced attachment hashes the same as an inline one
+        let attachment_id = match (&self.attachment, &self.attachment_reference) {
+            (Some(attachment), _) => Some(super::bytes_from_hash(attachment.canonical_id())),
+            (None, Some(reference)) => Some(reference.object_id.clone()),
+            (None, None) => None,
+        };
+        if let Some(attachment_id) = attachment_id {
+            hasher.update(&attachment_id.len().to_be_bytes());
+            hasher.update(&attachment_id);
+        }
+
+        // Always present since v3, so that it is not mistaken for an attachment
+        if !self.in_reply_to.is_empty() || !self.chatroom_id.is_empty() {
+            hasher.update(&self.in_reply_to.len().to_be_bytes());
+            hasher.update(&self.in_reply_to);
+        }
+
+        if !self.chatroom_id.is_empty() {
+            hasher.update(&self.chatroom_id.len().to_be_bytes());
+            hasher.update(&self.chatroom_id);
         }
 
         hasher.finalize()
     }
 }
+
+/// Converts a search query into an FTS5 expression matching all of its words, where the last one
+/// may be incomplete.
+///
+/// Every word is quoted, so that no character in the query is interpreted as an operator.
+fn match_expression(query: &str) -> Option<String> {
+    let words: Vec<_> = query
+        .split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" ") + "*")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Database;
+    use crate::database::Storage;
+
+    #[test]
+    fn find_by_chatroom_paginated() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let messages: Vec<_> = (0..8_i32)
+            .map(|n| Message {
+                time: n.into(),
+                sender: vec![1],
+                recipients: vec![vec![2]],
+                content: n.to_string(),
+                attachment: None,
+                attachment_reference: None,
+                in_reply_to: vec![],
+                chatroom_id: vec![],
+            })
+            .collect();
+        for message in messages.iter() {
+            MessageService::update(&connection, &database.objects, message)?;
+        }
+        let chatroom_id = super::super::bytes_from_hash(messages[0].chatroom_id());
+        let contents = |subscription: ChatroomMessagesSubscription| {
+            subscription
+                .messages
+                .into_iter()
+                .map(|message| message.content)
+                .collect::<Vec<_>>()
+        };
+
+        let latest = MessageService::find_by_chatroom(&connection, &chatroom_id, None, 3)?;
+        assert_eq!(vec!["5", "6", "7"], contents(latest));
+
+        let cursor = MessageCursor {
+            position: Position::BeforeMessageId(bytes_from_message(&messages[5])).into(),
+        };
+        let before = MessageService::find_by_chatroom(&connection, &chatroom_id, Some(&cursor), 3)?;
+        assert_eq!(vec!["2", "3", "4"], contents(before));
+
+        let cursor = MessageCursor {
+            position: Position::AfterTime(5.0).into(),
+        };
+        let after = MessageService::find_by_chatroom(&connection, &chatroom_id, Some(&cursor), 0)?;
+        assert_eq!(vec!["6", "7"], contents(after));
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_by_chatroom_revised() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let messages: Vec<_> = (0..2_i32)
+            .map(|n| Message {
+                time: n.into(),
+                sender: vec![1],
+                recipients: vec![vec![2]],
+                content: n.to_string(),
+                attachment: None,
+                attachment_reference: None,
+                in_reply_to: vec![],
+                chatroom_id: vec![],
+            })
+            .collect();
+        for message in messages.iter() {
+            MessageService::update(&connection, &database.objects, message)?;
+        }
+        let chatroom_id = super::super::bytes_from_hash(messages[0].chatroom_id());
+        let edit = |n: usize, sender: u8, time: f64, content: &str| EditMessage {
+            message_id: bytes_from_message(&messages[n]),
+            sender: vec![sender],
+            time,
+            content: content.into(),
+        };
+
+        // Only the latest edit by the sender applies
+        MessageService::edit(&connection, &edit(0, 1, 20.0, "0 edited twice"))?;
+        MessageService::edit(&connection, &edit(0, 1, 10.0, "0 edited"))?;
+        assert!(MessageService::edit(&connection, &edit(0, 2, 30.0, "Forged"))?.is_none());
+
+        // Deletion overrides any edit, even a later one
+        MessageService::delete(
+            &connection,
+            &DeleteMessage {
+                message_id: bytes_from_message(&messages[1]),
+                sender: vec![1],
+                time: 10.0,
+            },
+        )?;
+        MessageService::edit(&connection, &edit(1, 1, 20.0, "1 edited"))?;
+
+        let found = MessageService::find_by_chatroom(&connection, &chatroom_id, None, 0)?.messages;
+        assert_eq!("0 edited twice", found[0].content);
+        assert_eq!(20.0, found[0].time_edited);
+        assert!(!found[0].deleted);
+        assert_eq!("", found[1].content);
+        assert!(found[1].deleted);
+
+        // The content of a deleted message is erased
+        let content: String = Schema::table
+            .find(bytes_from_message(&messages[1]))
+            .select(Schema::content)
+            .first(&*connection)?;
+        assert_eq!("", content);
+        assert_eq!(
+            vec![messages[0].clone()],
+            MessageService::export(&connection)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_replies() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let parent = Message {
+            time: 0.0,
+            sender: vec![1],
+            recipients: vec![vec![2]],
+            content: "Parent".repeat(100),
+            attachment: None,
+            attachment_reference: None,
+            in_reply_to: vec![],
+            chatroom_id: vec![],
+        };
+        let reply = Message {
+            time: 1.0,
+            sender: vec![2],
+            recipients: vec![vec![1]],
+            content: "Reply".into(),
+            attachment: None,
+            attachment_reference: None,
+            in_reply_to: bytes_from_message(&parent),
+            chatroom_id: vec![],
+        };
+        assert_ne!(
+            reply.canonical_id(),
+            Message {
+                in_reply_to: vec![],
+                ..reply.clone()
+            }
+            .canonical_id()
+        );
+        MessageService::update(&connection, &database.objects, &parent)?;
+        MessageService::update(&connection, &database.objects, &reply)?;
+
+        let replies = MessageService::find_replies(&connection, &bytes_from_message(&parent))?;
+        assert_eq!(1, replies.messages.len());
+        assert_eq!("Reply", replies.messages[0].content);
+        assert_eq!(bytes_from_message(&parent), replies.messages[0].in_reply_to);
+        let snippet = replies.messages[0].parent.as_ref().unwrap();
+        assert_eq!(SNIPPET_LENGTH, snippet.content.chars().count());
+        assert_eq!(vec![1], snippet.sender.as_ref().unwrap().account_id);
+
+        // Exported as it is received
+        assert!(MessageService::export(&connection)?.contains(&reply));
+
+        Ok(())
+    }
+
+    #[test]
+    fn search() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let message = |n: i32, sender: u8, content: &str| Message {
+            time: n.into(),
+            sender: vec![sender],
+            recipients: vec![vec![3 - sender]],
+            content: content.into(),
+            attachment: None,
+            attachment_reference: None,
+            in_reply_to: vec![],
+            chatroom_id: vec![],
+        };
+        let messages = vec![
+            message(0, 1, "Where shall we meet?"),
+            message(1, 2, "Meeting at the station"),
+            message(2, 1, "The \"station\" (north)?"),
+            message(3, 2, "Nope"),
+        ];
+        for message in messages.iter() {
+            MessageService::update(&connection, &database.objects, message)?;
+        }
+        let search = |query: &str, sender: Vec<u8>, time_since: f64| {
+            let request = SearchMessagesRequest {
+                query: query.into(),
+                sender,
+                time_since,
+                ..Default::default()
+            };
+            MessageService::search(&connection, &request).map(|response| {
+                response
+                    .hits
+                    .into_iter()
+                    .map(|hit| hit.snippet)
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        // Newest first, the last word being a prefix
+        assert_eq!(
+            vec![
+                "The \"\u{2}station\u{3}\" (north)?",
+                "Meeting at the \u{2}station\u{3}"
+            ],
+            search("station", vec![], 0.0)?
+        );
+        assert_eq!(2, search("mee", vec![], 0.0)?.len());
+        assert_eq!(1, search("\"north)", vec![], 0.0)?.len());
+        assert_eq!(1, search("station", vec![2], 0.0)?.len());
+        assert_eq!(1, search("station", vec![], 2.0)?.len());
+        assert!(search("  ", vec![], 0.0)?.is_empty());
+
+        // Only the latest revision is found
+        MessageService::edit(
+            &connection,
+            &EditMessage {
+                message_id: bytes_from_message(&messages[3]),
+                sender: vec![2],
+                time: 10.0,
+                content: "Yes, the station".into(),
+            },
+        )?;
+        MessageService::delete(
+            &connection,
+            &DeleteMessage {
+                message_id: bytes_from_message(&messages[2]),
+                sender: vec![1],
+                time: 10.0,
+            },
+        )?;
+        assert!(search("nope", vec![], 0.0)?.is_empty());
+        assert_eq!(
+            vec![
+                "Yes, the \u{2}station\u{3}",
+                "Meeting at the \u{2}station\u{3}"
+            ],
+            search("station", vec![], 0.0)?
+        );
+
+        // Saving a message again keeps a single entry in the index
+        MessageService::update(&connection, &database.objects, &messages[1])?;
+        assert_eq!(
+            vec![
+                "Yes, the \u{2}station\u{3}",
+                "Meeting at the \u{2}station\u{3}"
+            ],
+            search("station", vec![], 0.0)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn mark_read() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let message = |n: i32, sender: u8| Message {
+            time: n.into(),
+            sender: vec![sender],
+            recipients: vec![vec![3 - sender]],
+            content: format!("Message {}", n),
+            attachment: None,
+            attachment_reference: None,
+            in_reply_to: vec![],
+            chatroom_id: vec![],
+        };
+        let messages: Vec<_> = (0..6)
+            .map(|n| message(n, if n == 3 { 1 } else { 2 }))
+            .collect();
+        for message in messages.iter() {
+            MessageService::update(&connection, &database.objects, message)?;
+        }
+        let chatroom_id = super::super::bytes_from_hash(messages[0].chatroom_id());
+        let count_unread = || {
+            MessageService::count_unread(&connection, &[1], Some(&chatroom_id))
+                .map(|counts| counts.get(&chatroom_id).cloned().unwrap_or_default())
+        };
+        let find_last_read = || {
+            MessageService::find_last_read(&connection, Some(&chatroom_id))
+                .map(|mut markers| markers.remove(&chatroom_id).unwrap())
+        };
+        assert_eq!(5, count_unread()?);
+
+        // A retracted message does not count
+        MessageService::delete(
+            &connection,
+            &DeleteMessage {
+                message_id: bytes_from_message(&messages[5]),
+                sender: vec![2],
+                time: 10.0,
+            },
+        )?;
+        assert_eq!(4, count_unread()?);
+        let latest = MessageService::find_latest(&connection, None)?
+            .remove(&chatroom_id)
+            .unwrap();
+        assert!(latest.deleted);
+
+        // Up to a message, excluding the local account's own
+        let receipts = MessageService::mark_read(
+            &connection,
+            &chatroom_id,
+            &[1],
+            Some(&bytes_from_message(&messages[3])),
+        )?;
+        assert_eq!(3, receipts.len());
+        assert_eq!(1, count_unread()?);
+        let (marker, _) = find_last_read()?;
+        assert_eq!(bytes_from_message(&messages[3]), marker);
+
+        // The marker never moves backward
+        MessageService::mark_read(
+            &connection,
+            &chatroom_id,
+            &[1],
+            Some(&bytes_from_message(&messages[1])),
+        )?;
+        let (marker, _) = find_last_read()?;
+        assert_eq!(bytes_from_message(&messages[3]), marker);
+
+        assert_eq!(
+            2,
+            MessageService::mark_read(&connection, &chatroom_id, &[1], None)?.len()
+        );
+        assert_eq!(0, count_unread()?);
+        let (marker, _) = find_last_read()?;
+        assert_eq!(bytes_from_message(&messages[5]), marker);
+        Ok(())
+    }
+
+    fn bytes_from_message(message: &Message) -> Vec<u8> {
+        super::super::bytes_from_hash(message.canonical_id())
+    }
+}
@@ -1,20 +1,496 @@
+use super::object_store::ObjectStore;
+use super::schema::message as SchemaMessage;
+use super::schema::message_recipients as SchemaRecipients;
 use super::schema::object as Schema;
+use super::schema::object_export as SchemaExport;
+use super::schema::object_transfer as SchemaTransfer;
+use super::schema::vcard as SchemaVcard;
 use crate::changelog::Blob;
+use crate::changelog::BlobReference;
+use crate::pki::CanonicalId;
 use diesel::prelude::*;
-use uuid::Uuid;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
 
+/// Binary objects such as attachments and vCard photos, addressed by their [CanonicalId].
+///
+/// Only their metadata is in the database, while their content is in an [ObjectStore].
 pub struct ObjectService;
 
 impl ObjectService {
-    pub fn save(connection: &'_ SqliteConnection, payload: &Blob) -> QueryResult<Uuid> {
-        let id = Uuid::new_v4();
-        diesel::replace_into(Schema::table)
+    /// Saves a [Blob] unless an identical one is already saved.
+    ///
+    /// Its content is only written if [ObjectStore::write_blob] has not written it beforehand.
+    ///
+    /// Returns the object ID, which is the [CanonicalId] of the [Blob].
+    pub fn save(
+        connection: &'_ SqliteConnection,
+        objects: &ObjectStore,
+        payload: &Blob,
+    ) -> QueryResult<Vec<u8>> {
+        let object_id = super::bytes_from_hash(payload.canonical_id());
+        objects.write(&object_id, &payload.content).map_err(abort)?;
+        diesel::insert_or_ignore_into(Schema::table)
             .values((
-                Schema::object_id.eq(id.as_bytes().as_ref()),
-                Schema::content.eq(&payload.content),
+                Schema::object_id.eq(&object_id),
                 Schema::mime.eq(&payload.mime),
+                Schema::size.eq(payload.content.len() as i64),
             ))
             .execute(connection)?;
-        Ok(id)
+        Ok(object_id)
+    }
+
+    /// Finds the metadata of a saved object.
+    ///
+    /// Its content is read from the [ObjectStore] without holding the database connection.
+    pub fn find_by_id(
+        connection: &'_ SqliteConnection,
+        object_id: &[u8],
+    ) -> QueryResult<Option<BlobReference>> {
+        Schema::table
+            .find(object_id)
+            .select((Schema::mime, Schema::size))
+            .first::<(String, i64)>(connection)
+            .optional()
+            .map(|object| {
+                object.map(|(mime, size)| BlobReference {
+                    object_id: object_id.into(),
+                    mime,
+                    size: size as u64,
+                })
+            })
+    }
+
+    /// Saves a [BlobReference] to be downloaded unless the object is already saved.
+    ///
+    /// Returns the object ID.
+    pub fn save_reference(
+        connection: &'_ SqliteConnection,
+        reference: &BlobReference,
+    ) -> QueryResult<Vec<u8>> {
+        let saved: bool = diesel::select(diesel::dsl::exists(
+            Schema::table.find(&reference.object_id),
+        ))
+        .get_result(connection)?;
+        if !saved {
+            diesel::insert_or_ignore_into(SchemaTransfer::table)
+                .values((
+                    SchemaTransfer::object_id.eq(&reference.object_id),
+                    SchemaTransfer::mime.eq(&reference.mime),
+                    SchemaTransfer::size.eq(reference.size as i64),
+                ))
+                .execute(connection)?;
+        }
+        Ok(reference.object_id.clone())
+    }
+
+    /// Finds the objects not fully downloaded yet and due for an attempt at `time`.
+    pub fn find_pending(
+        connection: &'_ SqliteConnection,
+        time: f64,
+    ) -> QueryResult<Vec<BlobReference>> {
+        Self::load_pending(connection, None, time)
+    }
+
+    /// Finds the objects not fully downloaded yet among some objects.
+    pub fn find_pending_by_ids(
+        connection: &'_ SqliteConnection,
+        object_ids: &[Vec<u8>],
+    ) -> QueryResult<Vec<BlobReference>> {
+        Self::load_pending(connection, Some(object_ids), f64::INFINITY)
+    }
+
+    fn load_pending(
+        connection: &'_ SqliteConnection,
+        object_ids: Option<&[Vec<u8>]>,
+        time: f64,
+    ) -> QueryResult<Vec<BlobReference>> {
+        let mut query = SchemaTransfer::table
+            .filter(SchemaTransfer::time_next_attempt.le(time))
+            .into_boxed();
+        if let Some(object_ids) = object_ids {
+            query = query.filter(SchemaTransfer::object_id.eq_any(object_ids.to_vec()));
+        }
+        query
+            .select((
+                SchemaTransfer::object_id,
+                SchemaTransfer::mime,
+                SchemaTransfer::size,
+            ))
+            .load::<(Vec<u8>, String, i64)>(connection)
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|(object_id, mime, size)| BlobReference {
+                        object_id,
+                        mime,
+                        size: size as u64,
+                    })
+                    .collect()
+            })
+    }
+
+    /// Finds the [BlobReference]s of objects, whether they are downloaded or not.
+    pub fn find_references(
+        connection: &'_ SqliteConnection,
+        object_ids: &[Vec<u8>],
+    ) -> QueryResult<HashMap<Vec<u8>, BlobReference>> {
+        let mut references = HashMap::new();
+        for (object_id, mime, size) in Schema::table
+            .filter(Schema::object_id.eq_any(object_ids))
+            .select((Schema::object_id, Schema::mime, Schema::size))
+            .load::<(Vec<u8>, String, i64)>(connection)?
+        {
+            let reference = BlobReference {
+                object_id: object_id.clone(),
+                mime,
+                size: size as u64,
+            };
+            references.insert(object_id, reference);
+        }
+        for reference in Self::find_pending_by_ids(connection, object_ids)? {
+            references
+                .entry(reference.object_id.clone())
+                .or_insert(reference);
+        }
+        Ok(references)
+    }
+
+    /// Finds the accounts in the chatrooms where an object is attached to a message.
+    pub fn find_sharers(
+        connection: &'_ SqliteConnection,
+        object_id: &[u8],
+    ) -> QueryResult<Vec<Vec<u8>>> {
+        let messages = SchemaMessage::table
+            .filter(SchemaMessage::attachment.eq(object_id))
+            .select((SchemaMessage::message_id, SchemaMessage::sender))
+            .load::<(Vec<u8>, Vec<u8>)>(connection)?;
+        let recipients = SchemaRecipients::table
+            .filter(
+                SchemaRecipients::message_id.eq_any(
+                    messages
+                        .iter()
+                        .map(|(message_id, _)| message_id.clone())
+                        .collect::<Vec<_>>(),
+                ),
+            )
+            .select(SchemaRecipients::recipient_account_id)
+            .load::<Vec<u8>>(connection)?;
+        let sharers: BTreeSet<_> = messages
+            .into_iter()
+            .map(|(_, sender)| sender)
+            .chain(recipients)
+            .collect();
+        Ok(sharers.into_iter().collect())
+    }
+
+    /// Finds the IDs of the chatrooms where an object is attached to a message.
+    pub fn find_chatroom_ids(
+        connection: &'_ SqliteConnection,
+        object_id: &[u8],
+    ) -> QueryResult<Vec<Vec<u8>>> {
+        SchemaMessage::table
+            .filter(SchemaMessage::attachment.eq(object_id))
+            .select(SchemaMessage::chatroom_id)
+            .distinct()
+            .load(connection)
+    }
+
+    /// Postpones downloading an object whose content turns out not to match its ID, as the peers
+    /// sharing it are unlikely to send anything else right away.
+    pub fn postpone(
+        connection: &'_ SqliteConnection,
+        object_id: &[u8],
+        time: f64,
+    ) -> QueryResult<()> {
+        let attempts = SchemaTransfer::table
+            .find(object_id)
+            .select(SchemaTransfer::attempts)
+            .first::<i32>(connection)
+            .optional()?;
+        if let Some(attempts) = attempts {
+            let attempts = attempts.saturating_add(1);
+            let delay = crate::outbox::retry_delay(attempts as u32);
+            diesel::update(SchemaTransfer::table.find(object_id))
+                .set((
+                    SchemaTransfer::attempts.eq(attempts),
+                    SchemaTransfer::time_next_attempt.eq(time + delay.as_secs_f64()),
+                ))
+                .execute(connection)?;
+        }
+        Ok(())
+    }
+
+    /// Records a fully downloaded object already verified by [ObjectStore::complete].
+    pub fn complete(
+        connection: &'_ SqliteConnection,
+        reference: &BlobReference,
+    ) -> QueryResult<()> {
+        diesel::insert_or_ignore_into(Schema::table)
+            .values((
+                Schema::object_id.eq(&reference.object_id),
+                Schema::mime.eq(&reference.mime),
+                Schema::size.eq(reference.size as i64),
+            ))
+            .execute(connection)?;
+        diesel::delete(SchemaTransfer::table.find(&reference.object_id))
+            .execute(connection)
+            .map(drop)
+    }
+
+    /// Deletes the objects no longer used by any message or vCard, including those being
+    /// downloaded.
+    ///
+    /// Their files are left for [ObjectService::sweep], so that they survive a rollback.
+    ///
+    /// Returns the number of objects deleted.
+    pub fn collect_garbage(connection: &'_ SqliteConnection) -> QueryResult<usize> {
+        let attachments = || {
+            SchemaMessage::table
+                .select(SchemaMessage::attachment)
+                .filter(SchemaMessage::attachment.is_not_null())
+        };
+        let photos = SchemaVcard::table
+            .select(SchemaVcard::photo)
+            .filter(SchemaVcard::photo.is_not_null());
+        let deleted = diesel::delete(
+            Schema::table
+                .filter(Schema::object_id.nullable().ne_all(attachments()))
+                .filter(Schema::object_id.nullable().ne_all(photos)),
+        )
+        .execute(connection)?;
+        let transfers_abandoned = diesel::delete(
+            SchemaTransfer::table
+                .filter(SchemaTransfer::object_id.nullable().ne_all(attachments())),
+        )
+        .execute(connection)?;
+        Ok(deleted + transfers_abandoned)
+    }
+
+    /// Deletes the files in an [ObjectStore] whose objects are no longer in the database.
+    ///
+    /// Must not run inside a transaction.
+    ///
+    /// Returns the number of objects deleted.
+    pub fn sweep(connection: &'_ SqliteConnection, objects: &ObjectStore) -> QueryResult<usize> {
+        let saved: BTreeSet<Vec<u8>> = Schema::table
+            .select(Schema::object_id)
+            .load::<Vec<u8>>(connection)?
+            .into_iter()
+            .chain(
+                SchemaTransfer::table
+                    .select(SchemaTransfer::object_id)
+                    .load::<Vec<u8>>(connection)?,
+            )
+            .collect();
+        let mut deleted = 0;
+        for object_id in objects.list().map_err(abort)? {
+            if !saved.contains(&object_id) {
+                objects.remove(&object_id).map_err(abort)?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Checks if an account may fetch an object, which must be attached to a message it received.
+    ///
+    /// A message sent by the account itself does not count, as it could reference any object whose
+    /// ID it knows.
+    pub fn is_shared_with(
+        connection: &'_ SqliteConnection,
+        object_id: &[u8],
+        account_id: &[u8],
+    ) -> QueryResult<bool> {
+        let message_ids = SchemaMessage::table
+            .filter(SchemaMessage::attachment.eq(object_id))
+            .filter(SchemaMessage::sender.ne(account_id))
+            .select(SchemaMessage::message_id)
+            .load::<Vec<u8>>(connection)?;
+        diesel::select(diesel::dsl::exists(
+            SchemaRecipients::table
+                .filter(SchemaRecipients::message_id.eq_any(message_ids))
+                .filter(SchemaRecipients::recipient_account_id.eq(account_id)),
+        ))
+        .get_result(connection)
+    }
+
+    /// Moves the content of objects saved in the database to an [ObjectStore].
+    ///
+    /// Objects saved under random UUIDs are moved to their [CanonicalId]s, merging duplicates.
+    pub fn export(connection: &'_ SqliteConnection, objects: &ObjectStore) -> QueryResult<()> {
+        let legacy_ids = SchemaExport::table
+            .select(SchemaExport::object_id)
+            .load::<Vec<u8>>(connection)?;
+        for legacy_id in legacy_ids {
+            let (mime, content) = SchemaExport::table
+                .inner_join(Schema::table)
+                .filter(SchemaExport::object_id.eq(&legacy_id))
+                .select((Schema::mime, SchemaExport::content))
+                .first::<(String, Vec<u8>)>(connection)?;
+            let object_id = Self::save(connection, objects, &Blob { mime, content })?;
+            if object_id != legacy_id {
+                diesel::update(
+                    SchemaMessage::table.filter(SchemaMessage::attachment.eq(&legacy_id)),
+                )
+                .set(SchemaMessage::attachment.eq(&object_id))
+                .execute(connection)?;
+                diesel::update(SchemaVcard::table.filter(SchemaVcard::photo.eq(&legacy_id)))
+                    .set(SchemaVcard::photo.eq(&object_id))
+                    .execute(connection)?;
+                diesel::delete(Schema::table.find(&legacy_id)).execute(connection)?;
+            }
+            diesel::delete(SchemaExport::table.find(&legacy_id)).execute(connection)?;
+        }
+        Ok(())
+    }
+}
+
+/// Aborts the current query or transaction due to a failure in an [ObjectStore].
+pub(super) fn abort(err: std::io::Error) -> diesel::result::Error {
+    diesel::result::Error::QueryBuilderError(err.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Database;
+    use crate::database::Storage;
+    use uuid::Uuid;
+
+    #[test]
+    fn deduplicate() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let objects = &database.objects;
+        let blob = Blob {
+            mime: "text/plain".into(),
+            content: b"Hello".to_vec(),
+        };
+        let object_id = crate::database::bytes_from_hash(blob.canonical_id());
+
+        // Saved in the database before objects were content-addressed
+        let legacy_id = Uuid::new_v4().as_bytes().to_vec();
+        diesel::insert_into(Schema::table)
+            .values((
+                Schema::object_id.eq(&legacy_id),
+                Schema::mime.eq(&blob.mime),
+                Schema::size.eq(blob.content.len() as i64),
+            ))
+            .execute(&*connection)?;
+        diesel::insert_into(SchemaExport::table)
+            .values((
+                SchemaExport::object_id.eq(&legacy_id),
+                SchemaExport::content.eq(&blob.content),
+            ))
+            .execute(&*connection)?;
+        diesel::insert_into(SchemaVcard::table)
+            .values((
+                SchemaVcard::vcard_id.eq(vec![0; 32]),
+                SchemaVcard::account_id.eq(vec![0; 32]),
+                SchemaVcard::name.eq("Alice"),
+                SchemaVcard::photo.eq(&legacy_id),
+                SchemaVcard::clock.eq(vec![]),
+            ))
+            .execute(&*connection)?;
+
+        assert_eq!(object_id, ObjectService::save(&connection, objects, &blob)?);
+        assert_eq!(object_id, ObjectService::save(&connection, objects, &blob)?);
+        ObjectService::export(&connection, objects)?;
+        let object_ids = Schema::table
+            .select(Schema::object_id)
+            .load::<Vec<u8>>(&*connection)?;
+        assert_eq!(vec![object_id.clone()], object_ids);
+        assert_eq!(Some(blob.content), objects.read(&object_id)?);
+        assert_eq!(0, ObjectService::collect_garbage(&connection)?);
+        assert_eq!(0, ObjectService::sweep(&connection, objects)?);
+
+        diesel::delete(SchemaVcard::table).execute(&*connection)?;
+        assert_eq!(1, ObjectService::collect_garbage(&connection)?);
+        assert_eq!(None, ObjectService::find_by_id(&connection, &object_id)?);
+        assert_eq!(1, ObjectService::sweep(&connection, objects)?);
+        assert_eq!(None, objects.read(&object_id)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn download() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let blob = Blob {
+            mime: "text/plain".into(),
+            content: b"Hello, world".to_vec(),
+        };
+        let reference = BlobReference {
+            object_id: crate::database::bytes_from_hash(blob.canonical_id()),
+            mime: blob.mime.clone(),
+            size: blob.content.len() as u64,
+        };
+        ObjectService::save_reference(&connection, &reference)?;
+        assert_eq!(
+            vec![reference.clone()],
+            ObjectService::find_pending(&connection, 0.0)?
+        );
+
+        // Corrupted downloads are retried later
+        ObjectService::postpone(&connection, &reference.object_id, 0.0)?;
+        assert!(ObjectService::find_pending(&connection, 0.0)?.is_empty());
+        assert_eq!(1, ObjectService::find_pending(&connection, 60.0)?.len());
+
+        // Partial downloads survive sweeping
+        database
+            .objects
+            .write_partial(&reference.object_id, 0, &blob.content)?;
+        assert_eq!(0, ObjectService::sweep(&connection, &database.objects)?);
+
+        assert!(database.objects.complete(&reference)?);
+        ObjectService::complete(&connection, &reference)?;
+        assert!(ObjectService::find_pending(&connection, 0.0)?.is_empty());
+        assert_eq!(
+            Some(reference.clone()),
+            ObjectService::find_by_id(&connection, &reference.object_id)?
+        );
+
+        // Already saved
+        ObjectService::save_reference(&connection, &reference)?;
+        assert!(ObjectService::find_pending(&connection, 0.0)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn share_with_recipients() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let reference = |n: u8| BlobReference {
+            object_id: vec![n; 32],
+            mime: "text/plain".into(),
+            size: 1024 * 1024,
+        };
+        let message = |sender: u8, recipient: u8, object: u8| crate::changelog::Message {
+            time: 0.0,
+            sender: vec![sender],
+            recipients: vec![vec![recipient]],
+            content: "".into(),
+            attachment: None,
+            attachment_reference: Some(reference(object)),
+            in_reply_to: vec![],
+            chatroom_id: vec![],
+        };
+        for message in [message(1, 2, 1), message(3, 1, 2)].iter() {
+            crate::database::message::MessageService::update(
+                &connection,
+                &database.objects,
+                message,
+            )?;
+        }
+
+        assert!(ObjectService::is_shared_with(&connection, &[1; 32], &[2])?);
+        assert!(!ObjectService::is_shared_with(&connection, &[1; 32], &[4])?);
+
+        // Referencing an object does not grant access to it
+        assert!(!ObjectService::is_shared_with(&connection, &[2; 32], &[3])?);
+
+        Ok(())
     }
 }
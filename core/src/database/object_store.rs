@@ -0,0 +1,205 @@
+use crate::changelog::Blob;
+use crate::changelog::BlobReference;
+use crate::pki::CanonicalId;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+use tempfile::TempDir;
+
+/// Suffix of the files being downloaded.
+const SUFFIX_PARTIAL: &str = ".part";
+
+/// Content of objects stored as files, named after their object IDs.
+///
+/// Their metadata is kept by [ObjectService](super::object::ObjectService).
+pub(crate) struct ObjectStore {
+    root: PathBuf,
+    _temporary: Option<TempDir>,
+}
+
+impl ObjectStore {
+    /// Opens a store in a directory, creating it if missing.
+    pub fn open(root: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&root)?;
+        Ok(Self {
+            root,
+            _temporary: None,
+        })
+    }
+
+    /// Opens a store in a temporary directory, which is deleted once the store is dropped.
+    pub fn temporary() -> std::io::Result<Self> {
+        let temporary = tempfile::tempdir()?;
+        Ok(Self {
+            root: temporary.path().to_path_buf(),
+            _temporary: Some(temporary),
+        })
+    }
+
+    fn path(&self, object_id: &[u8]) -> PathBuf {
+        self.root.join(hex::encode_upper(object_id))
+    }
+
+    fn path_partial(&self, object_id: &[u8]) -> PathBuf {
+        self.root
+            .join(hex::encode_upper(object_id) + SUFFIX_PARTIAL)
+    }
+
+    /// Runs blocking file I/O on the store without stalling other tasks.
+    pub async fn run<F, T>(self: &Arc<Self>, task: F) -> T
+    where
+        F: FnOnce(&Self) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let objects = self.clone();
+        crate::util::run_blocking(move || task(&objects)).await
+    }
+
+    /// Writes the content of an object unless it is already written.
+    ///
+    /// The file is written under a temporary name first, so that it is never seen incomplete.
+    pub fn write(&self, object_id: &[u8], content: &[u8]) -> std::io::Result<()> {
+        let path = self.path(object_id);
+        if path.exists() {
+            return Ok(());
+        }
+        let mut file = NamedTempFile::new_in(&self.root)?;
+        file.write_all(content)?;
+        file.as_file().sync_all()?;
+        file.persist(path).map(drop).map_err(|err| err.error)
+    }
+
+    /// Writes the content of a [Blob] ahead of saving it, so that
+    /// [ObjectService::save](super::object::ObjectService::save) does not write it while holding the
+    /// database connection.
+    pub fn write_blob(&self, blob: &Blob) -> std::io::Result<()> {
+        self.write(&super::bytes_from_hash(blob.canonical_id()), &blob.content)
+    }
+
+    /// Reads the content of an object.
+    pub fn read(&self, object_id: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.path(object_id)) {
+            Ok(content) => Ok(Some(content)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Deletes an object, including any partial download of it.
+    pub fn remove(&self, object_id: &[u8]) -> std::io::Result<()> {
+        for path in [self.path(object_id), self.path_partial(object_id)].iter() {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the IDs of all objects, including those partially downloaded.
+    pub fn list(&self) -> std::io::Result<Vec<Vec<u8>>> {
+        let mut object_ids = vec![];
+        for entry in std::fs::read_dir(&self.root)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            let name = name.strip_suffix(SUFFIX_PARTIAL).unwrap_or(&name);
+            match hex::decode(name) {
+                Ok(object_id) if object_id.len() == blake3::OUT_LEN => object_ids.push(object_id),
+                _ => {}
+            }
+        }
+        object_ids.sort();
+        object_ids.dedup();
+        Ok(object_ids)
+    }
+
+    /// Gets the size of the content downloaded so far.
+    pub fn downloaded_size(&self, object_id: &[u8]) -> std::io::Result<u64> {
+        match std::fs::metadata(self.path_partial(object_id)) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Writes a part of the content being downloaded, discarding anything after it.
+    pub fn write_partial(
+        &self,
+        object_id: &[u8],
+        position: u64,
+        content: &[u8],
+    ) -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(self.path_partial(object_id))?;
+        file.set_len(position)?;
+        file.seek(SeekFrom::Start(position))?;
+        file.write_all(content)?;
+        file.sync_data()
+    }
+
+    /// Verifies a fully downloaded object against its ID and makes it available.
+    ///
+    /// Returns whether it is intact. A corrupted one is discarded so that it is downloaded again.
+    pub fn complete(&self, reference: &BlobReference) -> std::io::Result<bool> {
+        let path_partial = self.path_partial(&reference.object_id);
+        let blob = Blob {
+            mime: reference.mime.clone(),
+            content: std::fs::read(&path_partial)?,
+        };
+        if blob.canonical_id().as_bytes()[..] == reference.object_id[..] {
+            File::open(&path_partial)?.sync_all()?;
+            std::fs::rename(&path_partial, self.path(&reference.object_id))?;
+            Ok(true)
+        } else {
+            std::fs::remove_file(&path_partial)?;
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn download() -> anyhow::Result<()> {
+        let store = ObjectStore::temporary()?;
+        let blob = Blob {
+            mime: "text/plain".into(),
+            content: b"Hello, world".to_vec(),
+        };
+        let reference = BlobReference {
+            object_id: crate::database::bytes_from_hash(blob.canonical_id()),
+            mime: blob.mime.clone(),
+            size: blob.content.len() as u64,
+        };
+
+        // Corrupted content is discarded
+        store.write_partial(&reference.object_id, 0, b"Hello, ")?;
+        store.write_partial(&reference.object_id, 7, b"world?")?;
+        assert_eq!(13, store.downloaded_size(&reference.object_id)?);
+        assert!(!store.complete(&reference)?);
+        assert_eq!(0, store.downloaded_size(&reference.object_id)?);
+
+        // Resumed from where it stopped
+        store.write_partial(&reference.object_id, 0, b"Hello, ")?;
+        assert_eq!(vec![reference.object_id.clone()], store.list()?);
+        let position = store.downloaded_size(&reference.object_id)?;
+        store.write_partial(&reference.object_id, position, b"world")?;
+        assert!(store.complete(&reference)?);
+        assert_eq!(Some(blob.content), store.read(&reference.object_id)?);
+        assert_eq!(vec![reference.object_id.clone()], store.list()?);
+
+        store.remove(&reference.object_id)?;
+        assert!(store.list()?.is_empty());
+        Ok(())
+    }
+}
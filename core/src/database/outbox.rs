@@ -0,0 +1,147 @@
+use super::schema::outbox as Schema;
+use crate::proto::Request;
+use chrono::Utc;
+use diesel::prelude::*;
+use prost::Message as _;
+use uuid::Uuid;
+
+/// [Request] waiting to be sent to a recipient.
+pub(crate) struct OutboxEntry {
+    pub id: Vec<u8>,
+    pub recipient_account_id: Vec<u8>,
+
+    /// Message whose delivery is tracked by this entry.
+    pub message_id: Option<Vec<u8>>,
+
+    pub request: Request,
+    pub attempts: i32,
+}
+
+/// Persistent queue of [Request]s to be sent to other [Node](crate::Node)s.
+pub(crate) struct OutboxService;
+
+impl OutboxService {
+    /// Enqueues a [Request] to be sent as soon as possible.
+    pub fn enqueue(
+        connection: &'_ SqliteConnection,
+        recipient_account_id: &[u8],
+        message_id: Option<&[u8]>,
+        request: &Request,
+    ) -> QueryResult<()> {
+        let mut raw_request = Vec::<u8>::new();
+        request
+            .encode(&mut raw_request)
+            .unwrap_or_else(|err| panic!("Failed to encode a request: {}", err));
+        diesel::insert_into(Schema::table)
+            .values((
+                Schema::id.eq(Uuid::new_v4().as_bytes().as_ref()),
+                Schema::recipient_account_id.eq(recipient_account_id),
+                Schema::message_id.eq(message_id),
+                Schema::request.eq(raw_request),
+                Schema::attempts.eq(0),
+                Schema::time_next_attempt.eq(super::float_from_time(Utc::now())),
+            ))
+            .execute(connection)?;
+        Ok(())
+    }
+
+    /// Finds the entries that should be attempted no later than `time`.
+    ///
+    /// Corrupted entries are removed.
+    pub fn find_due(connection: &'_ SqliteConnection, time: f64) -> QueryResult<Vec<OutboxEntry>> {
+        let rows = Schema::table
+            .filter(Schema::time_next_attempt.le(time))
+            .select((
+                Schema::id,
+                Schema::recipient_account_id,
+                Schema::message_id,
+                Schema::request,
+                Schema::attempts,
+            ))
+            .load::<(Vec<u8>, Vec<u8>, Option<Vec<u8>>, Vec<u8>, i32)>(connection)?;
+        let mut entries = Vec::with_capacity(rows.len());
+        for (id, recipient_account_id, message_id, raw_request, attempts) in rows {
+            match Request::decode(raw_request.as_slice()) {
+                Ok(request) => entries.push(OutboxEntry {
+                    id,
+                    recipient_account_id,
+                    message_id,
+                    request,
+                    attempts,
+                }),
+                Err(err) => {
+                    log::error!("Removing a corrupted outbox entry: {:?}", err);
+                    Self::remove(connection, &id)?;
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Finds when the next attempt is due.
+    pub fn time_next_attempt(connection: &'_ SqliteConnection) -> QueryResult<Option<f64>> {
+        Schema::table
+            .select(diesel::dsl::min(Schema::time_next_attempt))
+            .first(connection)
+    }
+
+    /// Schedules another attempt on an entry.
+    pub fn postpone(
+        connection: &'_ SqliteConnection,
+        id: &[u8],
+        attempts: i32,
+        time_next_attempt: f64,
+    ) -> QueryResult<()> {
+        diesel::update(Schema::table.find(id))
+            .set((
+                Schema::attempts.eq(attempts),
+                Schema::time_next_attempt.eq(time_next_attempt),
+            ))
+            .execute(connection)?;
+        Ok(())
+    }
+
+    /// Makes all entries to a recipient due immediately.
+    pub fn expedite(
+        connection: &'_ SqliteConnection,
+        recipient_account_id: &[u8],
+    ) -> QueryResult<()> {
+        diesel::update(Schema::table.filter(Schema::recipient_account_id.eq(recipient_account_id)))
+            .set(Schema::time_next_attempt.eq(super::float_from_time(Utc::now())))
+            .execute(connection)?;
+        Ok(())
+    }
+
+    pub fn remove(connection: &'_ SqliteConnection, id: &[u8]) -> QueryResult<()> {
+        diesel::delete(Schema::table.find(id)).execute(connection)?;
+        Ok(())
+    }
+
+    /// Removes all entries to a recipient.
+    ///
+    /// Returns the messages whose delivery is dropped.
+    pub fn remove_by_recipient(
+        connection: &'_ SqliteConnection,
+        recipient_account_id: &[u8],
+    ) -> QueryResult<Vec<Vec<u8>>> {
+        let message_ids = Schema::table
+            .filter(Schema::recipient_account_id.eq(recipient_account_id))
+            .select(Schema::message_id)
+            .load::<Option<Vec<u8>>>(connection)?
+            .into_iter()
+            .flatten()
+            .collect();
+        let count = diesel::delete(
+            Schema::table.filter(Schema::recipient_account_id.eq(recipient_account_id)),
+        )
+        .execute(connection)?;
+        if count > 0 {
+            log::info!(
+                "Removed {} outbox entries to {}",
+                count,
+                hex::encode_upper(recipient_account_id)
+            );
+        }
+        Ok(message_ids)
+    }
+}
@@ -1,6 +1,10 @@
+use super::chatroom::ChatroomService;
+use super::delivery::DeliveryService;
+use super::outbox::OutboxService;
 use super::schema::peer as Schema;
 use super::Event;
 use crate::changelog::PeerRole;
+use crate::daemon::DeliveryState;
 use crate::daemon::Roster;
 use crate::daemon::RosterItem;
 use crate::endpoint::CertificateVerifier;
@@ -12,19 +16,49 @@ pub(crate) struct PeerService {
 }
 
 impl PeerService {
+    /// Saves a [Peer](crate::changelog::Peer) unless a newer version is already saved.
+    ///
+    /// * `clock`: Encoded [HybridTimestamp](crate::changelog::HybridTimestamp) of the change.
     pub fn save(
         &self,
         connection: &'_ SqliteConnection,
         payload: crate::changelog::Peer,
-    ) -> QueryResult<Event> {
+        clock: &[u8],
+    ) -> QueryResult<Vec<Event>> {
+        let clock_saved = Schema::table
+            .find(&payload.account_id)
+            .select(Schema::clock)
+            .first::<Vec<u8>>(connection)
+            .optional()?;
+        if clock_saved.map_or(false, |saved| saved.as_slice() >= clock) {
+            return Ok(vec![]);
+        }
+
+        let role = payload.role();
+        let account_id = payload.account_id;
         diesel::replace_into(Schema::table)
             .values((
-                Schema::columns::account_id.eq(payload.account_id),
+                Schema::columns::account_id.eq(&account_id),
                 Schema::columns::name.eq(payload.name),
                 Schema::columns::role.eq(payload.role),
+                Schema::columns::clock.eq(clock),
             ))
             .execute(connection)?;
 
+        // Stop sending anything to a blocked peer
+        let mut events = vec![Event::Roster];
+        if let PeerRole::Blocked = role {
+            for message_id in OutboxService::remove_by_recipient(connection, &account_id)? {
+                events.push(DeliveryService::save(
+                    connection,
+                    &message_id,
+                    &account_id,
+                    DeliveryState::Failed,
+                    "Recipient is blocked",
+                )?);
+            }
+        }
+
         // Update certificate verifier rules
         if let Some(verifier) = &self.verifier {
             let blacklist = Self::blacklist(connection)?;
@@ -35,7 +69,43 @@ impl PeerService {
             verifier.set_rules(std::iter::empty(), blacklist)
         }
 
-        Ok(Event::Roster)
+        events.extend(ChatroomService::find_named_after(connection, &account_id)?);
+        Ok(events)
+    }
+
+    pub fn find_by_account_id(
+        connection: &'_ SqliteConnection,
+        account_id: &[u8],
+    ) -> QueryResult<Option<crate::changelog::Peer>> {
+        Schema::table
+            .find(account_id)
+            .select((Schema::name, Schema::role))
+            .first::<(String, i32)>(connection)
+            .optional()
+            .map(|result| {
+                result.map(|(name, role)| crate::changelog::Peer {
+                    account_id: account_id.into(),
+                    name,
+                    role,
+                })
+            })
+    }
+
+    /// Exports all [Peer](crate::changelog::Peer)s as changelog.
+    pub fn export(connection: &'_ SqliteConnection) -> QueryResult<Vec<crate::changelog::Peer>> {
+        Schema::table
+            .select((Schema::account_id, Schema::name, Schema::role))
+            .order(Schema::account_id.asc())
+            .load::<(Vec<u8>, String, i32)>(connection)
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|(account_id, name, role)| crate::changelog::Peer {
+                        account_id,
+                        name,
+                        role,
+                    })
+                    .collect()
+            })
     }
 
     pub fn blacklist(connection: &'_ SqliteConnection) -> QueryResult<Vec<Vec<u8>>> {
@@ -46,6 +116,14 @@ impl PeerService {
             .load(connection)
     }
 
+    pub fn friends(connection: &'_ SqliteConnection) -> QueryResult<Vec<Vec<u8>>> {
+        let friend_i32: i32 = PeerRole::Friend.into();
+        Schema::table
+            .select(Schema::account_id)
+            .filter(Schema::role.eq(friend_i32))
+            .load(connection)
+    }
+
     pub fn roster(connection: &'_ SqliteConnection) -> QueryResult<Roster> {
         let result = Schema::table
             .left_join(
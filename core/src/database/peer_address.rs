@@ -0,0 +1,78 @@
+use super::schema::peer_address as Schema;
+use chrono::Utc;
+use diesel::prelude::*;
+use std::net::SocketAddr;
+
+/// Address book of where the [Node](crate::Node)s of an account can be reached.
+pub(crate) struct PeerAddressService;
+
+impl PeerAddressService {
+    /// Records that an account is seen at an address just now.
+    pub fn save(
+        connection: &'_ SqliteConnection,
+        account_id: &[u8],
+        address: &SocketAddr,
+    ) -> QueryResult<()> {
+        diesel::replace_into(Schema::table)
+            .values((
+                Schema::account_id.eq(account_id),
+                Schema::address.eq(address.to_string()),
+                Schema::time_last_seen.eq(super::float_from_time(Utc::now())),
+            ))
+            .execute(connection)?;
+        Ok(())
+    }
+
+    /// Finds the known addresses of an account, starting from the most recently seen one.
+    pub fn find_by_account_id(
+        connection: &'_ SqliteConnection,
+        account_id: &[u8],
+    ) -> QueryResult<Vec<SocketAddr>> {
+        let addresses = Schema::table
+            .filter(Schema::account_id.eq(account_id))
+            .select(Schema::address)
+            .order(Schema::time_last_seen.desc())
+            .load::<String>(connection)?
+            .into_iter()
+            .filter_map(|address| match address.parse() {
+                Ok(address) => Some(address),
+                Err(err) => {
+                    log::error!("Bad address {} in the address book: {:?}", address, err);
+                    None
+                }
+            })
+            .collect();
+        Ok(addresses)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Database;
+    use crate::database::Storage;
+
+    #[test]
+    fn most_recently_seen_first() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let account_id = [0_u8; 32];
+        let address_1: SocketAddr = "[::1]:50001".parse()?;
+        let address_2: SocketAddr = "[::1]:50002".parse()?;
+
+        PeerAddressService::save(&connection, &account_id, &address_1)?;
+        PeerAddressService::save(&connection, &account_id, &address_2)?;
+        assert_eq!(
+            vec![address_2, address_1],
+            PeerAddressService::find_by_account_id(&connection, &account_id)?
+        );
+
+        PeerAddressService::save(&connection, &account_id, &address_1)?;
+        assert_eq!(
+            vec![address_1, address_2],
+            PeerAddressService::find_by_account_id(&connection, &account_id)?
+        );
+
+        Ok(())
+    }
+}
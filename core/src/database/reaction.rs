@@ -0,0 +1,140 @@
+use super::schema::message as SchemaMessage;
+use super::schema::message_reaction as Schema;
+use super::Event;
+use crate::changelog::Reaction;
+use crate::daemon::ReactionCount;
+use diesel::dsl::count_star;
+use diesel::prelude::*;
+use std::collections::HashMap;
+
+/// Large enough for an emoji made of several code points, such as a family or a flag.
+const MAX_EMOJI_SIZE_BYTES: usize = 32;
+
+/// Emoji reactions to messages.
+pub(crate) struct ReactionService;
+
+impl ReactionService {
+    /// Adds or withdraws a [Reaction] unless a newer change to it is already saved.
+    ///
+    /// * `clock`: Encoded [HybridTimestamp](crate::changelog::HybridTimestamp) of the change.
+    pub fn save(
+        connection: &'_ SqliteConnection,
+        payload: &Reaction,
+        clock: &[u8],
+    ) -> QueryResult<Option<Event>> {
+        let clock_saved = Schema::table
+            .find((&payload.message_id, &payload.account_id, &payload.emoji))
+            .select(Schema::clock)
+            .first::<Vec<u8>>(connection)
+            .optional()?;
+        if clock_saved.map_or(false, |saved| saved.as_slice() >= clock) {
+            return Ok(None);
+        }
+
+        diesel::replace_into(Schema::table)
+            .values((
+                Schema::message_id.eq(&payload.message_id),
+                Schema::account_id.eq(&payload.account_id),
+                Schema::emoji.eq(&payload.emoji),
+                Schema::removed.eq(payload.removed),
+                Schema::clock.eq(clock),
+            ))
+            .execute(connection)?;
+        SchemaMessage::table
+            .find(&payload.message_id)
+            .select(SchemaMessage::chatroom_id)
+            .first::<Vec<u8>>(connection)
+            .optional()
+            .map(|chatroom_id| chatroom_id.map(|chatroom_id| Event::Message { chatroom_id }))
+    }
+
+    /// Counts the accounts reacting with each emoji to each message.
+    pub fn count_by_messages(
+        connection: &'_ SqliteConnection,
+        message_ids: &[Vec<u8>],
+    ) -> QueryResult<HashMap<Vec<u8>, Vec<ReactionCount>>> {
+        let mut counts = HashMap::<Vec<u8>, Vec<ReactionCount>>::new();
+        for (message_id, emoji, count) in Schema::table
+            .filter(Schema::message_id.eq_any(message_ids))
+            .filter(Schema::removed.eq(false))
+            .group_by((Schema::message_id, Schema::emoji))
+            .select((Schema::message_id, Schema::emoji, count_star()))
+            .order((Schema::message_id.asc(), Schema::emoji.asc()))
+            .load::<(Vec<u8>, String, i64)>(connection)?
+        {
+            counts.entry(message_id).or_default().push(ReactionCount {
+                emoji,
+                count: count as u32,
+            });
+        }
+        Ok(counts)
+    }
+}
+
+/// Checks if the emoji of a [Reaction] is neither empty nor too long.
+pub(crate) fn verify_emoji(emoji: &str) -> Result<(), String> {
+    if emoji.is_empty() {
+        Err("Emoji is empty".into())
+    } else if emoji.len() > MAX_EMOJI_SIZE_BYTES {
+        Err(format!(
+            "Emoji is longer than {} bytes",
+            MAX_EMOJI_SIZE_BYTES
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::changelog::HybridTimestamp;
+    use crate::database::Database;
+    use crate::database::Storage;
+
+    #[test]
+    fn count_by_messages() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let message_id = vec![0; 32];
+        let reaction = |account: u8, emoji: &str, removed: bool| Reaction {
+            message_id: message_id.clone(),
+            account_id: vec![account],
+            emoji: emoji.into(),
+            removed,
+        };
+        let clock = |physical: u64| {
+            HybridTimestamp {
+                physical,
+                ..Default::default()
+            }
+            .to_bytes()
+        };
+
+        ReactionService::save(&connection, &reaction(1, "👍", false), &clock(1))?;
+        ReactionService::save(&connection, &reaction(2, "👍", false), &clock(1))?;
+        ReactionService::save(&connection, &reaction(2, "🎉", false), &clock(1))?;
+
+        // Withdrawing wins over an older addition arriving later
+        ReactionService::save(&connection, &reaction(1, "🎉", true), &clock(3))?;
+        ReactionService::save(&connection, &reaction(1, "🎉", false), &clock(2))?;
+        ReactionService::save(&connection, &reaction(2, "👍", true), &clock(2))?;
+
+        let counts = ReactionService::count_by_messages(&connection, &[message_id.clone()])?;
+        let counts: Vec<_> = counts[&message_id]
+            .iter()
+            .map(|count| (count.emoji.as_str(), count.count))
+            .collect();
+        assert_eq!(vec![("🎉", 1), ("👍", 1)], counts);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_emoji() {
+        assert!(super::verify_emoji("👍").is_ok());
+        assert!(super::verify_emoji("👨‍👩‍👧‍👦").is_ok());
+        assert!(super::verify_emoji("").is_err());
+        assert!(super::verify_emoji(&"👍".repeat(9)).is_err());
+    }
+}
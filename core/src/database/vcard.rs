@@ -1,7 +1,11 @@
+use super::chatroom::ChatroomService;
 use super::object::ObjectService;
+use super::object_store::ObjectStore;
 use super::peer::PeerService;
+use super::schema::object as SchemaObject;
 use super::schema::vcard as Schema;
 use super::Event;
+use crate::changelog::Blob;
 use crate::changelog::Vcard;
 use crate::pki::CanonicalId;
 use blake3::Hash;
@@ -12,42 +16,94 @@ use std::convert::AsRef;
 pub(crate) struct VcardService;
 
 impl VcardService {
+    /// Saves a [Vcard] unless a newer one of the same account is already saved.
+    ///
+    /// Older [Vcard]s of the same account are removed.
+    ///
+    /// * `clock`: Encoded [HybridTimestamp](crate::changelog::HybridTimestamp) of the change.
     pub fn save(
         connection: &'_ SqliteConnection,
-        vcards: impl Iterator<Item = Vcard>,
+        objects: &ObjectStore,
+        vcard: Vcard,
+        clock: &[u8],
     ) -> QueryResult<Vec<Event>> {
-        let mut events = vec![];
-        // TODO: Batch insert
-        for vcard in vcards {
-            let vcard_id = vcard.canonical_id();
+        let clock_newest = Schema::table
+            .filter(Schema::account_id.eq(&vcard.account_id))
+            .select(Schema::clock)
+            .order(Schema::clock.desc())
+            .first::<Vec<u8>>(connection)
+            .optional()?;
+        if clock_newest.map_or(false, |newest| newest.as_slice() >= clock) {
+            return Ok(vec![]);
+        }
+        diesel::delete(Schema::table.filter(Schema::account_id.eq(&vcard.account_id)))
+            .execute(connection)?;
 
-            let photo_id: Option<Vec<u8>> = vcard
-                .photo
-                .as_ref()
-                .map(|obj| ObjectService::save(connection, obj))
-                .transpose()?
-                .map(|id| id.as_bytes().as_ref().into());
+        let vcard_id = vcard.canonical_id();
+        let photo_id: Option<Vec<u8>> = vcard
+            .photo
+            .as_ref()
+            .map(|obj| ObjectService::save(connection, objects, obj))
+            .transpose()?;
 
-            diesel::replace_into(Schema::table)
-                .values((
-                    Schema::columns::vcard_id.eq(vcard_id.as_bytes().as_ref()),
-                    Schema::columns::account_id.eq(&vcard.account_id),
-                    Schema::columns::name.eq(&vcard.name),
-                    Schema::columns::photo.eq(photo_id),
-                ))
-                .execute(connection)?;
+        diesel::replace_into(Schema::table)
+            .values((
+                Schema::columns::vcard_id.eq(vcard_id.as_bytes().as_ref()),
+                Schema::columns::account_id.eq(&vcard.account_id),
+                Schema::columns::name.eq(&vcard.name),
+                Schema::columns::photo.eq(photo_id),
+                Schema::columns::clock.eq(clock),
+            ))
+            .execute(connection)?;
+        ObjectService::collect_garbage(connection)?;
 
-            // Publish events
-            events.push(Event::Vcard {
-                account_id: vcard.account_id.clone(),
-            });
-            if PeerService::is_in_roster(connection, &vcard.account_id)? {
-                events.push(Event::Roster);
-            }
+        // Publish events
+        let mut events = vec![Event::Vcard {
+            account_id: vcard.account_id.clone(),
+        }];
+        if PeerService::is_in_roster(connection, &vcard.account_id)? {
+            events.push(Event::Roster);
         }
+        events.extend(ChatroomService::find_named_after(
+            connection,
+            &vcard.account_id,
+        )?);
         Ok(events)
     }
 
+    /// Exports all [Vcard]s as changelog.
+    pub fn export(
+        connection: &'_ SqliteConnection,
+        objects: &ObjectStore,
+    ) -> QueryResult<Vec<Vcard>> {
+        let rows = Schema::table
+            .left_join(SchemaObject::table.on(SchemaObject::object_id.nullable().eq(Schema::photo)))
+            .select((
+                Schema::account_id,
+                Schema::name,
+                Schema::photo,
+                SchemaObject::mime.nullable(),
+            ))
+            .order(Schema::account_id.asc())
+            .load::<(Vec<u8>, String, Option<Vec<u8>>, Option<String>)>(connection)?;
+        let mut vcards = Vec::with_capacity(rows.len());
+        for (account_id, name, photo_id, mime) in rows {
+            let photo = match photo_id.zip(mime) {
+                Some((photo_id, mime)) => objects
+                    .read(&photo_id)
+                    .map_err(super::object::abort)?
+                    .map(|content| Blob { mime, content }),
+                None => None,
+            };
+            vcards.push(Vcard {
+                account_id,
+                name,
+                photo,
+            });
+        }
+        Ok(vcards)
+    }
+
     pub fn find_by_account_id(
         connection: &'_ SqliteConnection,
         account_id: &[u8],
@@ -0,0 +1,270 @@
+//! Discovery of [Node](crate::Node)s on the local network.
+//!
+//! [Node](crate::Node)s periodically multicast an [Announcement] and listen for those of others.
+//! An [Announcement] only carries a hash of the account ID keyed by a random nonce, so that it is
+//! only recognizable by those who already know the account.
+
+use crate::database::peer::PeerService;
+use crate::database::Database;
+use crate::proto::Announcement;
+use blake3::Hash;
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::future::Either;
+use prost::Message as _;
+use socket2::Domain;
+use socket2::Protocol;
+use socket2::Socket;
+use socket2::Type;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const NONCE_LENGTH: usize = 32;
+
+/// Large enough for an [Announcement].
+const MAX_ANNOUNCEMENT_SIZE_BYTES: usize = 128;
+
+/// Configuration of [Discovery].
+#[derive(Clone, Debug)]
+pub struct DiscoveryConfig {
+    /// Multicast group to announce to and listen on.
+    pub group: SocketAddr,
+
+    /// Address of the interface to multicast on if `group` is IPv4, or unspecified to let the system
+    /// choose.
+    pub interface_v4: Ipv4Addr,
+
+    /// Interval between [Announcement]s.
+    pub interval: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            group: SocketAddr::new(
+                Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0x7669, 0x736b, 0x61).into(),
+                7669,
+            ),
+            interface_v4: Ipv4Addr::UNSPECIFIED,
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A [Node](crate::Node) found on the local network.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Discovered {
+    pub account_id: Vec<u8>,
+    pub address: SocketAddr,
+}
+
+/// Announces the local [Node](crate::Node) and recognizes the others on the local network.
+pub(crate) struct Discovery {
+    socket: std::net::UdpSocket,
+    group: SocketAddr,
+    interval: Duration,
+    account_id: Hash,
+    port: u16,
+    database: Arc<Database>,
+
+    /// Nonces of the latest [Announcement]s sent, for ignoring them when they loop back.
+    nonces_sent: [[u8; NONCE_LENGTH]; 2],
+}
+
+impl Discovery {
+    /// Constructor.
+    ///
+    /// * `port`: Port of the local QUIC endpoint.
+    pub fn new(
+        config: &DiscoveryConfig,
+        account_id: Hash,
+        port: u16,
+        database: Arc<Database>,
+    ) -> std::io::Result<Self> {
+        let (domain, address_unspecified) = match config.group.ip() {
+            IpAddr::V4(_) => (Domain::IPV4, IpAddr::from(Ipv4Addr::UNSPECIFIED)),
+            IpAddr::V6(_) => (Domain::IPV6, IpAddr::from(Ipv6Addr::UNSPECIFIED)),
+        };
+
+        // Other Nodes on the same host listen on the same port
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::new(address_unspecified, config.group.port()).into())?;
+        if let IpAddr::V4(_) = config.group.ip() {
+            socket.set_multicast_if_v4(&config.interface_v4)?;
+        }
+        let socket: std::net::UdpSocket = socket.into();
+
+        match config.group.ip() {
+            IpAddr::V4(group) => {
+                socket.join_multicast_v4(&group, &config.interface_v4)?;
+                socket.set_multicast_loop_v4(true)?;
+            }
+            IpAddr::V6(group) => {
+                socket.join_multicast_v6(&group, 0)?;
+                socket.set_multicast_loop_v6(true)?;
+            }
+        }
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            group: config.group,
+            interval: config.interval,
+            account_id,
+            port,
+            database,
+            nonces_sent: Default::default(),
+        })
+    }
+
+    /// Announces periodically and sends every recognized [Node](crate::Node) to `discovered_sink`.
+    ///
+    /// Runs until `discovered_sink` is closed.
+    pub async fn run(
+        mut self,
+        discovered_sink: UnboundedSender<Discovered>,
+    ) -> std::io::Result<()> {
+        let socket = UdpSocket::from_std(self.socket.try_clone()?)?;
+        let mut interval = tokio::time::interval(self.interval);
+        let mut buffer = [0; MAX_ANNOUNCEMENT_SIZE_BYTES];
+        log::info!("Starting discovery on {}", self.group);
+
+        while !discovered_sink.is_closed() {
+            let received = {
+                let tick = interval.tick();
+                let receive = socket.recv_from(&mut buffer);
+                futures_util::pin_mut!(tick, receive);
+                match futures_util::future::select(tick, receive).await {
+                    Either::Left(_) => None,
+                    Either::Right((result, _)) => Some(result),
+                }
+            };
+            match received {
+                None => {
+                    let announcement = self.announcement();
+                    if let Err(err) = socket.send_to(&announcement, self.group).await {
+                        log::error!("Failed to announce: {:?}", err);
+                    }
+                }
+                Some(Ok((length, source))) => {
+                    if let Some(discovered) = self.recognize(&buffer[..length], source) {
+                        log::info!("Discovered {:?}", &discovered);
+                        let _ = discovered_sink.unbounded_send(discovered);
+                    }
+                }
+                Some(Err(err)) => log::error!("Failed to receive an announcement: {:?}", err),
+            }
+        }
+
+        log::info!("Stopping discovery on {}", self.group);
+        Ok(())
+    }
+
+    fn announcement(&mut self) -> Vec<u8> {
+        let nonce: [u8; NONCE_LENGTH] = rand::random();
+        self.nonces_sent = [nonce, self.nonces_sent[0]];
+        let announcement = Announcement {
+            account_id_hash: hash(&nonce, self.account_id.as_bytes()).as_bytes().to_vec(),
+            nonce: nonce.to_vec(),
+            port: self.port.into(),
+        };
+        let mut packet = Vec::<u8>::new();
+        announcement
+            .encode(&mut packet)
+            .unwrap_or_else(|err| panic!("Failed to encode an announcement: {}", err));
+        packet
+    }
+
+    fn recognize(&self, packet: &[u8], source: SocketAddr) -> Option<Discovered> {
+        let announcement = Announcement::decode(packet).ok()?;
+        let nonce = <[u8; NONCE_LENGTH]>::try_from(announcement.nonce.as_slice()).ok()?;
+        if self.nonces_sent.contains(&nonce) {
+            return None;
+        }
+        let account_id_hash: Hash =
+            <[u8; blake3::OUT_LEN]>::try_from(announcement.account_id_hash.as_slice())
+                .ok()?
+                .into();
+        let port = u16::try_from(announcement.port).ok()?;
+
+        let friends = PeerService::friends(&self.database.connection.lock().unwrap())
+            .unwrap_or_else(|err| {
+                log::error!("Failed to query the roster: {:?}", err);
+                Default::default()
+            });
+        std::iter::once(crate::database::bytes_from_hash(self.account_id))
+            .chain(friends)
+            .find(|account_id| hash(&nonce, account_id) == account_id_hash)
+            .map(|account_id| Discovered {
+                account_id,
+                address: SocketAddr::new(source.ip(), port),
+            })
+    }
+}
+
+fn hash(nonce: &[u8; NONCE_LENGTH], account_id: &[u8]) -> Hash {
+    blake3::keyed_hash(nonce, account_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::changelog::Peer;
+    use crate::changelog::PeerRole;
+    use crate::database::Storage;
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn discover_friend() -> anyhow::Result<()> {
+        // Loopback works without any network, unlike the default group
+        let config = DiscoveryConfig {
+            group: SocketAddr::new(
+                Ipv4Addr::new(239, 255, 0x76, 0x69).into(),
+                crate::util::random_port(),
+            ),
+            interface_v4: Ipv4Addr::LOCALHOST,
+            interval: Duration::from_millis(100),
+        };
+        let account_id_alice = blake3::hash(b"Alice");
+        let account_id_bob = blake3::hash(b"Bob");
+
+        let database_alice = Arc::new(Database::create(&Storage::InMemory)?);
+        let database_bob = Arc::new(Database::create(&Storage::InMemory)?);
+        let mut bob_as_friend = Peer {
+            account_id: account_id_bob.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        bob_as_friend.set_role(PeerRole::Friend);
+        PeerService { verifier: None }.save(
+            &database_alice.connection.lock().unwrap(),
+            bob_as_friend,
+            &[],
+        )?;
+
+        let alice = Discovery::new(&config, account_id_alice, 1000, database_alice)?;
+        let bob = Discovery::new(&config, account_id_bob, 2000, database_bob)?;
+        let (sink_alice, mut discovered_alice) = futures_channel::mpsc::unbounded();
+        let (sink_bob, mut discovered_bob) = futures_channel::mpsc::unbounded();
+        let task_alice = tokio::spawn(alice.run(sink_alice));
+        let task_bob = tokio::spawn(bob.run(sink_bob));
+
+        // Alice knows Bob but Bob does not know Alice
+        let discovered = discovered_alice.next().await.unwrap();
+        assert_eq!(account_id_bob.as_bytes(), discovered.account_id.as_slice());
+        assert_eq!(2000, discovered.address.port());
+        tokio::time::sleep(config.interval * 4).await;
+        assert!(discovered_bob.try_next().is_err());
+
+        drop(discovered_alice);
+        drop(discovered_bob);
+        task_alice.await??;
+        task_bob.await??;
+        Ok(())
+    }
+}
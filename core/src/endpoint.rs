@@ -1,5 +1,7 @@
 use crate::packet::ResponseWindow;
 use crate::pki::CanonicalId;
+use crate::presence::MAX_SIGNAL_SIZE_BYTES;
+use crate::proto::Signal;
 use crate::util::TaskSink;
 use crate::util::TOKIO_02;
 use crate::Connection;
@@ -10,10 +12,13 @@ use futures_core::Stream;
 use futures_util::FutureExt;
 use futures_util::SinkExt;
 use futures_util::StreamExt;
+use http::StatusCode;
+use prost::Message as _;
 use quinn::CertificateChain;
 use quinn::Endpoint;
 use quinn::Incoming;
 use quinn::IncomingBiStreams;
+use quinn::IncomingUniStreams;
 use quinn::NewConnection;
 use rustls::internal::msgs::handshake::DistinguishedNames;
 use rustls::ClientCertVerified;
@@ -28,13 +33,18 @@ use std::future::Future;
 use std::net::SocketAddr;
 use std::net::UdpSocket;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::RwLock;
+use tokio::sync::Mutex as AsyncMutex;
 use uuid::Uuid;
 use webpki::DNSName;
 use webpki::DNSNameRef;
 
 const ALPN_PROTOCOL: &str = "viska";
 
+/// Unidirectional streams only carry [Signal]s, so a few at a time are enough.
+const MAX_CONCURRENT_SIGNALS: u64 = 16;
+
 pub struct Config<'a> {
     pub certificate: &'a [u8],
     pub key: &'a [u8],
@@ -60,7 +70,7 @@ impl LocalEndpoint {
         let quinn_key = quinn::PrivateKey::from_der(config.key)?;
 
         let mut transport_config = quinn::TransportConfig::default();
-        transport_config.stream_window_uni(0);
+        transport_config.stream_window_uni(MAX_CONCURRENT_SIGNALS);
         let transport_config = Arc::new(transport_config);
 
         // Server config
@@ -152,18 +162,139 @@ impl ConnectionInfo for quinn::Connection {
     }
 }
 
+/// Established [Connection]s indexed by their remote [Node](crate::Node)s.
+#[derive(Default)]
+struct ConnectionPool {
+    connections: HashMap<Uuid, Arc<Connection>>,
+
+    /// Only for peers, because all devices of the local account share the same account ID.
+    by_account_id: HashMap<Vec<u8>, Uuid>,
+
+    by_address: HashMap<SocketAddr, Uuid>,
+}
+
+impl ConnectionPool {
+    /// Adds a [Connection] unless it duplicates an existing one to the same peer.
+    ///
+    /// Returns the [Connection] to use and the [Connection] to close if any.
+    fn add(
+        &mut self,
+        connection: Arc<Connection>,
+        local_account_id: Hash,
+    ) -> (Arc<Connection>, Option<Arc<Connection>>) {
+        let remote_account_id = connection.account_id().filter(|id| *id != local_account_id);
+        let existing = remote_account_id.and_then(|remote_account_id| {
+            self.find_by_account_id(remote_account_id.as_bytes())
+                .map(|existing| (existing, remote_account_id))
+        });
+        match existing {
+            Some((existing, remote_account_id))
+                if !supersedes(&connection, &existing, local_account_id, remote_account_id) =>
+            {
+                (existing, Some(connection))
+            }
+            Some((existing, _)) => {
+                self.remove(&existing.id);
+                self.insert(connection.clone(), remote_account_id);
+                (connection, Some(existing))
+            }
+            None => {
+                self.insert(connection.clone(), remote_account_id);
+                (connection, None)
+            }
+        }
+    }
+
+    fn insert(&mut self, connection: Arc<Connection>, peer_account_id: Option<Hash>) {
+        if let Some(peer_account_id) = peer_account_id {
+            self.by_account_id.insert(
+                crate::database::bytes_from_hash(peer_account_id),
+                connection.id,
+            );
+        }
+        self.by_address
+            .insert(connection.remote_address(), connection.id);
+        self.connections.insert(connection.id, connection);
+    }
+
+    fn remove(&mut self, connection_id: &Uuid) {
+        if self.connections.remove(connection_id).is_some() {
+            self.by_account_id.retain(|_, id| id != connection_id);
+            self.by_address.retain(|_, id| id != connection_id);
+        }
+    }
+
+    fn find_by_account_id(&self, account_id: &[u8]) -> Option<Arc<Connection>> {
+        match self.by_account_id.get(account_id) {
+            Some(id) => self.connections.get(id).cloned(),
+            None => self.find_all_by_account_id(account_id).into_iter().next(),
+        }
+    }
+
+    fn find_all_by_account_id(&self, account_id: &[u8]) -> Vec<Arc<Connection>> {
+        self.connections
+            .values()
+            .filter(|connection| {
+                connection
+                    .account_id()
+                    .map_or(false, |id| &id.as_bytes()[..] == account_id)
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn find_all(&self) -> Vec<Arc<Connection>> {
+        self.connections.values().cloned().collect()
+    }
+
+    fn find_by_address(&self, address: &SocketAddr) -> Option<Arc<Connection>> {
+        self.by_address
+            .get(address)
+            .and_then(|id| self.connections.get(id))
+            .cloned()
+    }
+}
+
+/// Decides if a new [Connection] to a peer supersedes an existing one.
+///
+/// When 2 [Node](crate::Node)s dial each other simultaneously, both of them must keep the same
+/// [Connection], so the one dialed by the [Node](crate::Node) with the smaller account ID wins.
+/// Otherwise, the new one wins because the existing one is probably stale.
+fn supersedes(
+    new: &Connection,
+    existing: &Connection,
+    local_account_id: Hash,
+    remote_account_id: Hash,
+) -> bool {
+    if new.outgoing == existing.outgoing {
+        true
+    } else {
+        let local_dials = local_account_id.as_bytes() < remote_account_id.as_bytes();
+        new.outgoing == local_dials
+    }
+}
+
 pub struct ConnectionManager {
-    connections: Arc<RwLock<HashMap<Uuid, Arc<Connection>>>>,
+    connections: Arc<RwLock<ConnectionPool>>,
+    dial_locks: Mutex<HashMap<SocketAddr, Arc<AsyncMutex<()>>>>,
     endpoint: LocalEndpoint,
     response_window_sink: UnboundedSender<ResponseWindow>,
+    connection_sink: UnboundedSender<Arc<Connection>>,
+    signal_sink: UnboundedSender<(Vec<u8>, Signal)>,
     task_sink: TaskSink,
 }
 
 impl ConnectionManager {
+    /// Constructor.
+    ///
+    /// Every established [Connection], incoming or outgoing, is sent to `connection_sink`. Every
+    /// [Signal] received is sent to `signal_sink` along with the account ID of its sender.
     pub fn new(
         config: &Config,
         verifier: Arc<CertificateVerifier>,
         response_window_sink: UnboundedSender<ResponseWindow>,
+        connection_sink: UnboundedSender<Arc<Connection>>,
+        signal_sink: UnboundedSender<(Vec<u8>, Signal)>,
     ) -> Result<(Self, impl Future<Output = ()>), Error> {
         let (task_sink, dynamic_task) = TaskSink::new();
         let (endpoint, incoming) = LocalEndpoint::start(config, verifier)?;
@@ -171,7 +302,10 @@ impl ConnectionManager {
         let instance = Self {
             endpoint,
             response_window_sink: response_window_sink.clone(),
+            connection_sink: connection_sink.clone(),
+            signal_sink: signal_sink.clone(),
             connections: Default::default(),
+            dial_locks: Default::default(),
             task_sink: task_sink.clone(),
         };
         let account_id = instance.endpoint.account_id;
@@ -182,13 +316,18 @@ impl ConnectionManager {
             let connections = connections.clone();
             let task_sink = task_sink.clone();
             let response_window_sink = response_window_sink.clone();
+            let connection_sink = connection_sink.clone();
+            let signal_sink = signal_sink.clone();
             async move {
                 match connecting.await {
                     Ok(new_connection) => {
                         Self::add(
                             new_connection,
+                            false,
                             connections.clone(),
                             response_window_sink,
+                            connection_sink,
+                            signal_sink,
                             task_sink.clone(),
                             account_id,
                         )
@@ -206,10 +345,14 @@ impl ConnectionManager {
         Ok((instance, task))
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn add(
         new_connection: NewConnection,
-        connections: Arc<RwLock<HashMap<Uuid, Arc<Connection>>>>,
+        outgoing: bool,
+        connections: Arc<RwLock<ConnectionPool>>,
         response_window_sink: UnboundedSender<ResponseWindow>,
+        connection_sink: UnboundedSender<Arc<Connection>>,
+        signal_sink: UnboundedSender<(Vec<u8>, Signal)>,
         task_sink: TaskSink,
         account_id: Hash,
     ) -> Arc<Connection> {
@@ -217,11 +360,12 @@ impl ConnectionManager {
         let connection = Arc::<Connection>::new(Connection {
             quic: new_connection.connection,
             id: connection_id,
+            outgoing,
         });
-        connections
+        let (kept, closing) = connections
             .write()
             .unwrap()
-            .insert(connection.id, connection.clone());
+            .add(connection.clone(), account_id);
 
         // Create ResponseWindow
         let connection_clone = connection.clone();
@@ -240,6 +384,28 @@ impl ConnectionManager {
             });
         task_sink.submit(response_windows_creator_task);
 
+        // Receive Signals
+        let connection_clone = connection.clone();
+        let signals_receiver_task =
+            new_connection
+                .uni_streams
+                .for_each_concurrent(None, move |incoming| {
+                    Self::consume_uni_streams(
+                        incoming,
+                        connection_clone.clone(),
+                        signal_sink.clone(),
+                    )
+                });
+        task_sink.submit(signals_receiver_task);
+
+        if let Some(duplicate) = closing {
+            log::info!("Closing duplicated connection {:?}", &duplicate);
+            duplicate.close(StatusCode::CONFLICT);
+        }
+        if !Arc::ptr_eq(&kept, &connection) {
+            return kept;
+        }
+
         log::info!(
             "Connected to {} {:?}",
             if Some(account_id) == connection.account_id() {
@@ -249,6 +415,7 @@ impl ConnectionManager {
             },
             &connection
         );
+        let _ = connection_sink.unbounded_send(connection.clone());
         connection
     }
 
@@ -279,15 +446,142 @@ impl ConnectionManager {
         };
     }
 
+    async fn consume_uni_streams(
+        incoming: <IncomingUniStreams as Stream>::Item,
+        connection: Arc<Connection>,
+        signal_sink: UnboundedSender<(Vec<u8>, Signal)>,
+    ) {
+        let account_id = match connection.account_id() {
+            Some(account_id) => crate::database::bytes_from_hash(account_id),
+            None => return,
+        };
+        let receiver = match incoming {
+            Ok(receiver) => receiver,
+            Err(err) => {
+                log::debug!("Stopped receiving signals on {:?}: {:?}", &connection, err);
+                return;
+            }
+        };
+        match receiver.read_to_end(MAX_SIGNAL_SIZE_BYTES).await {
+            Ok(raw) => match Signal::decode(raw.as_slice()) {
+                Ok(signal) => {
+                    let _ = signal_sink.unbounded_send((account_id, signal));
+                }
+                Err(err) => log::warn!("Received a bad signal on {:?}: {:?}", &connection, err),
+            },
+            Err(err) => log::warn!("Failed to receive a signal on {:?}: {:?}", &connection, err),
+        }
+    }
+
+    /// Connects to a remote [Node](crate::Node).
+    ///
+    /// An existing [Connection] to the same address is returned if any. Simultaneous dials to the
+    /// same address result in the same [Connection].
     pub async fn connect(&self, addr: &SocketAddr) -> Result<Arc<Connection>, ConnectionError> {
-        Ok(Self::add(
-            self.endpoint.connect(addr).await?,
-            self.connections.clone(),
-            self.response_window_sink.clone(),
-            self.task_sink.clone(),
-            self.endpoint.account_id,
-        )
-        .await)
+        let dial_lock = self
+            .dial_locks
+            .lock()
+            .unwrap()
+            .entry(*addr)
+            .or_default()
+            .clone();
+        let dial_guard = dial_lock.lock().await;
+
+        let existing = self.find_by_address(addr);
+        let result = match existing {
+            Some(connection) => Ok(connection),
+            None => match self.endpoint.connect(addr).await {
+                Ok(new_connection) => Ok(Self::add(
+                    new_connection,
+                    true,
+                    self.connections.clone(),
+                    self.response_window_sink.clone(),
+                    self.connection_sink.clone(),
+                    self.signal_sink.clone(),
+                    self.task_sink.clone(),
+                    self.endpoint.account_id,
+                )
+                .await),
+                Err(err) => Err(err),
+            },
+        };
+
+        drop(dial_guard);
+        let mut dial_locks = self.dial_locks.lock().unwrap();
+        if Arc::strong_count(&dial_lock) <= 2 {
+            dial_locks.remove(addr);
+        }
+
+        result
+    }
+
+    /// Connects to a [Node](crate::Node) of an account.
+    ///
+    /// An existing [Connection] is returned if any. Otherwise, the addresses are tried in order
+    /// until a [Node](crate::Node) authenticated as the account is connected.
+    pub async fn connect_account(
+        &self,
+        account_id: &[u8],
+        addresses: Vec<SocketAddr>,
+    ) -> Result<Arc<Connection>, ConnectionError> {
+        if let Some(connection) = self.find_by_account_id(account_id) {
+            return Ok(connection);
+        }
+
+        let mut last_error = ConnectionError::NoAddress;
+        for address in addresses {
+            match self.connect(&address).await {
+                Ok(connection)
+                    if connection
+                        .account_id()
+                        .map_or(false, |id| &id.as_bytes()[..] == account_id) =>
+                {
+                    return Ok(connection);
+                }
+                Ok(connection) => {
+                    log::warn!(
+                        "Expected account {} but connected to {:?}",
+                        hex::encode_upper(account_id),
+                        &connection
+                    );
+                    connection.close(StatusCode::MISDIRECTED_REQUEST);
+                    last_error = ConnectionError::UnexpectedAccount;
+                }
+                Err(err) => {
+                    log::info!("Failed to connect to {}: {:?}", address, &err);
+                    last_error = err;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Finds a connection to a [Node](crate::Node) of an account.
+    pub fn find_by_account_id(&self, account_id: &[u8]) -> Option<Arc<Connection>> {
+        self.connections
+            .read()
+            .unwrap()
+            .find_by_account_id(account_id)
+    }
+
+    /// Finds all connections to the [Node](crate::Node)s of an account.
+    ///
+    /// Useful for finding all other devices of the local account.
+    pub fn find_all_by_account_id(&self, account_id: &[u8]) -> Vec<Arc<Connection>> {
+        self.connections
+            .read()
+            .unwrap()
+            .find_all_by_account_id(account_id)
+    }
+
+    /// Finds all connections.
+    pub fn find_all(&self) -> Vec<Arc<Connection>> {
+        self.connections.read().unwrap().find_all()
+    }
+
+    /// Finds a connection to a [Node](crate::Node) at an address.
+    pub fn find_by_address(&self, address: &SocketAddr) -> Option<Arc<Connection>> {
+        self.connections.read().unwrap().find_by_address(address)
     }
 
     pub fn local_port(&self) -> std::io::Result<u16> {
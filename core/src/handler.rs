@@ -1,47 +1,325 @@
+use crate::changelog::changelog_payload::Content as ChangelogContent;
+use crate::changelog::ChangelogMerger;
+use crate::changelog::ChangelogPayload;
+use crate::changelog::MembershipChange;
+use crate::changelog::MembershipOperation;
+use crate::changelog::Message;
+use crate::changelog::Reaction;
 use crate::daemon::event::Content;
+use crate::daemon::DeliveryState;
 use crate::daemon::Event as DaemonEvent;
+use crate::daemon::SecurityAlert;
+use crate::database::chatroom::parse_group_id;
+use crate::database::chatroom::ChatroomService;
+use crate::database::delivery::DeliveryService;
 use crate::database::message::MessageService;
 use crate::database::Database;
 use crate::database::Event as DatabaseEvent;
+use crate::endpoint::ConnectionInfo;
+use crate::outbox::Outbox;
 use crate::packet::ResponseWindow;
 use crate::pki::CanonicalId;
 use crate::proto::request::Payload;
+use crate::proto::response::Payload as ResponsePayload;
+use crate::proto::Reconciliation;
 use crate::proto::Response;
+use crate::sync::DeviceSync;
+use blake3::Hash;
+use chrono::Utc;
 use diesel::prelude::*;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::broadcast::Sender;
 use tonic::Status;
 
+/// How far ahead of the local clock a peer may date a [MembershipChange], as it would otherwise
+/// never be superseded.
+const MAX_CLOCK_SKEW_SECONDS: f64 = 300.0;
+
 #[derive(Error, Debug)]
 #[error("Error during request handling")]
 pub enum Error {
     Database(#[from] diesel::result::Error),
     GrpcOperation(#[from] Status),
     GrpcConnection(#[from] tonic::transport::Error),
+    ObjectStore(#[from] std::io::Error),
 }
 
 pub trait Handler {
     fn handle(&self, window: &ResponseWindow) -> Result<Response, Error>;
 }
 
+/// Checks if a [Message] is genuinely sent by a remote account to the local account.
+///
+/// A [Message] to a known group chatroom must be sent by one of its current members.
+pub(crate) fn verify_message(
+    connection: &SqliteConnection,
+    local_account_id: Hash,
+    remote_account_id: Option<Hash>,
+    message: &Message,
+) -> QueryResult<Result<(), String>> {
+    let remote_account_id = remote_account_id.map(crate::database::bytes_from_hash);
+    if remote_account_id.as_ref() != Some(&message.sender) {
+        return Ok(Err(
+            "Sender of the message is not the connected account".into()
+        ));
+    }
+    let local_account_id = crate::database::bytes_from_hash(local_account_id);
+    if !message.recipients.contains(&local_account_id) {
+        return Ok(Err("Local account is not a recipient of the message".into()));
+    }
+    if let Err(reason) = crate::sync::verify_size(message) {
+        return Ok(Err(format!("Message is too large: {}", reason)));
+    }
+    if !message.chatroom_id.is_empty() {
+        if parse_group_id(&message.chatroom_id).is_none() {
+            return Ok(Err("Malformed chatroom ID".into()));
+        }
+        let members = ChatroomService::find_members(connection, &message.chatroom_id)?;
+        if !members.is_empty() && !members.contains(&message.sender) {
+            return Ok(Err("Sender is not a member of the chatroom".into()));
+        }
+    }
+    Ok(Ok(()))
+}
+
 pub(crate) struct PeerHandler {
+    pub account_id: Hash,
+    pub changelog_merger: Arc<ChangelogMerger>,
     pub database: Arc<Database>,
+    pub device_sync: Arc<DeviceSync>,
     pub event_sink_database: Sender<Arc<DatabaseEvent>>,
     pub event_sink_daemon: Sender<Arc<DaemonEvent>>,
+    pub outbox: Arc<Outbox>,
+}
+
+impl PeerHandler {
+    /// Checks if a [Message] is genuinely sent by the remote peer to the local account.
+    fn verify_message(
+        &self,
+        window: &ResponseWindow,
+        message: &Message,
+    ) -> Result<Result<(), String>, Error> {
+        Ok(verify_message(
+            &self.database.connection.lock().unwrap(),
+            self.account_id,
+            window.account_id(),
+            message,
+        )?)
+    }
+
+    /// Checks if a [MembershipChange] is genuinely issued by the remote peer and concerns the local
+    /// account.
+    ///
+    /// The issuer must be a current member of the chatroom, unless the local account does not know
+    /// the chatroom yet and is being invited to it. Only the concerned account joins or leaves, so
+    /// the other members listed may lag behind concurrent changes.
+    fn verify_membership_change(
+        &self,
+        window: &ResponseWindow,
+        change: &MembershipChange,
+    ) -> Result<Result<(), String>, Error> {
+        let remote_account_id = window.account_id().map(crate::database::bytes_from_hash);
+        if remote_account_id.as_ref() != Some(&change.issuer) {
+            return Ok(Err(
+                "Issuer of the membership change is not the connected account".into(),
+            ));
+        }
+        if parse_group_id(&change.chatroom_id).is_none() {
+            return Ok(Err("Malformed chatroom ID".into()));
+        }
+        let is_member_after = change.members.contains(&change.account_id);
+        let consistent = match change.operation() {
+            MembershipOperation::Invite => is_member_after,
+            MembershipOperation::Remove => !is_member_after && change.account_id != change.issuer,
+            MembershipOperation::Leave => !is_member_after && change.account_id == change.issuer,
+        };
+        if !consistent {
+            return Ok(Err(
+                "Members after the change contradict the operation".into()
+            ));
+        }
+        let local_account_id = crate::database::bytes_from_hash(self.account_id);
+        if !change.members.contains(&local_account_id) && change.account_id != local_account_id {
+            return Ok(Err(
+                "Membership change does not concern the local account".into()
+            ));
+        }
+
+        let time_limit = crate::database::float_from_time(Utc::now()) + MAX_CLOCK_SKEW_SECONDS;
+        if !change.time.is_finite() || change.time > time_limit {
+            return Ok(Err("Membership change is too far in the future".into()));
+        }
+
+        let members = ChatroomService::find_members(
+            &self.database.connection.lock().unwrap(),
+            &change.chatroom_id,
+        )?;
+        if members.is_empty() {
+            return if change.operation() == MembershipOperation::Invite
+                && change.account_id == local_account_id
+            {
+                Ok(Ok(()))
+            } else {
+                Ok(Err("Issuer is not a member of the chatroom".into()))
+            };
+        }
+        if members.contains(&change.issuer) {
+            Ok(Ok(()))
+        } else {
+            Ok(Err("Issuer is not a member of the chatroom".into()))
+        }
+    }
+
+    /// Checks if a revision of a [Message] is genuinely issued by its sender.
+    fn verify_revision(
+        &self,
+        window: &ResponseWindow,
+        message: &Message,
+        sender: &[u8],
+    ) -> Result<(), String> {
+        let remote_account_id = window.account_id().map(crate::database::bytes_from_hash);
+        if remote_account_id.as_deref() != Some(sender) {
+            return Err("Sender of the revision is not the connected account".into());
+        }
+        if message.sender != sender {
+            return Err("Only the sender of a message may revise it".into());
+        }
+        let local_account_id = crate::database::bytes_from_hash(self.account_id);
+        if !message.recipients.contains(&local_account_id) {
+            return Err("Local account is not a recipient of the message".into());
+        }
+        Ok(())
+    }
+
+    /// Checks if a [Reaction] is genuinely from the remote peer and to a [Message] in a chatroom
+    /// shared with it.
+    fn verify_reaction(
+        &self,
+        window: &ResponseWindow,
+        message: &Message,
+        reaction: &Reaction,
+    ) -> Result<(), String> {
+        let remote_account_id = window.account_id().map(crate::database::bytes_from_hash);
+        if remote_account_id.as_ref() != Some(&reaction.account_id) {
+            return Err("Reacting account is not the connected account".into());
+        }
+        let local_account_id = crate::database::bytes_from_hash(self.account_id);
+        let is_member = |account_id: &Vec<u8>| {
+            &message.sender == account_id || message.recipients.contains(account_id)
+        };
+        if !is_member(&reaction.account_id) || !is_member(&local_account_id) {
+            return Err("Message is not in a chatroom shared with the peer".into());
+        }
+        Ok(())
+    }
+
+    /// Commits a [ChangelogPayload] received from the remote peer.
+    fn commit(&self, content: ChangelogContent) -> Result<(), Error> {
+        let payload = ChangelogPayload {
+            content: content.into(),
+        };
+        self.changelog_merger.write_objects(&payload)?;
+        let (entries, database_events) = {
+            let connection = self.database.connection.lock().unwrap();
+            connection.transaction::<_, diesel::result::Error, _>(|| {
+                self.changelog_merger
+                    .commit(&connection, std::iter::once(payload))
+            })?
+        };
+        for event in database_events {
+            let _ = self.event_sink_database.send(event.into());
+        }
+        self.device_sync.push(entries);
+        Ok(())
+    }
+
+    /// Saves the receipts of [Message]s sent to the remote peer.
+    fn acknowledge(
+        &self,
+        window: &ResponseWindow,
+        message_ids: &[Vec<u8>],
+        state: DeliveryState,
+    ) -> Result<(), Error> {
+        let remote_account_id = window
+            .account_id()
+            .map(crate::database::bytes_from_hash)
+            .unwrap_or_default();
+        let events = {
+            let connection = self.database.connection.lock().unwrap();
+            connection.transaction::<_, diesel::result::Error, _>(|| {
+                let mut events = vec![];
+                for message_id in message_ids {
+                    events.extend(DeliveryService::acknowledge(
+                        &connection,
+                        message_id,
+                        &remote_account_id,
+                        state,
+                    )?);
+                }
+                Ok(events)
+            })?
+        };
+        for event in events {
+            let _ = self.event_sink_database.send(event.into());
+        }
+        Ok(())
+    }
+
+    /// Asks the remote peer to retry a request concerning a [Message] not received yet, instead of
+    /// keeping the request until the [Message] arrives.
+    fn postpone(&self, message_id: &[u8]) -> Response {
+        log::info!(
+            "Postponing a request concerning unknown message {}",
+            hex::encode_upper(message_id)
+        );
+        Response::unavailable_with_reason("Message is not received yet".into())
+    }
+
+    /// Rejects a request violating the protocol and reports it as a security alert.
+    fn reject(&self, window: &ResponseWindow, reason: String) -> Response {
+        let remote_account_id = window
+            .account_id()
+            .map(crate::database::bytes_from_hash)
+            .unwrap_or_default();
+        log::warn!(
+            "Rejecting a request from {}: {}",
+            hex::encode_upper(&remote_account_id),
+            &reason
+        );
+
+        let daemon_event = DaemonEvent {
+            content: Content::SecurityAlert(SecurityAlert {
+                account_id: remote_account_id,
+                reason: reason.clone(),
+            })
+            .into(),
+        };
+        let _ = self.event_sink_daemon.send(daemon_event.into());
+
+        Response::forbidden_with_reason(reason)
+    }
 }
 
 impl Handler for PeerHandler {
     fn handle(&self, window: &ResponseWindow) -> Result<Response, Error> {
         match &window.request.payload {
             Some(Payload::Message(message)) => {
-                let connection = self.database.connection.lock().unwrap();
+                if let Err(reason) = self.verify_message(window, message)? {
+                    return Ok(self.reject(window, reason));
+                }
 
-                let datbase_event =
-                    connection.transaction::<_, diesel::result::Error, _>(|| {
-                        MessageService::update(&connection, &message)
-                    })?;
-                let _ = self.event_sink_database.send(datbase_event.into());
+                self.commit(ChangelogContent::AddMessage(message.clone()))?;
+                {
+                    let connection = self.database.connection.lock().unwrap();
+                    let message_id = crate::database::bytes_from_hash(message.canonical_id());
+                    crate::outbox::enqueue_receipts(
+                        &connection,
+                        std::iter::once((message.sender.clone(), message_id)),
+                        false,
+                    )?;
+                }
+                self.outbox.wake();
 
                 let daemon_event = DaemonEvent {
                     content: Content::Message(message.canonical_id().as_bytes().to_vec()).into(),
@@ -50,16 +328,152 @@ impl Handler for PeerHandler {
 
                 Ok(Default::default())
             }
+            Some(Payload::EditMessage(edit)) => {
+                if let Err(reason) = crate::sync::verify_size(edit) {
+                    return Ok(self.reject(window, format!("Edit is too large: {}", reason)));
+                }
+                let message = MessageService::find_by_id(
+                    &self.database.connection.lock().unwrap(),
+                    &edit.message_id,
+                )?;
+                let message = match message {
+                    Some(message) => message,
+                    None => return Ok(self.postpone(&edit.message_id)),
+                };
+                if let Err(reason) = self.verify_revision(window, &message, &edit.sender) {
+                    return Ok(self.reject(window, reason));
+                }
+                self.commit(ChangelogContent::EditMessage(edit.clone()))?;
+                Ok(Default::default())
+            }
+            Some(Payload::DeleteMessage(delete)) => {
+                let message = MessageService::find_by_id(
+                    &self.database.connection.lock().unwrap(),
+                    &delete.message_id,
+                )?;
+                let message = match message {
+                    Some(message) => message,
+                    None => return Ok(self.postpone(&delete.message_id)),
+                };
+                if let Err(reason) = self.verify_revision(window, &message, &delete.sender) {
+                    return Ok(self.reject(window, reason));
+                }
+                self.commit(ChangelogContent::DeleteMessage(delete.clone()))?;
+                Ok(Default::default())
+            }
+            Some(Payload::Reaction(reaction)) => {
+                if let Err(reason) = crate::database::reaction::verify_emoji(&reaction.emoji) {
+                    return Ok(self.reject(window, reason));
+                }
+                let message = MessageService::find_by_id(
+                    &self.database.connection.lock().unwrap(),
+                    &reaction.message_id,
+                )?;
+                let message = match message {
+                    Some(message) => message,
+                    None => return Ok(self.postpone(&reaction.message_id)),
+                };
+                if let Err(reason) = self.verify_reaction(window, &message, reaction) {
+                    return Ok(self.reject(window, reason));
+                }
+                self.commit(ChangelogContent::Reaction(reaction.clone()))?;
+                Ok(Default::default())
+            }
+            Some(Payload::MembershipChange(change)) => {
+                if let Err(reason) = self.verify_membership_change(window, change)? {
+                    return Ok(self.reject(window, reason));
+                }
+                self.commit(ChangelogContent::ChangeMembership(change.clone()))?;
+                Ok(Default::default())
+            }
+            Some(Payload::Delivered(receipt)) => {
+                self.acknowledge(window, &receipt.ids, DeliveryState::Delivered)?;
+                Ok(Default::default())
+            }
+            Some(Payload::Read(receipt)) => {
+                self.acknowledge(window, &receipt.ids, DeliveryState::Read)?;
+                Ok(Default::default())
+            }
+            Some(Payload::Reconciliation(reconciliation)) => {
+                let remote_account_id = window
+                    .account_id()
+                    .map(crate::database::bytes_from_hash)
+                    .unwrap_or_default();
+                let connection = self.database.connection.lock().unwrap();
+                let members =
+                    ChatroomService::find_members(&connection, &reconciliation.chatroom_id)?;
+                if !members.contains(&remote_account_id) {
+                    return Ok(self.reject(window, "Not a member of the chatroom".into()));
+                }
+                let ranges = crate::reconciliation::respond(
+                    &connection,
+                    &reconciliation.chatroom_id,
+                    &reconciliation.sender,
+                    reconciliation.ranges.clone(),
+                )?;
+                Ok(Response {
+                    payload: ResponsePayload::Reconciliation(Reconciliation {
+                        chatroom_id: reconciliation.chatroom_id.clone(),
+                        ranges,
+                        sender: reconciliation.sender.clone(),
+                    })
+                    .into(),
+                    ..Default::default()
+                })
+            }
+            Some(Payload::MessagesRequest(request)) => {
+                let remote_account_id = window
+                    .account_id()
+                    .map(crate::database::bytes_from_hash)
+                    .unwrap_or_default();
+                let messages = crate::reconciliation::find_messages(
+                    &self.database.connection.lock().unwrap(),
+                    &remote_account_id,
+                    &request.message_ids,
+                )?;
+                Ok(Response {
+                    payload: ResponsePayload::Messages(messages).into(),
+                    ..Default::default()
+                })
+            }
             _ => DefaultHandler.handle(window),
         }
     }
 }
 
-pub struct DeviceHandler;
+pub(crate) struct DeviceHandler {
+    pub changelog_merger: Arc<ChangelogMerger>,
+    pub database: Arc<Database>,
+    pub device_sync: Arc<DeviceSync>,
+    pub event_sink_database: Sender<Arc<DatabaseEvent>>,
+}
 
 impl Handler for DeviceHandler {
     fn handle(&self, window: &ResponseWindow) -> Result<Response, Error> {
-        match &window.request {
+        match &window.request.payload {
+            Some(Payload::Changelog(changelog)) => {
+                for entry in changelog.entries.iter() {
+                    if let Some(payload) = &entry.payload {
+                        self.changelog_merger.write_objects(payload)?;
+                    }
+                }
+                let connection = self.database.connection.lock().unwrap();
+                let events = connection.transaction::<_, diesel::result::Error, _>(|| {
+                    self.changelog_merger
+                        .merge(&connection, changelog.entries.iter().cloned())
+                })?;
+                for event in events {
+                    let _ = self.event_sink_database.send(event.into());
+                }
+                Ok(Default::default())
+            }
+            Some(Payload::ChangelogRequest(request)) => {
+                let device_sync = self.device_sync.clone();
+                let device = window.connection().clone();
+                let since = request.since.clone();
+                crate::util::spawn(async move { device_sync.push_since(device, &since).await });
+                Ok(Default::default())
+            }
             _ => DefaultHandler.handle(window),
         }
     }
@@ -78,3 +492,129 @@ impl Handler for DefaultHandler {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::changelog::Chatroom;
+    use crate::changelog::MembershipChange;
+    use crate::changelog::MembershipOperation;
+    use crate::database::chatroom::generate_group_id;
+    use crate::database::chatroom::ChatroomService;
+    use crate::endpoint::ConnectionInfo;
+    use crate::proto::request::Payload;
+    use crate::proto::Request;
+    use chrono::Utc;
+    use http::StatusCode;
+
+    #[tokio::test]
+    async fn reject_forged_sender() -> anyhow::Result<()> {
+        let (target, _) = crate::util::start_dummy_node().await?;
+        let (prober, _) = crate::util::start_dummy_node().await?;
+        let target_address = format!("[::1]:{}", target.local_port()?).parse()?;
+        let connection = prober.connect(&target_address).await?;
+        let target_account_id = connection
+            .account_id()
+            .map(crate::database::bytes_from_hash)
+            .unwrap();
+
+        let request = Request {
+            payload: Payload::Message(crate::changelog::Message {
+                time: 0.0,
+                sender: vec![0; 32],
+                recipients: vec![target_account_id],
+                content: "Forged".into(),
+                attachment: None,
+                attachment_reference: None,
+                in_reply_to: vec![],
+                chatroom_id: vec![],
+            })
+            .into(),
+        };
+        let response = connection.request(&request).await?;
+        assert_eq!(Some(StatusCode::FORBIDDEN), response.status_code());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn postpone_revision_of_unknown_message() -> anyhow::Result<()> {
+        let (target, _) = crate::util::start_dummy_node().await?;
+        let (prober, _) = crate::util::start_dummy_node().await?;
+        let target_address = format!("[::1]:{}", target.local_port()?).parse()?;
+        let connection = prober.connect(&target_address).await?;
+
+        let request = Request {
+            payload: Payload::EditMessage(crate::changelog::EditMessage {
+                message_id: vec![0; 32],
+                sender: crate::database::bytes_from_hash(prober.account_id),
+                time: 0.0,
+                content: "Edited".into(),
+            })
+            .into(),
+        };
+        let response = connection.request(&request).await?;
+        assert_eq!(
+            Some(StatusCode::SERVICE_UNAVAILABLE),
+            response.status_code()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_concurrent_invites() -> anyhow::Result<()> {
+        let (target, _) = crate::util::start_dummy_node().await?;
+        let (inviter_1, _) = crate::util::start_dummy_node().await?;
+        let (inviter_2, _) = crate::util::start_dummy_node().await?;
+        let target_address = format!("[::1]:{}", target.local_port()?).parse()?;
+        let account_id = |node: &crate::Node| crate::database::bytes_from_hash(node.account_id);
+        let members = vec![
+            account_id(&target),
+            account_id(&inviter_1),
+            account_id(&inviter_2),
+        ];
+        let group_id = crate::database::bytes_from_hash(generate_group_id(&account_id(&inviter_1)));
+        ChatroomService::save(
+            &target.database.connection.lock().unwrap(),
+            &Chatroom {
+                name: "Group".into(),
+                members: members.clone(),
+                chatroom_id: group_id.clone(),
+            },
+            &[],
+        )?;
+
+        // Each inviter only knows about its own invitee
+        for (inviter, invitee) in [(&inviter_1, vec![5; 32]), (&inviter_2, vec![6; 32])].iter() {
+            let mut change = MembershipChange {
+                chatroom_id: group_id.clone(),
+                issuer: account_id(*inviter),
+                time: crate::database::float_from_time(Utc::now()),
+                account_id: invitee.clone(),
+                members: members
+                    .iter()
+                    .cloned()
+                    .chain(std::iter::once(invitee.clone()))
+                    .collect(),
+                ..Default::default()
+            };
+            change.set_operation(MembershipOperation::Invite);
+            let request = Request {
+                payload: Payload::MembershipChange(change).into(),
+            };
+            let connection = inviter.connect(&target_address).await?;
+            let response = connection.request(&request).await?;
+            assert_eq!(Some(StatusCode::OK), response.status_code());
+        }
+
+        let mut members_after =
+            ChatroomService::find_members(&target.database.connection.lock().unwrap(), &group_id)?;
+        members_after.sort();
+        let mut members_expected = members;
+        members_expected.extend(vec![vec![5; 32], vec![6; 32]]);
+        members_expected.sort();
+        assert_eq!(members_expected, members_after);
+
+        Ok(())
+    }
+}
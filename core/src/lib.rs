@@ -13,34 +13,55 @@ extern crate diesel_migrations;
 pub mod bridge;
 
 mod changelog;
+mod clock;
 mod daemon;
 pub mod database;
+pub mod discovery;
 mod endpoint;
 mod handler;
 mod mock_profile;
+mod outbox;
 mod packet;
 pub mod pki;
+mod presence;
 pub mod proto;
+mod reconciliation;
+mod sync;
+mod transfer;
 pub mod util;
 
+use self::changelog::ChangelogMerger;
+use self::clock::HybridClock;
 use self::daemon::Event;
 use self::database::ProfileConfig;
+use self::presence::PresenceTracker;
+use self::reconciliation::Reconciler;
+use self::transfer::Downloader;
+use crate::database::changelog::ChangelogService;
+use crate::database::outbox::OutboxService;
 use crate::database::peer::PeerService;
+use crate::database::peer_address::PeerAddressService;
 use crate::database::Database;
 use crate::endpoint::CertificateVerifier;
 use blake3::Hash;
 use database::DatabaseInitializationError;
-use database::Storage;
+use diesel::Connection as _;
+use discovery::Discovered;
+use discovery::Discovery;
+use discovery::DiscoveryConfig;
 use endpoint::ConnectionInfo;
 use endpoint::ConnectionManager;
 use futures_util::FutureExt;
+use futures_util::StreamExt;
 use http::StatusCode;
+use outbox::Outbox;
 use packet::ResponseWindow;
 use pki::CanonicalId;
 use prost::DecodeError;
 use prost::Message as _;
 use proto::Request;
 use proto::Response;
+use proto::Signal;
 use quinn::ReadToEndError;
 use serde_bytes::ByteBuf;
 use std::any::Any;
@@ -54,8 +75,10 @@ use std::sync::atomic::AtomicI32;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
+use sync::DeviceSync;
 use thiserror::Error;
 use tokio::sync::broadcast::Sender;
+use tokio::sync::oneshot;
 use uuid::Uuid;
 
 static CURRENT_NODE_HANDLE: AtomicI32 = AtomicI32::new(0);
@@ -92,7 +115,10 @@ pub fn stop(handle: i32) {
 
 /// The protagonist.
 pub struct Node {
-    connection_manager: ConnectionManager,
+    account_id: Hash,
+    connection_manager: Arc<ConnectionManager>,
+    discovery_shutdown_token: Mutex<Option<oneshot::Sender<()>>>,
+    database: Arc<Database>,
     _node_grpc_shutdown_token: Box<dyn Any + Send>,
     grpc_port: u16,
     event_sink_daemon: Sender<Arc<Event>>,
@@ -111,9 +137,20 @@ impl Node {
         profile_config: &ProfileConfig,
         grpc_port: u16,
     ) -> Result<(Self, impl Future<Output = ()>), NodeStartError> {
-        let database = Arc::new(Database::create(&Storage::OnDisk(
-            profile_config.path_database(account_id).await?,
-        ))?);
+        let database = Arc::new(Database::create(
+            &profile_config.storage(account_id).await?,
+        )?);
+        let clock = {
+            let connection = database.connection.lock().unwrap();
+            connection.transaction::<_, diesel::result::Error, _>(|| {
+                let clock = HybridClock::new(
+                    ChangelogService::device_id(&connection)?,
+                    ChangelogService::latest_timestamp(&connection)?.as_ref(),
+                );
+                ChangelogService::backfill(&connection, &database.objects, &clock)?;
+                Ok(clock)
+            })?
+        };
 
         let (event_sink_database, _) = tokio::sync::broadcast::channel(8);
 
@@ -131,37 +168,148 @@ impl Node {
             std::iter::empty(),
             PeerService::blacklist(&database.connection.lock().unwrap())?,
         );
-
-        // Start gRPC server
-        let (event_sink_daemon, _) = tokio::sync::broadcast::channel(8);
-        let (grpc_task, node_grpc_shutdown_token) = daemon::StandardNode::create(
-            grpc_port,
-            event_sink_database.clone(),
-            event_sink_daemon.clone(),
-            database.clone(),
-        );
+        let changelog_merger = Arc::new(ChangelogMerger {
+            clock,
+            peer_service: PeerService {
+                verifier: Some(certificate_verifier.clone()),
+            }
+            .into(),
+            objects: database.objects.clone(),
+        });
 
         // QUIC endpoint and connection manager
         let endpoint_config = self::endpoint::Config {
             certificate: &certificate,
             key: &key,
         };
+        let (event_sink_daemon, _) = tokio::sync::broadcast::channel(8);
         let (window_sender, window_receiver) = futures_channel::mpsc::unbounded::<ResponseWindow>();
+        let (connection_sender, connection_receiver) =
+            futures_channel::mpsc::unbounded::<Arc<Connection>>();
+        let (signal_sender, signal_receiver) =
+            futures_channel::mpsc::unbounded::<(Vec<u8>, Signal)>();
+        let (connection_manager, connection_manager_task) = ConnectionManager::new(
+            &endpoint_config,
+            certificate_verifier,
+            window_sender,
+            connection_sender,
+            signal_sender,
+        )?;
+        let connection_manager = Arc::new(connection_manager);
+
+        // Presence and typing indicators
+        let presence = Arc::new(PresenceTracker::new(
+            account_id.into(),
+            Arc::downgrade(&connection_manager),
+            database.clone(),
+        ));
+        let presence_task = presence.clone().run(signal_receiver);
+
+        // Device synchronization
+        let device_sync = Arc::new(DeviceSync::new(
+            account_id_calculated,
+            connection_manager.clone(),
+            database.clone(),
+        ));
+
+        // Outbox
+        let (outbox, outbox_task) = Outbox::new(
+            connection_manager.clone(),
+            database.clone(),
+            event_sink_database.clone(),
+        );
+        let outbox = Arc::new(outbox);
+
+        // Downloading large attachments
+        let (downloader, downloader_task) = Downloader::new(
+            connection_manager.clone(),
+            database.clone(),
+            event_sink_database.clone(),
+        );
+
+        // Request handlers
         let request_handler_task = ResponseWindow::consumer_task(
             account_id_calculated,
             window_receiver,
             database.clone(),
+            changelog_merger.clone(),
+            device_sync.clone(),
+            event_sink_database.clone(),
+            event_sink_daemon.clone(),
+            outbox.clone(),
+        );
+
+        // Message history recovery
+        let reconciler = Arc::new(Reconciler {
+            account_id: account_id_calculated,
+            changelog_merger: changelog_merger.clone(),
+            database: database.clone(),
+            device_sync: device_sync.clone(),
+            event_sink_database: event_sink_database.clone(),
+        });
+
+        // Learn where peers can be reached and retry sending anything to them, and catch up with
+        // other devices and peers
+        let connection_task = {
+            let database = database.clone();
+            let outbox = outbox.clone();
+            let device_sync = device_sync.clone();
+            let presence = presence.clone();
+            connection_receiver.for_each(move |connection| {
+                if connection.account_id() == Some(account_id_calculated) {
+                    let device_sync = device_sync.clone();
+                    self::util::spawn(async move { device_sync.pull(connection).await });
+                } else if let Some(account_id) = connection.account_id() {
+                    let reconciler = reconciler.clone();
+                    let peer = connection.clone();
+                    self::util::spawn(async move { reconciler.reconcile_all(&peer).await });
+
+                    let presence = presence.clone();
+                    let peer = connection.clone();
+                    self::util::spawn(async move { presence.greet(peer).await });
+
+                    let database_connection = database.connection.lock().unwrap();
+                    database_connection
+                        .transaction::<_, diesel::result::Error, _>(|| {
+                            PeerAddressService::save(
+                                &database_connection,
+                                account_id.as_bytes(),
+                                &connection.remote_address(),
+                            )?;
+                            OutboxService::expedite(&database_connection, account_id.as_bytes())
+                        })
+                        .unwrap_or_else(|err| {
+                            log::error!("Failed to process a new connection: {:?}", err)
+                        });
+                    outbox.wake();
+                    downloader.wake();
+                }
+                futures_util::future::ready(())
+            })
+        };
+
+        // Start gRPC server
+        let (grpc_task, node_grpc_shutdown_token) = daemon::StandardNode::create(
+            grpc_port,
+            account_id.into(),
             event_sink_database,
             event_sink_daemon.clone(),
+            database.clone(),
+            changelog_merger,
+            outbox,
+            device_sync,
+            presence,
         );
-        let (connection_manager, connection_manager_task) =
-            ConnectionManager::new(&endpoint_config, certificate_verifier, window_sender)?;
 
         let task = async move {
             futures_util::join!(
                 grpc_task.boxed(),
                 request_handler_task.boxed(),
                 connection_manager_task.boxed(),
+                connection_task.boxed(),
+                outbox_task.boxed(),
+                presence_task.boxed(),
+                downloader_task.boxed(),
             );
         };
 
@@ -171,7 +319,10 @@ impl Node {
         );
         Ok((
             Self {
+                account_id: account_id_calculated,
                 connection_manager,
+                discovery_shutdown_token: Default::default(),
+                database,
                 _node_grpc_shutdown_token: Box::new(node_grpc_shutdown_token),
                 grpc_port,
                 event_sink_daemon,
@@ -185,6 +336,59 @@ impl Node {
         self.connection_manager.connect(addr).await
     }
 
+    /// Connects to a remote [Node] of an account.
+    ///
+    /// The known addresses of the account are tried in order, starting from the most recently seen
+    /// one.
+    pub async fn connect_account(
+        &self,
+        account_id: &[u8],
+    ) -> Result<Arc<Connection>, ConnectionError> {
+        let addresses = PeerAddressService::find_by_account_id(
+            &self.database.connection.lock().unwrap(),
+            account_id,
+        )?;
+        self.connection_manager
+            .connect_account(account_id, addresses)
+            .await
+    }
+
+    /// Starts announcing this [Node] on the local network and discovering the others.
+    ///
+    /// Discovered [Node]s of the local account or of friends are connected to. Discovery runs until
+    /// [Node] is dropped or this method is called again.
+    pub fn start_discovery(&self, config: &DiscoveryConfig) -> std::io::Result<()> {
+        let discovery = Discovery::new(
+            config,
+            self.account_id,
+            self.local_port()?,
+            self.database.clone(),
+        )?;
+        let (discovered_sink, discovered_receiver) = futures_channel::mpsc::unbounded();
+        let connection_manager = self.connection_manager.clone();
+        let account_id = self.account_id;
+        let connect_task = discovered_receiver.for_each_concurrent(None, move |discovered| {
+            let connection_manager = connection_manager.clone();
+            async move {
+                let result = connect_discovered(&connection_manager, account_id, discovered).await;
+                if let Err(err) = result {
+                    log::warn!("Failed to connect to a discovered node: {:?}", err);
+                }
+            }
+        });
+
+        // Discovery stops once the connecting task drops the receiver
+        let (shutdown_token, shutdown) = oneshot::channel::<()>();
+        self::util::spawn(async move {
+            if let Err(err) = discovery.run(discovered_sink).await {
+                log::error!("Discovery failed: {:?}", err);
+            }
+        });
+        self::util::spawn(futures_util::future::select(connect_task, shutdown).map(drop));
+        *self.discovery_shutdown_token.lock().unwrap() = Some(shutdown_token);
+        Ok(())
+    }
+
     /// Gets the local port.
     pub fn local_port(&self) -> std::io::Result<u16> {
         self.connection_manager.local_port()
@@ -196,10 +400,37 @@ impl Node {
     }
 }
 
+async fn connect_discovered(
+    connection_manager: &ConnectionManager,
+    local_account_id: Hash,
+    discovered: Discovered,
+) -> Result<(), ConnectionError> {
+    if discovered.account_id.as_slice() == local_account_id.as_bytes() {
+        // Devices share the same account ID
+        if connection_manager
+            .find_by_address(&discovered.address)
+            .is_none()
+        {
+            connection_manager.connect(&discovered.address).await?;
+        }
+    } else if connection_manager
+        .find_by_account_id(&discovered.account_id)
+        .is_none()
+    {
+        connection_manager
+            .connect_account(&discovered.account_id, vec![discovered.address])
+            .await?;
+    }
+    Ok(())
+}
+
 /// Connection to a remote [Node].
 pub struct Connection {
     id: Uuid,
     quic: quinn::Connection,
+
+    /// Whether it is dialed by the local [Node].
+    outgoing: bool,
 }
 
 impl Connection {
@@ -233,6 +464,49 @@ impl Connection {
         }
     }
 
+    /// Sends a [Request] and awaits for its [Response] followed by some raw content.
+    ///
+    /// The [Response] is prefixed by its length in 4 bytes of big endian. The rest of the returned
+    /// stream is the content.
+    pub async fn request_content(
+        &self,
+        request: &Request,
+    ) -> Result<(Response, quinn::RecvStream), RequestError> {
+        let (mut sender, mut receiver) = self.quic.open_bi().await?;
+        let mut raw_request = Vec::<u8>::new();
+        request
+            .encode(&mut raw_request)
+            .unwrap_or_else(|err| panic!("Failed to encode a request: {}", err));
+
+        sender.write_all(&raw_request).await?;
+        sender.finish().await?;
+
+        let mut length = [0; 4];
+        receiver.read_exact(&mut length).await?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length > packet::MAX_PACKET_SIZE_BYTES {
+            self.close(StatusCode::PAYLOAD_TOO_LARGE);
+            return Err(RequestError::ResponseTooLong);
+        }
+        let mut raw_response = vec![0; length];
+        receiver.read_exact(&mut raw_response).await?;
+        let response = Response::decode(raw_response.as_slice())?;
+        log::debug!("Received response: {:?}", &response);
+        Ok((response, receiver))
+    }
+
+    /// Sends a [Signal] without waiting for any response.
+    pub async fn signal(&self, signal: &Signal) -> Result<(), RequestError> {
+        let mut sender = self.quic.open_uni().await?;
+        let mut raw_signal = Vec::<u8>::new();
+        signal
+            .encode(&mut raw_signal)
+            .unwrap_or_else(|err| panic!("Failed to encode a signal: {}", err));
+        sender.write_all(&raw_signal).await?;
+        sender.finish().await?;
+        Ok(())
+    }
+
     /// Closes the connection with a reason code.
     pub fn close(&self, code: StatusCode) {
         log::info!("Closing connection to {}", self.remote_address());
@@ -256,6 +530,7 @@ impl Debug for Connection {
             .debug_struct("Connection")
             .field("connection_id", &self.id)
             .field("remote_address", &self.remote_address())
+            .field("outgoing", &self.outgoing)
             .field(
                 "account_id",
                 &self
@@ -274,6 +549,7 @@ pub enum RequestError {
     BadResponse(#[from] DecodeError),
     Connection(#[from] quinn::ConnectionError),
     Read(#[from] quinn::ReadError),
+    ReadExact(#[from] quinn::ReadExactError),
     ResponseTooLong,
     Write(#[from] quinn::WriteError),
 }
@@ -282,8 +558,15 @@ pub enum RequestError {
 #[derive(Error, Debug)]
 #[error("Failed to connect to a remote node")]
 pub enum ConnectionError {
+    Database(#[from] diesel::result::Error),
     Start(#[from] quinn::ConnectError),
     Handshake(#[from] quinn::ConnectionError),
+
+    #[error("No known address of the account")]
+    NoAddress,
+
+    #[error("Remote node is not of the expected account")]
+    UnexpectedAccount,
 }
 
 #[derive(Error, Debug)]
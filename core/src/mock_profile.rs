@@ -1,10 +1,10 @@
+use crate::changelog::changelog_payload::Content;
 use crate::changelog::ChangelogMerger;
 use crate::changelog::ChangelogPayload;
 use crate::changelog::Message;
 use crate::changelog::Peer;
 use crate::changelog::PeerRole;
 use crate::changelog::Vcard;
-use crate::database::vcard::VcardService;
 use crate::database::Database;
 use chrono::prelude::*;
 use chrono::Duration;
@@ -38,14 +38,14 @@ impl MockProfileService {
             vcards.len(),
             changelog.len()
         );
+        let vcards = vcards.into_iter().map(|vcard| ChangelogPayload {
+            content: Content::AddVcard(vcard).into(),
+        });
         let connection = self.database.connection.lock().unwrap();
         connection.transaction::<_, diesel::result::Error, _>(|| {
-            log::info!("Committing the mock Vcards as a transaction");
-            VcardService::save(&connection, vcards.into_iter())?;
-
             log::info!("Merging changelog generated from `mock_profile`");
             self.changelog_merger
-                .commit(&connection, changelog.into_iter())?;
+                .commit(&connection, vcards.chain(changelog))?;
 
             Ok(())
         })
@@ -120,6 +120,9 @@ fn random_messages(account_id: &[u8], friends: &[Vcard]) -> Message {
         recipients,
         content,
         attachment: None,
+        attachment_reference: None,
+        in_reply_to: vec![],
+        chatroom_id: vec![],
     }
 }
 
@@ -144,11 +147,13 @@ fn random_account_id() -> Vec<u8> {
     (0..32_u8).map(|_| thread_rng().gen()).collect()
 }
 
+/// Some time within the past year, so that the chatrooms are ordered as if they were active.
 fn random_time() -> f64 {
     let mut rng = rand::thread_rng();
-    let offset = Duration::days(365 * 100).num_seconds();
+    let now = Utc::now().timestamp();
+    let offset = Duration::days(365).num_seconds();
     loop {
-        let time = rng.gen_range(-offset..offset);
+        let time = rng.gen_range((now - offset)..now);
         let result = Utc.timestamp_opt(time, 0);
         if let LocalResult::Single(datetime) = result {
             break crate::database::float_from_time(datetime);
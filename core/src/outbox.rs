@@ -0,0 +1,314 @@
+//! Delivery of [Request]s to other [Node](crate::Node)s.
+//!
+//! [Request]s are persisted in the database and are retried with exponential backoff until they
+//! are acknowledged, so that they survive restarts and recipients being offline.
+
+use crate::changelog::Message;
+use crate::daemon::DeliveryState;
+use crate::database::delivery::DeliveryService;
+use crate::database::outbox::OutboxEntry;
+use crate::database::outbox::OutboxService;
+use crate::database::peer_address::PeerAddressService;
+use crate::database::Database;
+use crate::database::Event as DatabaseEvent;
+use crate::endpoint::ConnectionManager;
+use crate::pki::CanonicalId;
+use crate::proto::request::Payload;
+use crate::proto::MessageIds;
+use crate::proto::Request;
+use chrono::Utc;
+use diesel::prelude::*;
+use futures_channel::mpsc::UnboundedReceiver;
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::future::Either;
+use futures_util::StreamExt;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::Sender;
+
+/// Delay before the first retry.
+const RETRY_DELAY_INITIAL: Duration = Duration::from_secs(5);
+
+/// Maximum delay between retries.
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// Time limit of an attempt, so that an unresponsive recipient never holds up the others.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends the [Request]s queued by [OutboxService].
+pub(crate) struct Outbox {
+    wake_sink: UnboundedSender<()>,
+}
+
+impl Outbox {
+    /// Constructor.
+    ///
+    /// The returned [Future] sends the queued [Request]s. It runs to completion once [Outbox] is
+    /// dropped.
+    pub fn new(
+        connection_manager: Arc<ConnectionManager>,
+        database: Arc<Database>,
+        event_sink_database: Sender<Arc<DatabaseEvent>>,
+    ) -> (Self, impl Future<Output = ()>) {
+        let (wake_sink, wake_receiver) = futures_channel::mpsc::unbounded();
+        let courier = Courier {
+            connection_manager,
+            database,
+            event_sink_database,
+        };
+        let task = async move {
+            crate::util::spawn(courier.run(wake_receiver))
+                .await
+                .unwrap()
+        };
+        (Self { wake_sink }, task)
+    }
+
+    /// Attempts all due [Request]s immediately.
+    ///
+    /// Must be called after new [Request]s are queued.
+    pub fn wake(&self) {
+        let _ = self.wake_sink.unbounded_send(());
+    }
+}
+
+/// Queues a [Message] authored by the local account to every recipient.
+pub(crate) fn enqueue_message(
+    connection: &'_ SqliteConnection,
+    account_id: &[u8],
+    message: &Message,
+) -> QueryResult<Vec<DatabaseEvent>> {
+    let message_id = crate::database::bytes_from_hash(message.canonical_id());
+    let request = Request {
+        payload: Some(Payload::Message(message.clone())),
+    };
+    let mut events = vec![];
+    for recipient in message.recipients.iter() {
+        if recipient == account_id {
+            continue;
+        }
+        OutboxService::enqueue(connection, recipient, Some(&message_id), &request)?;
+        events.push(DeliveryService::save(
+            connection,
+            &message_id,
+            recipient,
+            DeliveryState::Pending,
+            "",
+        )?);
+    }
+    Ok(events)
+}
+
+/// Queues a [Request] regarding a [Message] to every member of its chatroom other than the local
+/// account.
+pub(crate) fn enqueue_for_message(
+    connection: &'_ SqliteConnection,
+    account_id: &[u8],
+    message: &Message,
+    request: &Request,
+) -> QueryResult<()> {
+    let members = message
+        .recipients
+        .iter()
+        .chain(std::iter::once(&message.sender));
+    enqueue_to(connection, account_id, members, request)
+}
+
+/// Queues a [Request] to some accounts other than the local account.
+pub(crate) fn enqueue_to<'a>(
+    connection: &'_ SqliteConnection,
+    account_id: &[u8],
+    recipients: impl IntoIterator<Item = &'a Vec<u8>>,
+    request: &Request,
+) -> QueryResult<()> {
+    for recipient in recipients {
+        if recipient != account_id {
+            OutboxService::enqueue(connection, recipient, None, request)?;
+        }
+    }
+    Ok(())
+}
+
+/// Queues receipts of [Message]s to their senders.
+///
+/// * `receipts`: Senders and IDs of the [Message]s.
+/// * `read`: Whether the [Message]s are read or only delivered.
+pub(crate) fn enqueue_receipts(
+    connection: &'_ SqliteConnection,
+    receipts: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    read: bool,
+) -> QueryResult<()> {
+    let mut message_ids_by_sender = BTreeMap::<Vec<u8>, Vec<Vec<u8>>>::new();
+    for (sender, message_id) in receipts {
+        message_ids_by_sender
+            .entry(sender)
+            .or_default()
+            .push(message_id);
+    }
+    for (sender, ids) in message_ids_by_sender {
+        let ids = MessageIds { ids };
+        let payload = if read {
+            Payload::Read(ids)
+        } else {
+            Payload::Delivered(ids)
+        };
+        let request = Request {
+            payload: payload.into(),
+        };
+        OutboxService::enqueue(connection, &sender, None, &request)?;
+    }
+    Ok(())
+}
+
+struct Courier {
+    connection_manager: Arc<ConnectionManager>,
+    database: Arc<Database>,
+    event_sink_database: Sender<Arc<DatabaseEvent>>,
+}
+
+impl Courier {
+    async fn run(self, mut wake_receiver: UnboundedReceiver<()>) {
+        loop {
+            let due = self.find_due();
+            futures_util::future::join_all(due.into_iter().map(|entry| self.attempt(entry))).await;
+
+            let wake = wake_receiver.next();
+            let woken = match self.delay_until_next_attempt() {
+                Some(delay) => {
+                    let sleep = tokio::time::sleep(delay);
+                    futures_util::pin_mut!(sleep);
+                    match futures_util::future::select(wake, sleep).await {
+                        Either::Left((signal, _)) => signal.is_some(),
+                        Either::Right(_) => true,
+                    }
+                }
+                None => wake.await.is_some(),
+            };
+            if !woken {
+                log::info!("Shutting down outbox");
+                return;
+            }
+        }
+    }
+
+    fn find_due(&self) -> Vec<OutboxEntry> {
+        let connection = self.database.connection.lock().unwrap();
+        let now = crate::database::float_from_time(Utc::now());
+        OutboxService::find_due(&connection, now).unwrap_or_else(|err| {
+            log::error!("Failed to query the outbox: {:?}", err);
+            Default::default()
+        })
+    }
+
+    fn delay_until_next_attempt(&self) -> Option<Duration> {
+        let connection = self.database.connection.lock().unwrap();
+        let now = crate::database::float_from_time(Utc::now());
+        match OutboxService::time_next_attempt(&connection) {
+            Ok(time) => time.map(|time| Duration::from_secs_f64((time - now).max(0.0))),
+            Err(err) => {
+                log::error!("Failed to query the outbox: {:?}", err);
+                Some(RETRY_DELAY_MAX)
+            }
+        }
+    }
+
+    fn find_addresses(&self, account_id: &[u8]) -> Vec<SocketAddr> {
+        let connection = self.database.connection.lock().unwrap();
+        PeerAddressService::find_by_account_id(&connection, account_id).unwrap_or_else(|err| {
+            log::error!("Failed to query the address book: {:?}", err);
+            Default::default()
+        })
+    }
+
+    async fn attempt(&self, entry: OutboxEntry) {
+        let addresses = self.find_addresses(&entry.recipient_account_id);
+        let send = async {
+            let connection = self
+                .connection_manager
+                .connect_account(&entry.recipient_account_id, addresses)
+                .await
+                .map_err(|err| err.to_string())?;
+            connection
+                .request(&entry.request)
+                .await
+                .map_err(|err| err.to_string())
+        };
+        let result = match tokio::time::timeout(ATTEMPT_TIMEOUT, send).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("Timed out after {:?}", ATTEMPT_TIMEOUT)),
+        };
+        let (state, reason) = match result {
+            Ok(response) => match response.status_code() {
+                Some(code) if code.is_success() => (DeliveryState::Delivered, response.reason),
+                Some(code) if code.is_client_error() => (DeliveryState::Rejected, response.reason),
+                _ => (DeliveryState::Pending, response.reason),
+            },
+            Err(reason) => (DeliveryState::Pending, reason),
+        };
+        log::info!(
+            "Attempt {} sending to {}: {:?} {}",
+            entry.attempts + 1,
+            hex::encode_upper(&entry.recipient_account_id),
+            state,
+            &reason
+        );
+
+        let connection = self.database.connection.lock().unwrap();
+        let result = connection.transaction::<_, diesel::result::Error, _>(|| {
+            if let DeliveryState::Pending = state {
+                let attempts = entry.attempts + 1;
+                let delay = retry_delay(attempts as u32);
+                let time_next_attempt =
+                    crate::database::float_from_time(Utc::now()) + delay.as_secs_f64();
+                OutboxService::postpone(&connection, &entry.id, attempts, time_next_attempt)?;
+            } else {
+                OutboxService::remove(&connection, &entry.id)?;
+            }
+            entry
+                .message_id
+                .as_ref()
+                .map(|message_id| {
+                    DeliveryService::save(
+                        &connection,
+                        message_id,
+                        &entry.recipient_account_id,
+                        state,
+                        &reason,
+                    )
+                })
+                .transpose()
+        });
+        match result {
+            Ok(Some(event)) => {
+                let _ = self.event_sink_database.send(event.into());
+            }
+            Ok(None) => {}
+            Err(err) => log::error!("Failed to update the outbox: {:?}", err),
+        }
+    }
+}
+
+/// Calculates the delay before the next attempt after some failed attempts.
+pub(crate) fn retry_delay(attempts: u32) -> Duration {
+    let factor = 2_u32.saturating_pow(attempts.saturating_sub(1));
+    RETRY_DELAY_INITIAL
+        .checked_mul(factor)
+        .map_or(RETRY_DELAY_MAX, |delay| delay.min(RETRY_DELAY_MAX))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry_delay_grows_exponentially() {
+        assert_eq!(Duration::from_secs(5), retry_delay(1));
+        assert_eq!(Duration::from_secs(10), retry_delay(2));
+        assert_eq!(Duration::from_secs(40), retry_delay(4));
+        assert_eq!(RETRY_DELAY_MAX, retry_delay(16));
+        assert_eq!(RETRY_DELAY_MAX, retry_delay(u32::MAX));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::changelog::ChangelogMerger;
 use crate::daemon::Event as DaemonEvent;
 use crate::database::Database;
 use crate::database::Event as DatabaseEvent;
@@ -5,11 +6,15 @@ use crate::endpoint::ConnectionInfo;
 use crate::handler::DeviceHandler;
 use crate::handler::Handler;
 use crate::handler::PeerHandler;
+use crate::outbox::Outbox;
+use crate::proto::request::Payload;
 use crate::proto::Request;
 use crate::proto::Response;
+use crate::sync::DeviceSync;
 use crate::Connection;
 use blake3::Hash;
 use futures_core::Stream;
+use futures_util::FutureExt;
 use futures_util::StreamExt;
 use http::StatusCode;
 use prost::DecodeError;
@@ -69,26 +74,74 @@ impl ResponseWindow {
         }
     }
 
+    /// Gets the [Connection] where the [Request] comes from.
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+
     pub async fn send_response(mut self, response: Response) -> Result<(), WriteError> {
         send_response(&mut self.sender, &response).await
     }
 
+    /// Sends a [Response] followed by some raw content.
+    ///
+    /// The [Response] is prefixed by its length in 4 bytes of big endian.
+    pub async fn send_response_with_content(
+        mut self,
+        response: Response,
+        content: &[u8],
+    ) -> Result<(), WriteError> {
+        log::debug!("Sending response: {:?}", &response);
+        let mut raw = Vec::<u8>::new();
+        response
+            .encode(&mut raw)
+            .expect("Failed to encode a response");
+        self.sender
+            .write_all(&(raw.len() as u32).to_be_bytes())
+            .await?;
+        self.sender.write_all(&raw).await?;
+        send_raw(&mut self.sender, content).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn consumer_task(
         account_id: Hash,
         window_stream: impl Stream<Item = Self>,
         database: Arc<Database>,
+        changelog_merger: Arc<ChangelogMerger>,
+        device_sync: Arc<DeviceSync>,
         event_sink_database: Sender<Arc<DatabaseEvent>>,
         event_sink_daemon: Sender<Arc<DaemonEvent>>,
+        outbox: Arc<Outbox>,
     ) -> impl Future<Output = ()> {
         window_stream.for_each_concurrent(None, move |window| {
+            // Objects are streamed instead of being sent in a single packet
+            if let Some(Payload::FetchObject(request)) = &window.request.payload {
+                let request = request.clone();
+                let database = database.clone();
+                return async move {
+                    crate::transfer::serve(window, &database, account_id, request).await
+                }
+                .boxed();
+            }
+
             let handler: Box<dyn Handler + Send + Sync> = if window.account_id() == Some(account_id)
             {
-                Box::new(DeviceHandler)
+                Box::new(DeviceHandler {
+                    changelog_merger: changelog_merger.clone(),
+                    database: database.clone(),
+                    device_sync: device_sync.clone(),
+                    event_sink_database: event_sink_database.clone(),
+                })
             } else {
                 Box::new(PeerHandler {
+                    account_id,
+                    changelog_merger: changelog_merger.clone(),
                     database: database.clone(),
+                    device_sync: device_sync.clone(),
                     event_sink_database: event_sink_database.clone(),
                     event_sink_daemon: event_sink_daemon.clone(),
+                    outbox: outbox.clone(),
                 })
             };
             async move {
@@ -101,6 +154,7 @@ impl ResponseWindow {
                     .await
                     .unwrap_or_else(|err| log::error!("Error sending a response: {:?}", err));
             }
+            .boxed()
         })
     }
 }
@@ -0,0 +1,343 @@
+//! Presence and typing indicators.
+//!
+//! They are exchanged as [Signal]s over unidirectional QUIC streams without any response. A
+//! [Signal] is never persisted nor retried, and it expires unless repeated.
+
+use crate::daemon::PeerPresence;
+use crate::daemon::PresenceSubscription;
+use crate::database::chatroom::ChatroomService;
+use crate::database::Database;
+use crate::endpoint::ConnectionInfo;
+use crate::endpoint::ConnectionManager;
+use crate::proto::signal::Content;
+use crate::proto::Presence;
+use crate::proto::Signal;
+use crate::proto::Typing;
+use crate::Connection;
+use futures_core::Stream;
+use futures_util::future::Either;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::broadcast::Sender;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Large enough for a [Signal].
+pub(crate) const MAX_SIGNAL_SIZE_BYTES: usize = 1024;
+
+/// Interval between [Presence]s sent to every connected peer.
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a [Presence] lasts, tolerating a few lost ones.
+const PRESENCE_TTL: Duration = Duration::from_secs(90);
+
+/// How long a [Typing] lasts unless repeated.
+const TYPING_TTL: Duration = Duration::from_secs(10);
+
+/// What the [Signal]s from a peer tell.
+#[derive(Default)]
+struct PeerState {
+    away: bool,
+    presence_expiry: Option<Instant>,
+
+    /// When the [Typing] in each chatroom expires.
+    typing: HashMap<Vec<u8>, Instant>,
+}
+
+/// Tracks the presence and typing of peers, and announces those of the local account.
+pub(crate) struct PresenceTracker {
+    account_id: Vec<u8>,
+    connection_manager: Weak<ConnectionManager>,
+    database: Arc<Database>,
+    peers: Mutex<HashMap<Vec<u8>, PeerState>>,
+    away: AtomicBool,
+    event_sink: Sender<()>,
+
+    /// Wakes the expiry timer whenever a [Signal] arrives.
+    expiry_changed: Notify,
+}
+
+impl PresenceTracker {
+    /// Constructor.
+    ///
+    /// Nothing is sent once `connection_manager` is dropped.
+    pub fn new(
+        account_id: Vec<u8>,
+        connection_manager: Weak<ConnectionManager>,
+        database: Arc<Database>,
+    ) -> Self {
+        let (event_sink, _) = tokio::sync::broadcast::channel(8);
+        Self {
+            account_id,
+            connection_manager,
+            database,
+            peers: Default::default(),
+            away: Default::default(),
+            event_sink,
+            expiry_changed: Notify::new(),
+        }
+    }
+
+    /// Records every [Signal] from `signal_stream`, notifies when one expires, and periodically
+    /// announces the [Presence] of the local account to all connected peers.
+    ///
+    /// Runs until `signal_stream` ends and the [ConnectionManager] is dropped.
+    pub fn run(
+        self: Arc<Self>,
+        signal_stream: impl Stream<Item = (Vec<u8>, Signal)>,
+    ) -> impl Future<Output = ()> {
+        let tracker = self.clone();
+        let receive_task = signal_stream.for_each(move |(account_id, signal)| {
+            tracker.receive(account_id, signal);
+            futures_util::future::ready(())
+        });
+        let tracker = self.clone();
+        let expire_task = async move {
+            loop {
+                let notified = tracker.expiry_changed.notified();
+                match tracker.next_expiry() {
+                    Some(expiry) => {
+                        let sleep = tokio::time::sleep_until(expiry);
+                        futures_util::pin_mut!(sleep, notified);
+                        if let Either::Left(_) = futures_util::future::select(sleep, notified).await
+                        {
+                            let _ = tracker.event_sink.send(());
+                        }
+                    }
+                    None => notified.await,
+                }
+            }
+        };
+        let announce_task = async move {
+            let mut interval = tokio::time::interval(PRESENCE_INTERVAL);
+            while self.connection_manager.strong_count() > 0 {
+                interval.tick().await;
+                self.announce().await;
+            }
+        };
+        async move {
+            // The expiry timer stops along with the signals
+            let receive_task = async move {
+                futures_util::pin_mut!(receive_task, expire_task);
+                futures_util::future::select(receive_task, expire_task).await;
+            };
+            futures_util::join!(receive_task, announce_task);
+        }
+    }
+
+    /// Subscribes to the changes of [PresenceTracker::snapshot], including expiry.
+    pub fn subscribe(&self) -> Receiver<()> {
+        self.event_sink.subscribe()
+    }
+
+    /// Lists the peers whose [Presence] or [Typing] has not expired.
+    pub fn snapshot(&self) -> PresenceSubscription {
+        self.snapshot_at(Instant::now())
+    }
+
+    fn snapshot_at(&self, now: Instant) -> PresenceSubscription {
+        let mut peers = self.peers.lock().unwrap();
+        prune(&mut peers, now);
+
+        let mut peers: Vec<_> = peers
+            .iter()
+            .map(|(account_id, state)| {
+                let mut typing: Vec<_> = state.typing.keys().cloned().collect();
+                typing.sort();
+                PeerPresence {
+                    account_id: account_id.clone(),
+                    away: state.away,
+                    typing,
+                }
+            })
+            .collect();
+        peers.sort_by(|a, b| a.account_id.cmp(&b.account_id));
+        PresenceSubscription { peers }
+    }
+
+    /// Finds when the next [Presence] or [Typing] expires.
+    fn next_expiry(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        prune(&mut peers, now);
+        peers
+            .values()
+            .flat_map(|state| state.presence_expiry.iter().chain(state.typing.values()))
+            .filter(|expiry| **expiry > now)
+            .min()
+            .copied()
+    }
+
+    /// Records a [Signal] from a peer.
+    pub fn receive(&self, account_id: Vec<u8>, signal: Signal) {
+        if account_id == self.account_id {
+            return;
+        }
+        let now = Instant::now();
+        match signal.content {
+            Some(Content::Presence(presence)) => {
+                let mut peers = self.peers.lock().unwrap();
+                let state = peers.entry(account_id).or_default();
+                state.away = presence.away;
+                state.presence_expiry = Some(now + PRESENCE_TTL);
+            }
+            Some(Content::Typing(typing)) => {
+                if !self.is_member(&account_id, &typing.chatroom_id) {
+                    log::warn!(
+                        "Ignoring typing in a chatroom from a non-member {}",
+                        hex::encode_upper(&account_id)
+                    );
+                    return;
+                }
+                let mut peers = self.peers.lock().unwrap();
+                let state = peers.entry(account_id).or_default();
+                if typing.typing {
+                    state.typing.insert(typing.chatroom_id, now + TYPING_TTL);
+                } else {
+                    state.typing.remove(&typing.chatroom_id);
+                }
+            }
+            None => return,
+        }
+        let _ = self.event_sink.send(());
+        self.expiry_changed.notify_one();
+    }
+
+    fn is_member(&self, account_id: &[u8], chatroom_id: &[u8]) -> bool {
+        ChatroomService::find_members(&self.database.connection.lock().unwrap(), chatroom_id)
+            .map(|members| members.iter().any(|member| member == account_id))
+            .unwrap_or_else(|err| {
+                log::error!("Failed to query chatroom members: {:?}", err);
+                false
+            })
+    }
+
+    pub fn set_away(&self, away: bool) {
+        self.away.store(away, Ordering::SeqCst);
+    }
+
+    fn presence(&self) -> Signal {
+        Signal {
+            content: Content::Presence(Presence {
+                away: self.away.load(Ordering::SeqCst),
+            })
+            .into(),
+        }
+    }
+
+    /// Sends the [Presence] of the local account to all connected peers.
+    pub async fn announce(&self) {
+        let connections = match self.connection_manager.upgrade() {
+            Some(connection_manager) => connection_manager.find_all(),
+            None => return,
+        };
+        let peers = connections.into_iter().filter(|connection| {
+            connection
+                .account_id()
+                .map_or(false, |id| id.as_bytes() != self.account_id.as_slice())
+        });
+        send(peers, &self.presence()).await;
+    }
+
+    /// Sends the [Presence] of the local account to a newly connected peer.
+    pub async fn greet(&self, connection: Arc<Connection>) {
+        send(std::iter::once(connection), &self.presence()).await;
+    }
+
+    /// Tells the other members of a chatroom whether the local account is typing.
+    pub async fn send_typing(&self, chatroom_id: &[u8], typing: bool, members: &[Vec<u8>]) {
+        let connection_manager = match self.connection_manager.upgrade() {
+            Some(connection_manager) => connection_manager,
+            None => return,
+        };
+        let connections = members
+            .iter()
+            .filter(|member| **member != self.account_id)
+            .filter_map(|member| connection_manager.find_by_account_id(member));
+        let signal = Signal {
+            content: Content::Typing(Typing {
+                chatroom_id: chatroom_id.into(),
+                typing,
+            })
+            .into(),
+        };
+        send(connections, &signal).await;
+    }
+}
+
+/// Forgets the [Presence]s and [Typing]s expired by `now`.
+fn prune(peers: &mut HashMap<Vec<u8>, PeerState>, now: Instant) {
+    peers.retain(|_, state| {
+        state.typing.retain(|_, expiry| *expiry > now);
+        !state.typing.is_empty() || state.presence_expiry.map_or(false, |expiry| expiry > now)
+    });
+}
+
+/// Sends a [Signal] to some [Connection]s, ignoring any failure.
+async fn send(connections: impl Iterator<Item = Arc<Connection>>, signal: &Signal) {
+    let tasks = connections.map(|connection| async move {
+        if let Err(err) = connection.signal(signal).await {
+            log::debug!("Failed to send a signal to {:?}: {:?}", &connection, err);
+        }
+    });
+    futures_util::future::join_all(tasks).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::changelog::Chatroom;
+    use crate::database::Storage;
+
+    #[test]
+    fn expire() -> anyhow::Result<()> {
+        let database = Arc::new(Database::create(&Storage::InMemory)?);
+        let chatroom = Chatroom {
+            name: "Chatroom".into(),
+            members: vec![vec![1], vec![2]],
+            chatroom_id: vec![],
+        };
+        let chatroom_id = crate::database::bytes_from_hash(chatroom.chatroom_id());
+        ChatroomService::save(&database.connection.lock().unwrap(), &chatroom, &[])?;
+        let tracker = PresenceTracker::new(vec![1], Weak::new(), database);
+        let typing = Signal {
+            content: Content::Typing(Typing {
+                chatroom_id: chatroom_id.clone(),
+                typing: true,
+            })
+            .into(),
+        };
+        let presence = Signal {
+            content: Content::Presence(Presence { away: true }).into(),
+        };
+
+        tracker.receive(vec![2], presence);
+        tracker.receive(vec![2], typing.clone());
+
+        // Neither the local account nor a non-member counts
+        tracker.receive(vec![1], typing.clone());
+        tracker.receive(vec![3], typing);
+
+        let now = Instant::now();
+        let peers = tracker.snapshot_at(now).peers;
+        assert_eq!(1, peers.len());
+        assert_eq!(vec![2], peers[0].account_id);
+        assert!(peers[0].away);
+        assert_eq!(vec![chatroom_id], peers[0].typing);
+
+        let peers = tracker.snapshot_at(now + TYPING_TTL).peers;
+        assert_eq!(1, peers.len());
+        assert!(peers[0].typing.is_empty());
+
+        assert!(tracker.snapshot_at(now + PRESENCE_TTL).peers.is_empty());
+        Ok(())
+    }
+}
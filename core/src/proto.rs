@@ -4,8 +4,21 @@ tonic::include_proto!("viska.proto");
 
 use http::StatusCode;
 use prost::DecodeError;
+use std::convert::TryFrom;
 
 impl Response {
+    /// Gets the status code.
+    ///
+    /// An absent status is seen as [StatusCode::OK].
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self.status {
+            0 => Some(StatusCode::OK),
+            status => u16::try_from(status)
+                .ok()
+                .and_then(|code| StatusCode::from_u16(code).ok()),
+        }
+    }
+
     /// Creates a response with HTTP status code 403.
     pub fn forbidden() -> Self {
         Self {
@@ -14,10 +27,37 @@ impl Response {
         }
     }
 
+    /// Creates a response with HTTP status code 403 and a reason.
+    pub fn forbidden_with_reason(reason: String) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN.as_u16().into(),
+            reason,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a response with HTTP status code 404.
+    pub fn not_found() -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND.as_u16().into(),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a response with HTTP status code 503 and a reason, asking to retry later.
+    pub fn unavailable_with_reason(reason: String) -> Self {
+        Self {
+            status: StatusCode::SERVICE_UNAVAILABLE.as_u16().into(),
+            reason,
+            ..Default::default()
+        }
+    }
+
     pub fn bad_request(reason: String) -> Self {
         Self {
             status: StatusCode::BAD_REQUEST.as_u16().into(),
             reason,
+            ..Default::default()
         }
     }
 
@@ -25,6 +65,7 @@ impl Response {
         Self {
             status: StatusCode::BAD_REQUEST.as_u16().into(),
             reason: format!("{}", src),
+            ..Default::default()
         }
     }
 }
@@ -34,6 +75,7 @@ impl From<crate::handler::Error> for Response {
         Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16().into(),
             reason: format!("{}", src),
+            ..Default::default()
         }
     }
 }
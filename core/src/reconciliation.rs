@@ -0,0 +1,508 @@
+//! Range-based set reconciliation of messages between [Node](crate::Node)s.
+//!
+//! Both [Node]s sort the message IDs of a chatroom and compare the fingerprints of ranges of them.
+//! Ranges with different fingerprints are recursively split until they are small enough to list
+//! all of their message IDs, so that only the differences and a few fingerprints are transferred.
+//! The messages missing locally are then fetched in batches.
+//!
+//! Only the messages sent by the peer itself are reconciled, as a peer cannot prove a message was
+//! sent by someone else. Those are recovered by reconciling with their senders instead.
+//!
+//! [Node]: crate::Node
+
+use crate::changelog::changelog_payload::Content;
+use crate::changelog::ChangelogMerger;
+use crate::changelog::ChangelogPayload;
+use crate::changelog::Message;
+use crate::database::chatroom::ChatroomService;
+use crate::database::message::MessageService;
+use crate::database::Database;
+use crate::database::Event as DatabaseEvent;
+use crate::endpoint::ConnectionInfo;
+use crate::pki::CanonicalId;
+use crate::proto::reconciliation_range::Content as RangeContent;
+use crate::proto::request::Payload;
+use crate::proto::response::Payload as ResponsePayload;
+use crate::proto::MessageIds;
+use crate::proto::Messages;
+use crate::proto::MessagesRequest;
+use crate::proto::Reconciliation;
+use crate::proto::ReconciliationRange;
+use crate::proto::Request;
+use crate::proto::Response;
+use crate::sync::DeviceSync;
+use crate::Connection;
+use crate::RequestError;
+use blake3::Hash;
+use blake3::Hasher;
+use diesel::prelude::*;
+use prost::Message as _;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::broadcast::Sender;
+
+/// Ranges with at most this many message IDs are listed instead of split.
+const MAX_LISTED_IDS: usize = 16;
+
+/// Number of ranges a range is split into.
+const SPLIT_FACTOR: usize = 16;
+
+/// Guards against a peer never agreeing, as 16 rounds are enough for billions of messages.
+const MAX_ROUNDS: usize = 16;
+
+/// Number of messages asked for in a [MessagesRequest].
+const FETCH_BATCH_SIZE: usize = 64;
+
+/// Leaves enough room in a packet for the encoding overhead of a [Response].
+const MAX_BATCH_SIZE_BYTES: usize = crate::packet::MAX_PACKET_SIZE_BYTES / 2;
+
+#[derive(Error, Debug)]
+#[error("Failed to reconcile messages")]
+pub(crate) enum Error {
+    Database(#[from] diesel::result::Error),
+    Request(#[from] RequestError),
+    Rejected(Response),
+    ObjectStore(#[from] std::io::Error),
+}
+
+/// Recovers the messages missing locally from peers.
+pub(crate) struct Reconciler {
+    pub account_id: Hash,
+    pub changelog_merger: Arc<ChangelogMerger>,
+    pub database: Arc<Database>,
+    pub device_sync: Arc<DeviceSync>,
+    pub event_sink_database: Sender<Arc<DatabaseEvent>>,
+}
+
+impl Reconciler {
+    /// Reconciles all chatrooms shared with a peer.
+    pub async fn reconcile_all(&self, peer: &Connection) {
+        let account_id = match peer.account_id() {
+            Some(account_id) => account_id,
+            None => return,
+        };
+        let chatroom_ids = ChatroomService::find_ids_by_member(
+            &self.database.connection.lock().unwrap(),
+            account_id.as_bytes(),
+        );
+        match chatroom_ids {
+            Ok(chatroom_ids) => {
+                for chatroom_id in chatroom_ids {
+                    match self.reconcile(peer, &chatroom_id).await {
+                        Ok(0) => {}
+                        Ok(count) => log::info!(
+                            "Recovered {} messages in chatroom {} from {:?}",
+                            count,
+                            hex::encode_upper(&chatroom_id),
+                            peer
+                        ),
+                        Err(err) => {
+                            log::error!("Failed to reconcile with {:?}: {:?}", peer, err)
+                        }
+                    }
+                }
+            }
+            Err(err) => log::error!("Failed to query the chatrooms: {:?}", err),
+        }
+    }
+
+    /// Fetches the messages of a chatroom missing locally from a peer.
+    ///
+    /// Returns the number of messages recovered.
+    pub async fn reconcile(&self, peer: &Connection, chatroom_id: &[u8]) -> Result<usize, Error> {
+        let sender = match peer.account_id() {
+            Some(account_id) => crate::database::bytes_from_hash(account_id),
+            None => return Ok(0),
+        };
+        let mut ranges = start(
+            &self.database.connection.lock().unwrap(),
+            chatroom_id,
+            &sender,
+        )?;
+        let mut missing = vec![];
+        for _ in 0..MAX_ROUNDS {
+            if ranges.is_empty() {
+                break;
+            }
+            let request = Payload::Reconciliation(Reconciliation {
+                chatroom_id: chatroom_id.into(),
+                ranges,
+                sender: sender.clone(),
+            });
+            let ranges_remote = match request_payload(peer, request).await? {
+                Some(ResponsePayload::Reconciliation(reconciliation)) => reconciliation.ranges,
+                _ => vec![],
+            };
+            let (next, missing_in_round) = compare(
+                &self.database.connection.lock().unwrap(),
+                chatroom_id,
+                &sender,
+                ranges_remote,
+            )?;
+            ranges = next;
+            missing.extend(missing_in_round);
+        }
+
+        let mut recovered = 0;
+        while !missing.is_empty() {
+            let batch: Vec<_> = missing
+                .drain(..FETCH_BATCH_SIZE.min(missing.len()))
+                .collect();
+            let request = Payload::MessagesRequest(MessagesRequest {
+                message_ids: batch.clone(),
+            });
+            let messages = match request_payload(peer, request).await? {
+                Some(ResponsePayload::Messages(messages)) => messages.messages,
+                _ => vec![],
+            };
+
+            // Whatever not fitting in the response is asked again
+            let mut requested: BTreeSet<_> = batch.into_iter().collect();
+            let requested_count = requested.len();
+            let messages = accept(
+                &self.database.connection.lock().unwrap(),
+                self.account_id,
+                peer.account_id(),
+                chatroom_id,
+                &mut requested,
+                messages,
+            )?;
+            if requested.len() == requested_count {
+                break;
+            }
+            missing.extend(requested);
+            if messages.is_empty() {
+                continue;
+            }
+            recovered += messages.len();
+            self.commit(messages).await?;
+        }
+        Ok(recovered)
+    }
+
+    async fn commit(&self, messages: Vec<Message>) -> Result<(), Error> {
+        let payloads: Vec<_> = messages
+            .into_iter()
+            .map(|message| ChangelogPayload {
+                content: Content::AddMessage(message).into(),
+            })
+            .collect();
+        let changelog_merger = self.changelog_merger.clone();
+        let payloads = crate::util::run_blocking(move || {
+            for payload in payloads.iter() {
+                changelog_merger.write_objects(payload)?;
+            }
+            Ok::<_, std::io::Error>(payloads)
+        })
+        .await?;
+        let (entries, events) = {
+            let connection = self.database.connection.lock().unwrap();
+            connection.transaction::<_, diesel::result::Error, _>(|| {
+                self.changelog_merger
+                    .commit(&connection, payloads.into_iter())
+            })?
+        };
+        for event in events {
+            let _ = self.event_sink_database.send(event.into());
+        }
+        self.device_sync.push(entries);
+        Ok(())
+    }
+}
+
+/// Filters the [Message]s fetched from a peer down to the requested ones it genuinely sent to the
+/// local account.
+fn accept(
+    connection: &SqliteConnection,
+    local_account_id: Hash,
+    remote_account_id: Option<Hash>,
+    chatroom_id: &[u8],
+    requested: &mut BTreeSet<Vec<u8>>,
+    messages: Vec<Message>,
+) -> QueryResult<Vec<Message>> {
+    let mut accepted = vec![];
+    for message in messages {
+        if message.chatroom_id().as_bytes() != chatroom_id
+            || !requested.remove(&message.canonical_id().as_bytes()[..])
+        {
+            continue;
+        }
+        match crate::handler::verify_message(
+            connection,
+            local_account_id,
+            remote_account_id,
+            &message,
+        )? {
+            Ok(()) => accepted.push(message),
+            Err(reason) => log::warn!("Rejecting a fetched message: {}", reason),
+        }
+    }
+    Ok(accepted)
+}
+
+/// Answers a round of [Reconciliation] with the ranges where both sides still disagree.
+pub(crate) fn respond(
+    connection: &SqliteConnection,
+    chatroom_id: &[u8],
+    sender: &[u8],
+    ranges: Vec<ReconciliationRange>,
+) -> QueryResult<Vec<ReconciliationRange>> {
+    let mut ranges_disagreed = vec![];
+    for range in ranges {
+        let ids = MessageService::find_ids_in_range(
+            connection,
+            chatroom_id,
+            sender,
+            &range.lower_bound,
+            &range.upper_bound,
+        )?;
+        if let Some(RangeContent::Fingerprint(fingerprint)) = &range.content {
+            if fingerprint.as_slice() == self::fingerprint(&ids).as_bytes() {
+                continue;
+            }
+        }
+        if ids.len() <= MAX_LISTED_IDS {
+            ranges_disagreed.push(ReconciliationRange {
+                content: RangeContent::MessageIds(MessageIds { ids }).into(),
+                ..range
+            });
+        } else {
+            ranges_disagreed.extend(split(range.lower_bound, range.upper_bound, &ids));
+        }
+    }
+    Ok(ranges_disagreed)
+}
+
+/// Starts a [Reconciliation] with a single range covering all messages of a chatroom sent by an
+/// account.
+fn start(
+    connection: &SqliteConnection,
+    chatroom_id: &[u8],
+    sender: &[u8],
+) -> QueryResult<Vec<ReconciliationRange>> {
+    let ids = MessageService::find_ids_in_range(connection, chatroom_id, sender, &[], &[])?;
+    Ok(vec![ReconciliationRange {
+        lower_bound: vec![],
+        upper_bound: vec![],
+        content: RangeContent::Fingerprint(fingerprint(&ids).as_bytes().to_vec()).into(),
+    }])
+}
+
+/// Compares the ranges answered by the remote side with the local ones.
+///
+/// Returns the ranges for the next round and the IDs of the messages missing locally.
+fn compare(
+    connection: &SqliteConnection,
+    chatroom_id: &[u8],
+    sender: &[u8],
+    ranges_remote: Vec<ReconciliationRange>,
+) -> QueryResult<(Vec<ReconciliationRange>, Vec<Vec<u8>>)> {
+    let mut next = vec![];
+    let mut missing = vec![];
+    for range in ranges_remote {
+        match range.content {
+            Some(RangeContent::MessageIds(message_ids)) => {
+                missing.extend(MessageService::find_absent(connection, message_ids.ids)?);
+            }
+            Some(RangeContent::Fingerprint(fingerprint_remote)) => {
+                let ids = MessageService::find_ids_in_range(
+                    connection,
+                    chatroom_id,
+                    sender,
+                    &range.lower_bound,
+                    &range.upper_bound,
+                )?;
+                let fingerprint_local = fingerprint(&ids).as_bytes().to_vec();
+                if fingerprint_local != fingerprint_remote {
+                    next.push(ReconciliationRange {
+                        content: RangeContent::Fingerprint(fingerprint_local).into(),
+                        ..range
+                    });
+                }
+            }
+            None => log::warn!("Skipping an empty reconciliation range"),
+        }
+    }
+    Ok((next, missing))
+}
+
+/// Splits a range into [SPLIT_FACTOR] ranges of roughly the same number of message IDs.
+fn split(
+    lower_bound: Vec<u8>,
+    upper_bound: Vec<u8>,
+    ids: &[Vec<u8>],
+) -> impl Iterator<Item = ReconciliationRange> + '_ {
+    let chunk_size = (ids.len() + SPLIT_FACTOR - 1) / SPLIT_FACTOR;
+    let chunks: Vec<_> = ids.chunks(chunk_size).collect();
+    let lower_bounds = std::iter::once(lower_bound).chain(chunks[1..].iter().map(|c| c[0].clone()));
+    let upper_bounds = chunks[1..]
+        .iter()
+        .map(|c| c[0].clone())
+        .chain(std::iter::once(upper_bound));
+    lower_bounds.zip(upper_bounds).zip(chunks.into_iter()).map(
+        |((lower_bound, upper_bound), chunk)| ReconciliationRange {
+            lower_bound,
+            upper_bound,
+            content: RangeContent::Fingerprint(fingerprint(chunk).as_bytes().to_vec()).into(),
+        },
+    )
+}
+
+/// Hashes sorted message IDs.
+fn fingerprint(ids: &[Vec<u8>]) -> Hash {
+    let mut hasher = Hasher::default();
+    hasher.update(b"Viska reconciliation");
+    for id in ids {
+        hasher.update(&id.len().to_be_bytes());
+        hasher.update(id);
+    }
+    hasher.finalize()
+}
+
+/// Finds the [Message]s requested by a peer that it is allowed to see, as many as fitting in a
+/// packet.
+pub(crate) fn find_messages(
+    connection: &SqliteConnection,
+    account_id: &[u8],
+    message_ids: &[Vec<u8>],
+) -> QueryResult<Messages> {
+    let mut messages = Messages::default();
+    for message in MessageService::find_by_ids(connection, message_ids)? {
+        let visible = message.sender == account_id
+            || message
+                .recipients
+                .iter()
+                .any(|recipient| recipient == account_id);
+        if !visible {
+            continue;
+        }
+        if !messages.messages.is_empty()
+            && messages.encoded_len() + message.encoded_len() > MAX_BATCH_SIZE_BYTES
+        {
+            break;
+        }
+        messages.messages.push(message);
+    }
+    Ok(messages)
+}
+
+async fn request_payload(
+    peer: &Connection,
+    payload: Payload,
+) -> Result<Option<ResponsePayload>, Error> {
+    let request = Request {
+        payload: payload.into(),
+    };
+    let response = peer.request(&request).await?;
+    if response
+        .status_code()
+        .map_or(false, |code| code.is_success())
+    {
+        Ok(response.payload)
+    } else {
+        Err(Error::Rejected(response))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Storage;
+
+    #[test]
+    fn find_missing() -> anyhow::Result<()> {
+        let local_database = Database::create(&Storage::InMemory)?;
+        let remote_database = Database::create(&Storage::InMemory)?;
+        let local = local_database.connection.lock().unwrap();
+        let remote = remote_database.connection.lock().unwrap();
+        let message = |n: i32| Message {
+            time: n.into(),
+            sender: vec![1],
+            recipients: vec![vec![2]],
+            content: n.to_string(),
+            attachment: None,
+            attachment_reference: None,
+            in_reply_to: vec![],
+            chatroom_id: vec![],
+        };
+        let chatroom_id = crate::database::bytes_from_hash(message(0).chatroom_id());
+
+        // Both have most messages, but each has a few the other one lacks
+        let mut missing_expected = BTreeSet::new();
+        for n in 0..1000 {
+            if n % 97 != 0 {
+                MessageService::update(&local, &local_database.objects, &message(n))?;
+            }
+            if n % 89 != 0 {
+                MessageService::update(&remote, &remote_database.objects, &message(n))?;
+                if n % 97 == 0 {
+                    missing_expected.insert(message(n).canonical_id().as_bytes().to_vec());
+                }
+            }
+        }
+
+        let mut ranges = start(&local, &chatroom_id, &[1])?;
+        let mut missing = BTreeSet::new();
+        let mut rounds = 0;
+        while !ranges.is_empty() {
+            let ranges_remote = respond(&remote, &chatroom_id, &[1], ranges)?;
+            let (next, missing_in_round) = compare(&local, &chatroom_id, &[1], ranges_remote)?;
+            ranges = next;
+            missing.extend(missing_in_round);
+            rounds += 1;
+        }
+        assert_eq!(missing_expected, missing);
+        assert!(rounds <= 3);
+
+        // Nothing to do once synchronized
+        let ranges = start(&remote, &chatroom_id, &[1])?;
+        assert!(respond(&remote, &chatroom_id, &[1], ranges)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_third_party_sender() -> anyhow::Result<()> {
+        let database = Database::create(&Storage::InMemory)?;
+        let connection = database.connection.lock().unwrap();
+        let local = Hash::from([1; 32]);
+        let remote = Hash::from([2; 32]);
+        let third_party = Hash::from([3; 32]);
+        let message = |sender: Hash, recipients: &[Hash]| Message {
+            time: 0.0,
+            sender: crate::database::bytes_from_hash(sender),
+            recipients: recipients
+                .iter()
+                .copied()
+                .map(crate::database::bytes_from_hash)
+                .collect(),
+            content: "Hello".into(),
+            attachment: None,
+            attachment_reference: None,
+            in_reply_to: vec![],
+            chatroom_id: vec![],
+        };
+        let genuine = message(remote, &[local, third_party]);
+        let forged = message(third_party, &[local, remote]);
+        let chatroom_id = crate::database::bytes_from_hash(genuine.chatroom_id());
+        assert_eq!(chatroom_id, forged.chatroom_id().as_bytes());
+
+        let mut requested: BTreeSet<_> = [&genuine, &forged]
+            .iter()
+            .map(|message| message.canonical_id().as_bytes().to_vec())
+            .collect();
+        let accepted = accept(
+            &connection,
+            local,
+            Some(remote),
+            &chatroom_id,
+            &mut requested,
+            vec![genuine.clone(), forged],
+        )?;
+        assert_eq!(vec![genuine], accepted);
+        assert!(requested.is_empty());
+
+        Ok(())
+    }
+}
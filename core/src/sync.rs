@@ -0,0 +1,192 @@
+//! Synchronization between devices of the same account.
+//!
+//! Devices exchange [ChangelogEntry]s over their [Connection]s. Once connected, each device asks
+//! the other one for the entries it does not have yet, and every later change is pushed to all
+//! devices connected at the time.
+
+use crate::changelog::ChangelogEntry;
+use crate::changelog::SequenceNumber;
+use crate::database::changelog::ChangelogService;
+use crate::database::Database;
+use crate::endpoint::ConnectionManager;
+use crate::proto::request::Payload;
+use crate::proto::Changelog;
+use crate::proto::ChangelogRequest;
+use crate::proto::Request;
+use crate::Connection;
+use blake3::Hash;
+use prost::Message as _;
+use std::sync::Arc;
+
+/// Leaves enough room in a packet for the encoding overhead of a [Request].
+const MAX_BATCH_SIZE_BYTES: usize = crate::packet::MAX_PACKET_SIZE_BYTES / 2;
+
+pub(crate) struct DeviceSync {
+    account_id: Hash,
+    connection_manager: Arc<ConnectionManager>,
+    database: Arc<Database>,
+}
+
+impl DeviceSync {
+    pub fn new(
+        account_id: Hash,
+        connection_manager: Arc<ConnectionManager>,
+        database: Arc<Database>,
+    ) -> Self {
+        Self {
+            account_id,
+            connection_manager,
+            database,
+        }
+    }
+
+    /// Pushes [ChangelogEntry]s committed locally to all connected devices.
+    pub fn push(&self, entries: Vec<ChangelogEntry>) {
+        let devices = self
+            .connection_manager
+            .find_all_by_account_id(self.account_id.as_bytes());
+        if devices.is_empty() || entries.is_empty() {
+            return;
+        }
+        let requests = Arc::new(batch(entries));
+        for device in devices {
+            let requests = requests.clone();
+            crate::util::spawn(async move { send(&device, &requests).await });
+        }
+    }
+
+    /// Asks a newly connected device for the [ChangelogEntry]s missing locally.
+    pub async fn pull(&self, device: Arc<Connection>) {
+        let since = {
+            let connection = self.database.connection.lock().unwrap();
+            ChangelogService::latest(&connection)
+        };
+        match since {
+            Ok(since) => {
+                let request = Request {
+                    payload: Payload::ChangelogRequest(ChangelogRequest { since }).into(),
+                };
+                send(&device, std::slice::from_ref(&request)).await
+            }
+            Err(err) => log::error!("Failed to query the changelog: {:?}", err),
+        }
+    }
+
+    /// Pushes the [ChangelogEntry]s newer than `since` to a device.
+    pub async fn push_since(&self, device: Arc<Connection>, since: &[SequenceNumber]) {
+        let entries = {
+            let connection = self.database.connection.lock().unwrap();
+            ChangelogService::find_since(&connection, since)
+        };
+        match entries {
+            Ok(entries) => send(&device, &batch(entries)).await,
+            Err(err) => log::error!("Failed to query the changelog: {:?}", err),
+        }
+    }
+}
+
+/// Checks if some content is small enough to be committed, as its [ChangelogEntry] is never split
+/// across packets.
+pub(crate) fn verify_size(content: &impl prost::Message) -> Result<(), String> {
+    if content.encoded_len() > MAX_BATCH_SIZE_BYTES {
+        Err(format!("Larger than {} bytes", MAX_BATCH_SIZE_BYTES))
+    } else {
+        Ok(())
+    }
+}
+
+/// Splits [ChangelogEntry]s into [Request]s fitting in a packet.
+fn batch(entries: impl IntoIterator<Item = ChangelogEntry>) -> Vec<Request> {
+    let mut requests = vec![];
+    let mut changelog = Changelog::default();
+    for entry in entries {
+        if !changelog.entries.is_empty()
+            && changelog.encoded_len() + entry.encoded_len() > MAX_BATCH_SIZE_BYTES
+        {
+            requests.push(Request {
+                payload: Payload::Changelog(std::mem::take(&mut changelog)).into(),
+            });
+        }
+        changelog.entries.push(entry);
+    }
+    if !changelog.entries.is_empty() {
+        requests.push(Request {
+            payload: Payload::Changelog(changelog).into(),
+        });
+    }
+    requests
+}
+
+/// Sends [Request]s in order until one of them fails.
+async fn send(device: &Connection, requests: &[Request]) {
+    for request in requests {
+        match device.request(request).await {
+            Ok(response)
+                if response
+                    .status_code()
+                    .map_or(false, |code| code.is_success()) => {}
+            Ok(response) => {
+                log::error!("Device rejected changelog: {:?}", response);
+                return;
+            }
+            Err(err) => {
+                log::error!("Failed to push changelog to {:?}: {:?}", device, err);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::daemon::node_client::NodeClient;
+    use crate::daemon::CreateChatroomRequest;
+    use crate::daemon::RenameChatroomRequest;
+    use futures_util::StreamExt;
+    use tonic::transport::Channel;
+
+    #[tokio::test]
+    async fn sync_chatroom() -> anyhow::Result<()> {
+        let ((device_1, _), (device_2, _)) = crate::util::start_dummy_devices().await?;
+        let mut client_1 =
+            NodeClient::<Channel>::connect(format!("http://[::1]:{}", device_1.grpc_port()))
+                .await?;
+        let mut client_2 =
+            NodeClient::<Channel>::connect(format!("http://[::1]:{}", device_2.grpc_port()))
+                .await?;
+
+        // Created before the devices connect
+        let chatroom_id = client_1
+            .create_chatroom(CreateChatroomRequest {
+                name: "Before".into(),
+                members: vec![vec![0; 32]],
+            })
+            .await?
+            .into_inner();
+        let mut stream = client_2
+            .watch_chatroom(chatroom_id.clone())
+            .await?
+            .into_inner();
+        let device_1_address = format!("[::1]:{}", device_1.local_port()?).parse()?;
+        device_2.connect(&device_1_address).await?;
+        while let Some(chatroom) = stream.next().await {
+            if chatroom?.name == "Before" {
+                break;
+            }
+        }
+
+        // Renamed after the devices connect
+        client_1
+            .rename_chatroom(RenameChatroomRequest {
+                chatroom_id,
+                name: "After".into(),
+            })
+            .await?;
+        while let Some(chatroom) = stream.next().await {
+            if chatroom?.name == "After" {
+                return Ok(());
+            }
+        }
+        panic!("Chatroom not synchronized")
+    }
+}
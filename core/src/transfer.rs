@@ -0,0 +1,308 @@
+//! Transfer of objects too large to be sent along with what references them.
+//!
+//! Such an object is sent as a [BlobReference] and is fetched afterwards from any peer sharing it.
+//! Its content is written to a file in chunks, so that an interrupted download resumes where it
+//! stopped.
+
+use crate::changelog::BlobReference;
+use crate::database::object::ObjectService;
+use crate::database::Database;
+use crate::database::Event as DatabaseEvent;
+use crate::endpoint::ConnectionInfo;
+use crate::endpoint::ConnectionManager;
+use crate::packet::ResponseWindow;
+use crate::proto::request::Payload;
+use crate::proto::FetchObject;
+use crate::proto::Request;
+use crate::proto::Response;
+use crate::Connection;
+use crate::RequestError;
+use blake3::Hash;
+use chrono::Utc;
+use diesel::prelude::*;
+use futures_channel::mpsc::UnboundedReceiver;
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::future::Either;
+use futures_util::StreamExt;
+use std::future::Future;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::broadcast::Sender;
+
+/// Largest attachment sent inline within a message.
+pub(crate) const MAX_INLINE_SIZE_BYTES: usize = 64 * 1024;
+
+/// Size of the chunks saved while downloading.
+const CHUNK_SIZE_BYTES: usize = 64 * 1024;
+
+/// Serves a [FetchObject] to a peer that received the object or another device of the local
+/// account.
+pub(crate) async fn serve(
+    window: ResponseWindow,
+    database: &Database,
+    account_id: Hash,
+    request: FetchObject,
+) {
+    let reference = {
+        let connection = database.connection.lock().unwrap();
+        let permitted = match window.account_id() {
+            Some(peer) if peer == account_id => Ok(true),
+            Some(peer) => {
+                ObjectService::is_shared_with(&connection, &request.object_id, peer.as_bytes())
+            }
+            None => Ok(false),
+        };
+        permitted.and_then(|permitted| {
+            if permitted {
+                ObjectService::find_by_id(&connection, &request.object_id)
+            } else {
+                Ok(None)
+            }
+        })
+    };
+
+    // Read without holding the database connection
+    let content = match reference {
+        Ok(Some(_)) => {
+            let object_id = request.object_id.clone();
+            let content = database
+                .objects
+                .run(move |objects| objects.read(&object_id))
+                .await;
+            Ok(content.unwrap_or_else(|err| {
+                log::error!("Failed to read an object: {:?}", err);
+                None
+            }))
+        }
+        Ok(None) => Ok(None),
+        Err(err) => Err(err),
+    };
+    let result = match content {
+        Ok(Some(content)) => match content.get(request.offset as usize..) {
+            Some(content) => {
+                window
+                    .send_response_with_content(Default::default(), content)
+                    .await
+            }
+            None => {
+                let reason = format!("Offset beyond {} bytes", content.len());
+                window
+                    .send_response_with_content(Response::bad_request(reason), &[])
+                    .await
+            }
+        },
+        Ok(None) => {
+            window
+                .send_response_with_content(Response::not_found(), &[])
+                .await
+        }
+        Err(err) => {
+            log::error!("Failed to query an object: {:?}", err);
+            let response = Response::from(crate::handler::Error::from(err));
+            window.send_response_with_content(response, &[]).await
+        }
+    };
+    result.unwrap_or_else(|err| log::error!("Error sending an object: {:?}", err));
+}
+
+/// Downloads the objects referenced by [BlobReference]s.
+pub(crate) struct Downloader {
+    wake_sink: UnboundedSender<()>,
+}
+
+impl Downloader {
+    /// Constructor.
+    ///
+    /// The returned [Future] downloads the pending objects whenever a message arrives or
+    /// [Downloader::wake] is called. It runs to completion once [Downloader] is dropped.
+    pub fn new(
+        connection_manager: Arc<ConnectionManager>,
+        database: Arc<Database>,
+        event_sink_database: Sender<Arc<DatabaseEvent>>,
+    ) -> (Self, impl Future<Output = ()>) {
+        let (wake_sink, wake_receiver) = futures_channel::mpsc::unbounded();
+        let worker = Worker {
+            connection_manager,
+            database,
+            event_sink_database,
+        };
+        let task = async move { crate::util::spawn(worker.run(wake_receiver)).await.unwrap() };
+        (Self { wake_sink }, task)
+    }
+
+    /// Attempts all pending downloads immediately.
+    ///
+    /// Should be called when peers become reachable.
+    pub fn wake(&self) {
+        let _ = self.wake_sink.unbounded_send(());
+    }
+}
+
+struct Worker {
+    connection_manager: Arc<ConnectionManager>,
+    database: Arc<Database>,
+    event_sink_database: Sender<Arc<DatabaseEvent>>,
+}
+
+impl Worker {
+    async fn run(self, mut wake_receiver: UnboundedReceiver<()>) {
+        let mut event_stream = self.event_sink_database.subscribe();
+        loop {
+            self.download_all().await;
+
+            let woken = loop {
+                let wake = wake_receiver.next();
+                let event = event_stream.recv();
+                futures_util::pin_mut!(event);
+                match futures_util::future::select(wake, event).await {
+                    Either::Left((signal, _)) => break signal.is_some(),
+                    Either::Right((Ok(event), _)) => {
+                        if let DatabaseEvent::Message { .. } = event.as_ref() {
+                            break true;
+                        }
+                    }
+                    Either::Right((Err(_), _)) => break true,
+                }
+            };
+            if !woken {
+                log::info!("Shutting down downloader");
+                return;
+            }
+        }
+    }
+
+    async fn download_all(&self) {
+        let pending = {
+            let connection = self.database.connection.lock().unwrap();
+            let now = crate::database::float_from_time(Utc::now());
+            ObjectService::find_pending(&connection, now).unwrap_or_else(|err| {
+                log::error!("Failed to query pending downloads: {:?}", err);
+                Default::default()
+            })
+        };
+        for reference in pending {
+            let sharers = {
+                let connection = self.database.connection.lock().unwrap();
+                ObjectService::find_sharers(&connection, &reference.object_id).unwrap_or_else(
+                    |err| {
+                        log::error!("Failed to query who shares an object: {:?}", err);
+                        Default::default()
+                    },
+                )
+            };
+            let peers = sharers
+                .iter()
+                .filter_map(|sharer| self.connection_manager.find_by_account_id(sharer));
+            for peer in peers {
+                match self.download(&peer, &reference).await {
+                    Ok(()) => break,
+                    Err(Error::Corrupted) => {
+                        log::warn!(
+                            "Object {} from {:?} is corrupted",
+                            hex::encode_upper(&reference.object_id),
+                            &peer
+                        );
+                        let connection = self.database.connection.lock().unwrap();
+                        let now = crate::database::float_from_time(Utc::now());
+                        ObjectService::postpone(&connection, &reference.object_id, now)
+                            .unwrap_or_else(|err| {
+                                log::error!("Failed to postpone a download: {:?}", err)
+                            });
+                        break;
+                    }
+                    Err(err) => log::warn!(
+                        "Failed to download object {} from {:?}: {}",
+                        hex::encode_upper(&reference.object_id),
+                        &peer,
+                        err
+                    ),
+                }
+            }
+        }
+    }
+
+    async fn download(&self, peer: &Connection, reference: &BlobReference) -> Result<(), Error> {
+        let objects = &self.database.objects;
+        let object_id = reference.object_id.clone();
+        let offset = objects
+            .run(move |objects| objects.downloaded_size(&object_id))
+            .await?;
+        let request = Request {
+            payload: Payload::FetchObject(FetchObject {
+                object_id: reference.object_id.clone(),
+                offset,
+            })
+            .into(),
+        };
+        let (response, mut receiver) = peer.request_content(&request).await?;
+        match response.status_code() {
+            Some(code) if code.is_success() => {}
+            _ => return Err(Error::Refused(response.reason)),
+        }
+
+        let mut position = offset;
+        let mut chunk = vec![0; CHUNK_SIZE_BYTES];
+        loop {
+            let mut filled = 0;
+            while filled < chunk.len() {
+                match receiver.read(&mut chunk[filled..]).await? {
+                    Some(size) => filled += size,
+                    None => break,
+                }
+            }
+            if filled == 0 {
+                break;
+            }
+            if position + filled as u64 > reference.size {
+                return Err(Error::Corrupted);
+            }
+            let object_id = reference.object_id.clone();
+            let content = chunk[..filled].to_vec();
+            objects
+                .run(move |objects| objects.write_partial(&object_id, position, &content))
+                .await?;
+            position += filled as u64;
+        }
+        if position < reference.size {
+            return Err(Error::Incomplete);
+        }
+
+        let reference_downloaded = reference.clone();
+        if !objects
+            .run(move |objects| objects.complete(&reference_downloaded))
+            .await?
+        {
+            return Err(Error::Corrupted);
+        }
+
+        let connection = self.database.connection.lock().unwrap();
+        let chatroom_ids = connection.transaction::<_, diesel::result::Error, _>(|| {
+            ObjectService::complete(&connection, reference)?;
+            ObjectService::find_chatroom_ids(&connection, &reference.object_id)
+        })?;
+        for chatroom_id in chatroom_ids {
+            let _ = self
+                .event_sink_database
+                .send(DatabaseEvent::Message { chatroom_id }.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Failed to download an object")]
+enum Error {
+    Database(#[from] diesel::result::Error),
+    ObjectStore(#[from] std::io::Error),
+    Read(#[from] quinn::ReadError),
+    Request(#[from] RequestError),
+
+    #[error("Refused: {0}")]
+    Refused(String),
+
+    #[error("Stream ended before the object is complete")]
+    Incomplete,
+
+    #[error("Content does not match the object ID")]
+    Corrupted,
+}
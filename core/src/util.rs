@@ -19,17 +19,57 @@ pub async fn start_dummy_node() -> anyhow::Result<(Node, impl Future<Output = ()
     // TODO: In-memory database
     let tmp_dir = tempfile::tempdir()?.into_path();
     let account_id = crate::database::create_standard_profile(tmp_dir.clone()).await?;
-    let profile_config = ProfileConfig { dir_data: tmp_dir };
-    let node_grpc_port = random_port();
+    start_node(&account_id, ProfileConfig { dir_data: tmp_dir }).await
+}
+
+/// Configures to start 2 [Node]s of the same account that do nothing, as if they were on
+/// different devices.
+pub async fn start_dummy_devices() -> anyhow::Result<(
+    (Node, impl Future<Output = ()>),
+    (Node, impl Future<Output = ()>),
+)> {
+    let tmp_dir_1 = tempfile::tempdir()?.into_path();
+    let account_id = crate::database::create_standard_profile(tmp_dir_1.clone()).await?;
+    let profile_config_1 = ProfileConfig {
+        dir_data: tmp_dir_1,
+    };
+
+    // Same certificate but a blank database
+    let profile_config_2 = ProfileConfig {
+        dir_data: tempfile::tempdir()?.into_path(),
+    };
+    let path_database = profile_config_2.path_database(&account_id).await?;
+    async_fs::create_dir_all(path_database.parent().unwrap()).await?;
+    async_fs::copy(
+        profile_config_1.path_certificate(&account_id).await?,
+        profile_config_2.path_certificate(&account_id).await?,
+    )
+    .await?;
+    async_fs::copy(
+        profile_config_1.path_key(&account_id).await?,
+        profile_config_2.path_key(&account_id).await?,
+    )
+    .await?;
 
-    let (node, task) = Node::new(&account_id, &profile_config, node_grpc_port).await?;
+    Ok((
+        start_node(&account_id, profile_config_1).await?,
+        start_node(&account_id, profile_config_2).await?,
+    ))
+}
+
+async fn start_node(
+    account_id: &[u8],
+    profile_config: ProfileConfig,
+) -> anyhow::Result<(Node, impl Future<Output = ()>)> {
+    let node_grpc_port = random_port();
+    let (node, task) = Node::new(account_id, &profile_config, node_grpc_port).await?;
     let handle = EXECUTOR.spawn(task);
     let task = async move { handle.await.unwrap() };
     Ok((node, task))
 }
 
 /// Generates a random port within the private range untouched by IANA.
-fn random_port() -> u16 {
+pub(crate) fn random_port() -> u16 {
     thread_rng().gen_range(49152..u16::MAX)
 }
 
@@ -68,3 +108,17 @@ where
         EXECUTOR.spawn(task)
     }
 }
+
+/// Runs blocking code such as file I/O on a thread where it does not stall other tasks.
+pub(crate) async fn run_blocking<F, T>(task: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let handle = if let Ok(runtime) = Handle::try_current() {
+        runtime.spawn_blocking(task)
+    } else {
+        EXECUTOR.spawn_blocking(task)
+    };
+    handle.await.unwrap()
+}
@@ -0,0 +1,21 @@
+use std::net::SocketAddrV6;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[tokio::test]
+async fn reuse_connection() -> anyhow::Result<()> {
+    let (dummy, _) = viska::util::start_dummy_node().await?;
+    let dummy_port = dummy.local_port()?;
+
+    let (prober, _) = viska::util::start_dummy_node().await?;
+    let addr = SocketAddrV6::from_str(&format!("[::1]:{}", dummy_port))?.into();
+    let (connection_1, connection_2) =
+        futures_util::join!(prober.connect(&addr), prober.connect(&addr));
+    let (connection_1, connection_2) = (connection_1?, connection_2?);
+    assert!(Arc::ptr_eq(&connection_1, &connection_2));
+
+    let connection_3 = prober.connect(&addr).await?;
+    assert!(Arc::ptr_eq(&connection_1, &connection_3));
+
+    Ok(())
+}
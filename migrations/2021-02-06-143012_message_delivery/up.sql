@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS message_delivery (
+  message_id           BLOB NOT NULL REFERENCES message(message_id) ON DELETE CASCADE,
+  recipient_account_id BLOB NOT NULL,
+
+  state                INTEGER NOT NULL,
+  reason               TEXT NOT NULL,
+  time_updated         DOUBLE NOT NULL,
+
+  PRIMARY KEY (message_id, recipient_account_id)
+);
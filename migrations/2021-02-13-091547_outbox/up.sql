@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS outbox (
+  id                   BLOB PRIMARY KEY NOT NULL, -- UUID
+
+  recipient_account_id BLOB NOT NULL,
+  message_id           BLOB REFERENCES message(message_id) ON DELETE CASCADE, -- Whose delivery is tracked
+  request              BLOB NOT NULL, -- Encoded `Request`
+  attempts             INTEGER NOT NULL,
+  time_next_attempt    DOUBLE NOT NULL
+);
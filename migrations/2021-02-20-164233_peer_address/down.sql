@@ -0,0 +1 @@
+DROP TABLE IF EXISTS peer_address;
\ No newline at end of file
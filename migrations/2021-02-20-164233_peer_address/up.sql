@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS peer_address (
+  account_id     BLOB NOT NULL,
+  address        TEXT NOT NULL, -- IP address and port
+
+  time_last_seen DOUBLE NOT NULL,
+
+  PRIMARY KEY (account_id, address)
+);
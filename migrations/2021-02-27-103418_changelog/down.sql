@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS changelog;
+DROP TABLE IF EXISTS local_device;
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS local_device (
+  device_id BLOB PRIMARY KEY NOT NULL -- UUID, only 1 row
+);
+
+CREATE TABLE IF NOT EXISTS changelog (
+  device_id BLOB NOT NULL, -- Where the payload was committed
+  sequence  BIGINT NOT NULL, -- Starting from 1 on each device
+  payload   BLOB NOT NULL, -- Encoded `ChangelogPayload`
+
+  PRIMARY KEY (device_id, sequence)
+);
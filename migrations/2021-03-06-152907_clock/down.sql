@@ -0,0 +1,4 @@
+ALTER TABLE vcard DROP COLUMN clock;
+ALTER TABLE peer DROP COLUMN clock;
+ALTER TABLE chatroom DROP COLUMN clock;
+ALTER TABLE changelog DROP COLUMN clock;
@@ -0,0 +1,5 @@
+-- Encoded `HybridTimestamp` of the changelog entry that last updated the row
+ALTER TABLE changelog ADD COLUMN clock BLOB NOT NULL DEFAULT X'';
+ALTER TABLE chatroom ADD COLUMN clock BLOB NOT NULL DEFAULT X'';
+ALTER TABLE peer ADD COLUMN clock BLOB NOT NULL DEFAULT X'';
+ALTER TABLE vcard ADD COLUMN clock BLOB NOT NULL DEFAULT X'';
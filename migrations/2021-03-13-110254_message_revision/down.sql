@@ -0,0 +1 @@
+DROP TABLE IF EXISTS message_revision;
\ No newline at end of file
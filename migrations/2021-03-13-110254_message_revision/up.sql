@@ -0,0 +1,11 @@
+-- Edits and deletions of messages, applying only if issued by the sender of the message
+CREATE TABLE IF NOT EXISTS message_revision (
+  message_id BLOB NOT NULL,
+  sender     BLOB NOT NULL,
+  time       DOUBLE NOT NULL,
+
+  -- NULL if the message is deleted
+  content    TEXT,
+
+  PRIMARY KEY (message_id, sender, time)
+);
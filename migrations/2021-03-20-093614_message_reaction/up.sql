@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS message_reaction (
+  message_id BLOB NOT NULL,
+  account_id BLOB NOT NULL,
+  emoji      TEXT NOT NULL,
+
+  -- Whether the reaction is withdrawn, kept for resolving conflicts
+  removed    BOOLEAN NOT NULL,
+
+  -- Encoded `HybridTimestamp` of the changelog entry that last updated the row
+  clock      BLOB NOT NULL,
+
+  PRIMARY KEY (message_id, account_id, emoji)
+);
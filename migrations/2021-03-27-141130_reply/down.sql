@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS message_in_reply_to;
+ALTER TABLE message DROP COLUMN in_reply_to;
@@ -0,0 +1,4 @@
+-- ID of the message replied to
+ALTER TABLE message ADD COLUMN in_reply_to BLOB;
+
+CREATE INDEX IF NOT EXISTS message_in_reply_to ON message (in_reply_to);
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS message_read;
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- Messages received and read by the local account, whose senders are sent read receipts
+CREATE TABLE IF NOT EXISTS message_read (
+  message_id BLOB PRIMARY KEY NOT NULL REFERENCES message(message_id) ON DELETE CASCADE,
+  time_read  DOUBLE NOT NULL
+);
+
+-- Existing messages are seen as read without sending receipts
+INSERT OR IGNORE INTO message_read (message_id, time_read) SELECT message_id, time FROM message;
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS object_chunk;
+DROP TABLE IF EXISTS object_transfer;
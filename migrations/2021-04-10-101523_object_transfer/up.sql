@@ -0,0 +1,17 @@
+-- Objects referenced by messages but not fully downloaded yet
+CREATE TABLE IF NOT EXISTS object_transfer (
+  object_id BLOB PRIMARY KEY NOT NULL,
+
+  mime      TEXT NOT NULL,
+  size      BIGINT NOT NULL
+);
+
+-- Content downloaded so far
+CREATE TABLE IF NOT EXISTS object_chunk (
+  object_id BLOB NOT NULL REFERENCES object_transfer(object_id) ON DELETE CASCADE,
+  position  BIGINT NOT NULL,
+
+  content   BLOB NOT NULL,
+
+  PRIMARY KEY (object_id, position)
+);
@@ -0,0 +1,20 @@
+-- Content of the objects moved to files is lost
+DROP TABLE IF EXISTS object_export;
+
+CREATE TABLE IF NOT EXISTS object_content (
+  object_id BLOB PRIMARY KEY NOT NULL,
+
+  content   BLOB NOT NULL,
+  mime      TEXT NOT NULL
+);
+DROP TABLE object;
+ALTER TABLE object_content RENAME TO object;
+
+CREATE TABLE IF NOT EXISTS object_chunk (
+  object_id BLOB NOT NULL REFERENCES object_transfer(object_id) ON DELETE CASCADE,
+  position  BIGINT NOT NULL,
+
+  content   BLOB NOT NULL,
+
+  PRIMARY KEY (object_id, position)
+);
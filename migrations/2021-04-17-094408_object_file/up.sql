@@ -0,0 +1,21 @@
+-- Content of the objects saved before they were stored as files, moved out by the application
+CREATE TABLE IF NOT EXISTS object_export (
+  object_id BLOB PRIMARY KEY NOT NULL,
+
+  content   BLOB NOT NULL
+);
+INSERT INTO object_export SELECT object_id, content FROM object;
+
+-- Only the metadata of objects stays in the database
+CREATE TABLE IF NOT EXISTS object_metadata (
+  object_id BLOB PRIMARY KEY NOT NULL, -- Canonical ID
+
+  mime      TEXT NOT NULL,
+  size      BIGINT NOT NULL
+);
+INSERT INTO object_metadata SELECT object_id, mime, length(content) FROM object;
+DROP TABLE object;
+ALTER TABLE object_metadata RENAME TO object;
+
+-- Partial downloads are files as well, so those in progress start over
+DROP TABLE IF EXISTS object_chunk;
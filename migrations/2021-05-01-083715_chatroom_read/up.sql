@@ -0,0 +1,8 @@
+-- Latest message marked read in each chatroom
+CREATE TABLE IF NOT EXISTS chatroom_read (
+  chatroom_id BLOB PRIMARY KEY NOT NULL,
+
+  message_id  BLOB NOT NULL,
+  time        DOUBLE NOT NULL, -- Of the message
+  time_read   DOUBLE NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Previous values of `chatroom.time_updated` are lost
+DROP INDEX IF EXISTS message_chatroom_time;
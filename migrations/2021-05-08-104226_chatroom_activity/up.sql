@@ -0,0 +1,7 @@
+-- Last activity of a chatroom is the time of its latest message
+UPDATE chatroom SET time_updated = (
+  SELECT MAX(message.time) FROM message WHERE message.chatroom_id = chatroom.chatroom_id
+)
+WHERE EXISTS (SELECT 1 FROM message WHERE message.chatroom_id = chatroom.chatroom_id);
+
+CREATE INDEX IF NOT EXISTS message_chatroom_time ON message (chatroom_id, time);
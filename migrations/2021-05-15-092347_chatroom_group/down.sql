@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS chatroom_membership;
+ALTER TABLE chatroom DROP COLUMN is_group;
@@ -0,0 +1,16 @@
+-- Whether the chatroom has a stable ID generated by its creator instead of one derived from its
+-- members
+ALTER TABLE chatroom ADD COLUMN is_group BOOLEAN NOT NULL DEFAULT 0;
+
+-- Latest change to the membership of each account in each group chatroom, so that changes
+-- concerning different accounts never override each other
+CREATE TABLE IF NOT EXISTS chatroom_membership (
+  chatroom_id BLOB NOT NULL,
+  account_id  BLOB NOT NULL,
+
+  change_id   BLOB NOT NULL, -- Canonical ID of the `MembershipChange`
+  time        DOUBLE NOT NULL,
+  is_member   BOOLEAN NOT NULL,
+
+  PRIMARY KEY (chatroom_id, account_id)
+);
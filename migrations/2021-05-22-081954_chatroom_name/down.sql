@@ -0,0 +1 @@
+UPDATE chatroom SET name = 'New chatroom' WHERE name = '';
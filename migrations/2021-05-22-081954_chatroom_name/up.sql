@@ -0,0 +1,2 @@
+-- Chatrooms created for messages are named after their members instead
+UPDATE chatroom SET name = '' WHERE name = 'New chatroom' AND clock = X'';
@@ -0,0 +1,30 @@
+DROP INDEX IF EXISTS message_search_id;
+ALTER TABLE message DROP COLUMN search_id;
+DROP TABLE IF EXISTS message_search;
+-- Full-text index of the latest revision of messages, kept in sync by the application
+CREATE VIRTUAL TABLE IF NOT EXISTS message_search USING fts5 (
+  message_id UNINDEXED,
+  content
+);
+
+-- Index existing messages, skipping retracted ones
+INSERT INTO message_search (message_id, content)
+SELECT
+  message.message_id,
+  COALESCE(
+    (
+      SELECT message_revision.content FROM message_revision
+      WHERE message_revision.message_id = message.message_id
+        AND message_revision.sender = message.sender
+      ORDER BY message_revision.time DESC, message_revision.content DESC
+      LIMIT 1
+    ),
+    message.content
+  )
+FROM message
+WHERE NOT EXISTS (
+  SELECT 1 FROM message_revision
+  WHERE message_revision.message_id = message.message_id
+    AND message_revision.sender = message.sender
+    AND message_revision.content IS NULL
+);
@@ -0,0 +1,30 @@
+-- Row of each message in the full-text index, so that updating one never scans the index
+ALTER TABLE message ADD COLUMN search_id INTEGER;
+CREATE INDEX IF NOT EXISTS message_search_id ON message(search_id);
+
+DROP TABLE IF EXISTS message_search;
+CREATE VIRTUAL TABLE IF NOT EXISTS message_search USING fts5 (content);
+
+-- Index existing messages, skipping retracted ones
+INSERT INTO message_search (rowid, content)
+SELECT
+  message.rowid,
+  COALESCE(
+    (
+      SELECT message_revision.content FROM message_revision
+      WHERE message_revision.message_id = message.message_id
+        AND message_revision.sender = message.sender
+      ORDER BY message_revision.time DESC, message_revision.content DESC
+      LIMIT 1
+    ),
+    message.content
+  )
+FROM message
+WHERE NOT EXISTS (
+  SELECT 1 FROM message_revision
+  WHERE message_revision.message_id = message.message_id
+    AND message_revision.sender = message.sender
+    AND message_revision.content IS NULL
+);
+
+UPDATE message SET search_id = rowid WHERE rowid IN (SELECT rowid FROM message_search);
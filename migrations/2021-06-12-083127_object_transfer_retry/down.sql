@@ -0,0 +1,2 @@
+ALTER TABLE object_transfer DROP COLUMN time_next_attempt;
+ALTER TABLE object_transfer DROP COLUMN attempts;
@@ -0,0 +1,3 @@
+-- Downloads turning out corrupted are retried with exponential backoff
+ALTER TABLE object_transfer ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE object_transfer ADD COLUMN time_next_attempt DOUBLE NOT NULL DEFAULT 0;